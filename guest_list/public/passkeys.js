@@ -0,0 +1,141 @@
+// Passkey registration on the apps page and sign in on the sign in page.
+// The server sends and expects binary values as base64 URL encoded strings.
+
+function toBytes(base64Url) {
+  const base64 = base64Url.replace(/-/g, "+").replace(/_/g, "/");
+  return Uint8Array.from(atob(base64), (character) => character.charCodeAt(0));
+}
+
+function toBase64Url(buffer) {
+  const binary = String.fromCharCode(...new Uint8Array(buffer));
+  return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+function showError(message) {
+  const error = document.getElementById("passkey-error");
+  error.textContent = message;
+  error.hidden = false;
+}
+
+async function post(url, body) {
+  const response = await fetch(url, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: body === undefined ? undefined : JSON.stringify(body),
+  });
+  if (!response.ok) {
+    throw new Error(await response.text());
+  }
+  return response;
+}
+
+async function addPasskey(name) {
+  const start = await post("/apps/passkeys/registration/start");
+  const { ceremony_id, options } = await start.json();
+
+  const publicKey = options.publicKey;
+  publicKey.challenge = toBytes(publicKey.challenge);
+  publicKey.user.id = toBytes(publicKey.user.id);
+  for (const credential of publicKey.excludeCredentials ?? []) {
+    credential.id = toBytes(credential.id);
+  }
+
+  const credential = await navigator.credentials.create({ publicKey });
+
+  await post("/apps/passkeys/registration/finish", {
+    ceremony_id,
+    name,
+    credential: {
+      id: credential.id,
+      rawId: toBase64Url(credential.rawId),
+      type: credential.type,
+      response: {
+        attestationObject: toBase64Url(credential.response.attestationObject),
+        clientDataJSON: toBase64Url(credential.response.clientDataJSON),
+      },
+      extensions: credential.getClientExtensionResults(),
+    },
+  });
+}
+
+async function signInWithPasskey(mediation, signal) {
+  const start = await post("/signin/passkey/start");
+  const { ceremony_id, options } = await start.json();
+
+  const publicKey = options.publicKey;
+  publicKey.challenge = toBytes(publicKey.challenge);
+  for (const credential of publicKey.allowCredentials ?? []) {
+    credential.id = toBytes(credential.id);
+  }
+
+  const credential = await navigator.credentials.get({ publicKey, mediation, signal });
+  const response = credential.response;
+
+  await post("/signin/passkey/finish", {
+    ceremony_id,
+    credential: {
+      id: credential.id,
+      rawId: toBase64Url(credential.rawId),
+      type: credential.type,
+      response: {
+        authenticatorData: toBase64Url(response.authenticatorData),
+        clientDataJSON: toBase64Url(response.clientDataJSON),
+        signature: toBase64Url(response.signature),
+        userHandle: response.userHandle ? toBase64Url(response.userHandle) : null,
+      },
+      extensions: credential.getClientExtensionResults(),
+    },
+  });
+
+  // The session cookie was set by the response
+  window.location.assign("/apps");
+}
+
+function setUpRegistration(form) {
+  form.addEventListener("submit", async (event) => {
+    event.preventDefault();
+    try {
+      await addPasskey(form.elements.name.value);
+      window.location.reload();
+    } catch (error) {
+      showError(`The passkey could not be added. ${error.message}`);
+    }
+  });
+}
+
+async function setUpSignIn(button) {
+  // Offer passkeys in the autofill of the email field if the browser supports it
+  let conditional = null;
+  if (await PublicKeyCredential.isConditionalMediationAvailable?.()) {
+    conditional = new AbortController();
+    signInWithPasskey("conditional", conditional.signal).catch((error) => {
+      if (error.name !== "AbortError") {
+        showError(`Sign in with the passkey failed. ${error.message}`);
+      }
+    });
+  }
+
+  button.hidden = false;
+  button.addEventListener("click", async () => {
+    // Browsers only allow one pending passkey request
+    conditional?.abort();
+    conditional = null;
+    try {
+      await signInWithPasskey("optional");
+    } catch (error) {
+      showError(`Sign in with the passkey failed. ${error.message}`);
+    }
+  });
+}
+
+if (window.PublicKeyCredential) {
+  const form = document.getElementById("add-passkey");
+  if (form) {
+    setUpRegistration(form);
+  }
+
+  const button = document.getElementById("passkey-signin");
+  if (button) {
+    setUpSignIn(button);
+  }
+}
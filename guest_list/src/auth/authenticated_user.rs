@@ -1,75 +1,222 @@
 use axum::{
     async_trait,
-    extract::{FromRef, FromRequestParts},
-    http::request::Parts,
+    extract::{FromRequestParts, Request, State},
+    http::{header::SET_COOKIE, request::Parts},
+    middleware::Next,
     response::{IntoResponse, Redirect, Response},
 };
-use axum_extra::extract::{cookie::Cookie, CookieJar};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
 use libsql::named_params;
-use reqwest::StatusCode;
+use time::OffsetDateTime;
 
+use super::{SESSION_ACTIVITY_RESOLUTION, SESSION_IDLE_TIMEOUT, SESSION_LIFETIME};
 use crate::AppState;
 
+pub(crate) const SESSION_COOKIE: &str = "session";
+
+/// The user of a valid session. Requires the `load_session` middleware.
+#[derive(Clone)]
 pub(crate) struct AuthenticatedUser {
     pub id: String,
-    name: String,
-    email_address: String,
+    /// The session the user is authenticated with
+    pub session_id: String,
+    pub name: String,
+    pub email_address: String,
+    pub is_email_verified: bool,
 }
 
 #[async_trait]
 impl<S> FromRequestParts<S> for AuthenticatedUser
 where
-    AppState: FromRef<S>,
     S: Send + Sync,
 {
-    type Rejection = Response;
+    type Rejection = Redirect;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<AuthenticatedUser>()
+            .cloned()
+            //TODO display error message
+            .ok_or_else(|| Redirect::to("/signin"))
+    }
+}
+
+/// A user that verified their email address. Required to use the apps.
+pub(crate) struct VerifiedUser(pub(crate) AuthenticatedUser);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for VerifiedUser
+where
+    S: Send + Sync,
+{
+    type Rejection = Redirect;
 
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let jar = CookieJar::from_request_parts(parts, state)
-            .await
-            .map_err(|error| error.into_response())?;
+        let user = AuthenticatedUser::from_request_parts(parts, state).await?;
+        if !user.is_email_verified {
+            // Ask the user to follow the link in the email
+            return Err(Redirect::to("/signup/completed"));
+        }
 
-        let cookie = jar.get("session");
+        Ok(Self(user))
+    }
+}
 
-        let Some(cookie) = cookie else {
-            //TODO display error message
-            return Err(Redirect::to("/signin").into_response());
-        };
+pub(crate) fn session_cookie(session_id: String, expires_at: OffsetDateTime) -> Cookie<'static> {
+    // The cookie does not need to be encrypted as it doesn't contain any sensitive information
+    Cookie::build((SESSION_COOKIE, session_id))
+        .path("/")
+        .secure(true)
+        // Tell browsers to not allow JavaScript to access the cookie. Prevents some XSS attacks
+        // (JS can still indirectly find out if user is authenticated by trying to access authenticated endpoints)
+        .http_only(true)
+        // Prevents CSRF attack
+        .same_site(SameSite::Strict)
+        .expires(expires_at)
+        .build()
+}
 
-        let session_id = cookie.value();
-        // TODO use stateful cookies to reduce round trips to the database
-        let state = AppState::from_ref(state);
-        let mut rows = state
-            .connection
-            .query(
-                "SELECT
+enum Session {
+    Valid(AuthenticatedUser),
+    /// The session was extended until the new expiration date
+    Renewed(AuthenticatedUser, OffsetDateTime),
+    /// The session does not exist or expired
+    Invalid,
+}
+
+/// Loads the user of the session cookie for the `AuthenticatedUser` extractor.
+/// Removes the cookie of invalid sessions and extends the cookie of renewed sessions.
+pub(crate) async fn load_session(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(cookie) = jar.get(SESSION_COOKIE) else {
+        return next.run(request).await;
+    };
+
+    let session_id = cookie.value().to_owned();
+    // TODO use stateful cookies to reduce round trips to the database
+    let jar = match check_session(&state, &session_id).await {
+        Session::Valid(user) => {
+            request.extensions_mut().insert(user);
+            jar
+        }
+        Session::Renewed(user, expires_at) => {
+            request.extensions_mut().insert(user);
+            jar.add(session_cookie(session_id, expires_at))
+        }
+        // The path has to match the one the cookie was set with for the browser to remove it
+        Session::Invalid => jar.remove(Cookie::build(SESSION_COOKIE).path("/")),
+    };
+
+    let response = next.run(request).await;
+
+    // Handlers that set the session cookie themselves, like signing out, take precedence
+    let prefix = format!("{}=", SESSION_COOKIE);
+    let sets_session_cookie = response
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .any(|value| value.as_bytes().starts_with(prefix.as_bytes()));
+    if sets_session_cookie {
+        return response;
+    }
+
+    (jar, response).into_response()
+}
+
+async fn check_session(state: &AppState, session_id: &str) -> Session {
+    let mut rows = state
+        .connection
+        .query(
+            "SELECT
                 user_id,
                 name,
                 email_address,
-                expires_at_utc
+                expires_at_utc,
+                idle_expires_at_utc,
+                email_verified_at_utc
             FROM sessions
             JOIN users
                 ON sessions.user_id = users.id
                 AND sessions.id = :session_id",
+            named_params![
+                ":session_id": session_id
+            ],
+        )
+        .await
+        .unwrap();
+
+    let Some(row) = rows.next().await.unwrap() else {
+        return Session::Invalid;
+    };
+
+    let user = AuthenticatedUser {
+        id: row.get(0).unwrap(),
+        session_id: session_id.to_owned(),
+        name: row.get(1).unwrap(),
+        email_address: row.get(2).unwrap(),
+        is_email_verified: row.get::<Option<i64>>(5).unwrap().is_some(),
+    };
+    let expires_at = OffsetDateTime::from_unix_timestamp(row.get(3).unwrap()).unwrap();
+    let idle_expires_at = OffsetDateTime::from_unix_timestamp(row.get(4).unwrap()).unwrap();
+
+    let now = state.clock.now();
+    if expires_at <= now || idle_expires_at <= now {
+        // A stolen cookie must not be usable after the session expired
+        state
+            .connection
+            .execute(
+                "DELETE FROM sessions WHERE id = :id",
+                named_params![":id": session_id],
+            )
+            .await
+            .unwrap();
+
+        return Session::Invalid;
+    }
+
+    // Keep active users signed in
+    if expires_at - now < SESSION_LIFETIME / 2 {
+        let expires_at = now + SESSION_LIFETIME;
+        state
+            .connection
+            .execute(
+                "UPDATE sessions
+                SET expires_at_utc = :expires_at_utc, idle_expires_at_utc = :idle_expires_at_utc
+                WHERE id = :id",
                 named_params![
-                    ":session_id": session_id
+                    ":id": session_id,
+                    ":expires_at_utc": expires_at.unix_timestamp(),
+                    ":idle_expires_at_utc": (now + SESSION_IDLE_TIMEOUT).unix_timestamp(),
                 ],
             )
             .await
             .unwrap();
 
-        let Some(row) = rows.next().await.unwrap() else {
-            // Remove invalid cookie
-            let jar = jar.remove(Cookie::from("session"));
-            return Err((jar, Redirect::to("/signin")).into_response());
-        };
-
-        let user = AuthenticatedUser {
-            id: row.get(0).unwrap(),
-            name: row.get(1).unwrap(),
-            email_address: row.get(2).unwrap(),
-        };
+        return Session::Renewed(user, expires_at);
+    }
 
-        Ok(user)
+    // Only record activity occasionally to not write on every request
+    if idle_expires_at - now < SESSION_IDLE_TIMEOUT - SESSION_ACTIVITY_RESOLUTION {
+        state
+            .connection
+            .execute(
+                "UPDATE sessions SET idle_expires_at_utc = :idle_expires_at_utc WHERE id = :id",
+                named_params![
+                    ":id": session_id,
+                    ":idle_expires_at_utc": (now + SESSION_IDLE_TIMEOUT).unix_timestamp(),
+                ],
+            )
+            .await
+            .unwrap();
     }
+
+    Session::Valid(user)
 }
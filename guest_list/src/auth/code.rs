@@ -0,0 +1,254 @@
+//! Sign in with the code from the sign in email for users that read their email on another device than they sign in on
+
+use std::net::IpAddr;
+
+use askama_axum::{IntoResponse, Template};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Response,
+    Form,
+};
+use axum_extra::extract::{cookie::Cookie, CookieJar};
+use libsql::{named_params, Connection};
+use nanoid::nanoid;
+use serde::Deserialize;
+use time::Duration;
+
+use super::{finish_signin, SIGNIN_BROWSER_COOKIE};
+use crate::{client_ip::ClientIp, email::Email, AppState};
+
+const SIGNIN_CODE_LENGTH: usize = 6;
+const DIGITS: [char; 10] = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9'];
+/// Wrong codes are counted over this window so the code can not be guessed by requesting new ones
+pub(crate) const CODE_FAILURE_WINDOW: Duration = Duration::minutes(15);
+/// Wrong codes for one account within the window after which its codes are no longer checked.
+/// Signing in with the link still works so an attacker can't lock the user out.
+const MAX_CODE_FAILURES_PER_ACCOUNT: i64 = 5;
+/// Wrong codes from one IP address within the window, to stop guessing the codes of many accounts at once
+const MAX_CODE_FAILURES_PER_IP: i64 = 20;
+
+// Does not give away if the email has an account or if the attempt was locked
+const INVALID_CODE_MESSAGE: &str =
+    "The code is wrong or expired or the sign in was requested in another browser. After too many wrong codes, wait 15 minutes or use the link from the email.";
+
+pub(super) fn generate_code() -> String {
+    nanoid!(SIGNIN_CODE_LENGTH, &DIGITS)
+}
+
+/// Compares in constant time to not leak how many digits of a guess are correct
+fn is_same_code(expected: &str, actual: &str) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .bytes()
+            .zip(actual.bytes())
+            .fold(0, |difference, (expected, actual)| {
+                difference | (expected ^ actual)
+            })
+            == 0
+}
+
+#[derive(Template)]
+#[template(path = "signin_code.html")]
+pub(super) struct SigninCodeTemplate {
+    pub(super) email: Option<String>,
+    pub(super) error: Option<&'static str>,
+}
+
+pub(super) async fn get_signin_code_page() -> impl IntoResponse {
+    SigninCodeTemplate {
+        email: None,
+        error: None,
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(super) struct SigninCodeRequest {
+    email: String,
+    code: String,
+}
+
+pub(super) async fn complete_signin_with_code(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
+    Form(request): Form<SigninCodeRequest>,
+) -> Response {
+    let invalid_code = |email: Option<String>| {
+        let template = SigninCodeTemplate {
+            email,
+            error: Some(INVALID_CODE_MESSAGE),
+        };
+        (StatusCode::BAD_REQUEST, template).into_response()
+    };
+
+    let now = state.clock.now().unix_timestamp();
+    let window_start = now - CODE_FAILURE_WINDOW.whole_seconds();
+    if ip_code_failures(&state.connection, ip_address, window_start).await
+        >= MAX_CODE_FAILURES_PER_IP
+    {
+        tracing::warn!(
+            "Rejected sign in code from {} after too many wrong codes",
+            ip_address
+        );
+        return invalid_code(None);
+    }
+
+    let Ok(email) = Email::parse(&request.email) else {
+        return invalid_code(None);
+    };
+
+    // Only the browser that requested the sign in can complete it, so the code alone is worthless to someone that
+    // intercepted the email. Only its latest attempt can be completed as the user expects the latest code to work.
+    let browser_token = jar
+        .get(SIGNIN_BROWSER_COOKIE)
+        .map(|cookie| cookie.value().to_owned())
+        .unwrap_or_default();
+    let mut rows = state
+        .connection
+        .query(
+            "SELECT
+                signin_attempts.id,
+                signin_attempts.user_id,
+                signin_attempts.code,
+                signin_attempts.expires_at_utc
+            FROM signin_attempts
+            JOIN users ON signin_attempts.user_id = users.id
+            WHERE users.email_address = :email AND signin_attempts.browser_token = :browser_token
+            ORDER BY signin_attempts.expires_at_utc DESC
+            LIMIT 1",
+            named_params![
+                ":email": email.0.clone(),
+                ":browser_token": browser_token,
+            ],
+        )
+        .await
+        .unwrap();
+
+    let Some(row) = rows.next().await.unwrap() else {
+        // Guesses for addresses without an account still count against the IP address
+        record_code_failure(&state.connection, None, ip_address, now).await;
+        return invalid_code(Some(email.0));
+    };
+
+    let attempt_id: String = row.get(0).unwrap();
+    let user_id: String = row.get(1).unwrap();
+    let code: String = row.get(2).unwrap();
+    let expires_at: i64 = row.get(3).unwrap();
+
+    if account_code_failures(&state.connection, &user_id, window_start).await
+        >= MAX_CODE_FAILURES_PER_ACCOUNT
+    {
+        tracing::warn!("Rejected sign in code for an account after too many wrong codes");
+        return invalid_code(Some(email.0));
+    }
+
+    // The link and the code expire together
+    if expires_at < now || !is_same_code(&code, request.code.trim()) {
+        record_code_failure(&state.connection, Some(&user_id), ip_address, now).await;
+        return invalid_code(Some(email.0));
+    }
+
+    let jar = jar.remove(Cookie::build(SIGNIN_BROWSER_COOKIE).path("/signin"));
+    finish_signin(&state, jar, ip_address, &headers, &attempt_id, user_id).await
+}
+
+async fn record_code_failure(
+    connection: &Connection,
+    user_id: Option<&str>,
+    ip_address: IpAddr,
+    now: i64,
+) {
+    connection
+        .execute(
+            "INSERT INTO signin_code_failures (user_id, ip_address, failed_at_utc)
+            VALUES (:user_id, :ip_address, :now)",
+            named_params![
+                ":user_id": user_id,
+                ":ip_address": ip_address.to_string(),
+                ":now": now,
+            ],
+        )
+        .await
+        .unwrap();
+}
+
+/// Wrong codes for the account since the start of the window
+async fn account_code_failures(connection: &Connection, user_id: &str, since: i64) -> i64 {
+    let mut rows = connection
+        .query(
+            "SELECT COUNT(*) FROM signin_code_failures
+            WHERE user_id = :user_id AND failed_at_utc > :since",
+            named_params![":user_id": user_id, ":since": since],
+        )
+        .await
+        .unwrap();
+    rows.next().await.unwrap().unwrap().get(0).unwrap()
+}
+
+/// Wrong codes from the IP address since the start of the window
+async fn ip_code_failures(connection: &Connection, ip_address: IpAddr, since: i64) -> i64 {
+    let mut rows = connection
+        .query(
+            "SELECT COUNT(*) FROM signin_code_failures
+            WHERE ip_address = :ip_address AND failed_at_utc > :since",
+            named_params![":ip_address": ip_address.to_string(), ":since": since],
+        )
+        .await
+        .unwrap();
+    rows.next().await.unwrap().unwrap().get(0).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use libsql::{Builder, Connection};
+
+    use super::{
+        account_code_failures, ip_code_failures, record_code_failure, CODE_FAILURE_WINDOW,
+    };
+    use crate::database::create_tables;
+
+    const NOW: i64 = 1_700_000_000;
+
+    async fn connect() -> Connection {
+        let database = Builder::new_local(":memory:").build().await.unwrap();
+        let connection = database.connect().unwrap();
+        create_tables(&connection).await.unwrap();
+        connection
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn counts_failures_per_account_and_ip_within_window() {
+        let connection = connect().await;
+        let window_start = NOW - CODE_FAILURE_WINDOW.whole_seconds();
+        record_code_failure(&connection, Some("user"), ip("192.0.2.1"), NOW).await;
+        record_code_failure(&connection, Some("user"), ip("192.0.2.2"), NOW - 1).await;
+        record_code_failure(&connection, None, ip("192.0.2.1"), NOW).await;
+        // Failures at the start of the window have run out
+        record_code_failure(&connection, Some("user"), ip("192.0.2.1"), window_start).await;
+
+        assert_eq!(
+            account_code_failures(&connection, "user", window_start).await,
+            2
+        );
+        assert_eq!(
+            account_code_failures(&connection, "other", window_start).await,
+            0
+        );
+        assert_eq!(
+            ip_code_failures(&connection, ip("192.0.2.1"), window_start).await,
+            2
+        );
+        assert_eq!(
+            ip_code_failures(&connection, ip("192.0.2.2"), window_start).await,
+            1
+        );
+    }
+}
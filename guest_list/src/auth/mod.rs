@@ -1,11 +1,18 @@
+use std::net::IpAddr;
+
 use crate::{
-    email::{send_sign_in_email, Email},
+    client_ip::ClientIp,
+    email::{locale::Locale, send_sign_in_email, Email, SignInEmail},
     AppState,
 };
 use askama_axum::{IntoResponse, Template};
 use axum::{
     extract::{Path, State},
-    response::Redirect,
+    http::{
+        header::{ACCEPT_LANGUAGE, USER_AGENT},
+        HeaderMap, StatusCode,
+    },
+    response::{Redirect, Response},
     routing::get,
     Form, Router,
 };
@@ -16,17 +23,31 @@ use axum_extra::extract::{
 use libsql::named_params;
 use nanoid::nanoid;
 use serde::Deserialize;
+use time::OffsetDateTime;
 
-use self::authenticated_user::AuthenticatedUser;
+use self::authenticated_user::{session_cookie, AuthenticatedUser, SESSION_COOKIE};
 
 pub(crate) mod authenticated_user;
+mod code;
+pub(crate) mod passkeys;
+mod sessions;
 
 //TODO decide how long a session should live
 const SESSION_LIFETIME: time::Duration = time::Duration::days(30);
+/// Sessions that are not used for this long expire even if their lifetime is not over
+const SESSION_IDLE_TIMEOUT: time::Duration = time::Duration::days(7);
+/// How often the last activity of a session is recorded
+const SESSION_ACTIVITY_RESOLUTION: time::Duration = time::Duration::hours(1);
 const SIGNIN_ATTEMPT_LIFETIME: time::Duration = time::Duration::minutes(15);
+/// Identifies the browser that requested a sign in so the link can be completed there without confirmation
+const SIGNIN_BROWSER_COOKIE: &str = "signin_browser";
+/// User agents are only shown to the user to recognize their sessions and don't need to be stored in full
+const MAX_USER_AGENT_LENGTH: usize = 256;
 
 pub(crate) const USER_HOME_PAGE: &str = "/apps";
 
+pub(crate) use code::CODE_FAILURE_WINDOW;
+
 #[derive(Deserialize, Debug)]
 struct CreateAccountRequest {
     email: String,
@@ -34,85 +55,265 @@ struct CreateAccountRequest {
 }
 
 async fn create_account(
-    State(AppState {
-        connection,
-        configuration,
-        client,
-        secrets,
-    }): State<AppState>,
+    State(state): State<AppState>,
+    jar: CookieJar,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
     Form(request): Form<CreateAccountRequest>,
 ) -> impl IntoResponse {
-    //TODO check if user already exists
-    let user_id = nanoid!();
-    connection
-        .execute(
-            "INSERT INTO users (id, name, email_address) VALUES (:id, :name, :email_address)",
+    let email = match Email::parse(&request.email) {
+        Ok(email) => email,
+        Err(error) => {
+            let sign_up_template = SignUpTemplate {
+                error: Some(error.to_string()),
+            };
+            return (StatusCode::BAD_REQUEST, sign_up_template).into_response();
+        }
+    };
+
+    // Signing up with a registered address signs in instead
+    // The response is the same either way to not give away which emails are registered
+    // Until the address is verified the name can be changed as it might not have been chosen by the owner
+    let mut rows = state
+        .connection
+        .query(
+            "INSERT INTO users (id, name, email_address, created_at_utc)
+            VALUES (:id, :name, :email_address, :created_at_utc)
+            ON CONFLICT (email_address) DO UPDATE SET name = CASE
+                WHEN users.email_verified_at_utc IS NULL THEN excluded.name
+                ELSE users.name
+            END
+            RETURNING id, name",
             named_params![
-                ":id": user_id.clone(),
+                ":id": nanoid!(),
                 ":name": request.name,
-                ":email_address": request.email.clone(),
+                ":email_address": email.0.clone(),
+                ":created_at_utc": state.clock.now().unix_timestamp(),
             ],
         )
         .await
         .unwrap();
 
+    let row = rows.next().await.unwrap().unwrap();
+    let browser_token = signin_browser_token(&jar);
+    let expires_at = state.clock.now() + SIGNIN_ATTEMPT_LIFETIME;
+    let jar = jar.add(signin_browser_cookie(browser_token.clone(), expires_at));
+    let signup_completed_template = SignupCompletedTemplate {
+        email: Some(email.0.clone()),
+    };
+    let user = SigninUser {
+        id: row.get(0).unwrap(),
+        name: row.get(1).unwrap(),
+        email,
+    };
+
+    send_signin_link(&state, user, browser_token, ip_address, &headers).await;
+
+    (jar, signup_completed_template).into_response()
+}
+
+/// The user a sign in link is sent to
+struct SigninUser {
+    id: String,
+    name: String,
+    email: Email,
+}
+
+/// Lets the user recognize where a sign in or session comes from
+fn user_agent(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LENGTH).collect())
+}
+
+/// Returns the token of the browser from an earlier sign in or a new one.
+/// Reusing it keeps the links of earlier sign in attempts from the same browser working.
+fn signin_browser_token(jar: &CookieJar) -> String {
+    jar.get(SIGNIN_BROWSER_COOKIE)
+        .map(|cookie| cookie.value().to_owned())
+        .unwrap_or_else(|| nanoid!())
+}
+
+fn signin_browser_cookie(token: String, expires_at: OffsetDateTime) -> Cookie<'static> {
+    Cookie::build((SIGNIN_BROWSER_COOKIE, token))
+        .path("/signin")
+        .secure(true)
+        .http_only(true)
+        // Lax so the cookie is sent when the link is opened from a mail client or web mail
+        .same_site(SameSite::Lax)
+        .expires(expires_at)
+        .build()
+}
+
+/// Creates a sign in attempt bound to the requesting browser and sends the link to complete it to the user
+async fn send_signin_link(
+    state: &AppState,
+    user: SigninUser,
+    browser_token: String,
+    ip_address: IpAddr,
+    headers: &HeaderMap,
+) {
     let attempt_id = nanoid!();
-    let expires_at = time::OffsetDateTime::now_utc() + SIGNIN_ATTEMPT_LIFETIME;
-    let expires_at = expires_at.unix_timestamp();
-    connection
+    let code = code::generate_code();
+    let expires_at = state.clock.now() + SIGNIN_ATTEMPT_LIFETIME;
+    state
+        .connection
         .execute(
-            "INSERT INTO signin_attempts VALUES (:id, :user_id, :expires_at)",
+            "INSERT INTO signin_attempts (id, user_id, browser_token, code, expires_at_utc)
+            VALUES (:id, :user_id, :browser_token, :code, :expires_at)",
             named_params![
                 ":id": attempt_id.clone(),
-                ":user_id": user_id,
-                ":expires_at": expires_at,
+                ":user_id": user.id,
+                ":browser_token": browser_token,
+                ":code": code.clone(),
+                ":expires_at": expires_at.unix_timestamp(),
             ],
         )
         .await
         .unwrap();
 
-    //TODO email address validation
-    send_sign_in_email(
-        Email(request.email),
-        &client,
+    let accept_language = headers
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|language| language.to_str().ok());
+    let email = SignInEmail {
+        to: user.email,
+        name: user.name,
+        locale: Locale::from_accept_language(accept_language),
         attempt_id,
-        configuration.server_url,
-        secrets.resend_auth_token,
+        code,
+        expires_at,
+        ip_address,
+        user_agent: user_agent(headers),
+    };
+
+    send_sign_in_email(
+        state.email_sender.as_ref(),
+        state.configuration.server_url.clone(),
+        email,
     )
     .await
     .unwrap();
+}
 
-    Redirect::to("/signup/completed")
+/// A sign in attempt that has not expired yet
+struct SigninAttempt {
+    user_id: String,
+    browser_token: String,
 }
 
-async fn complete_signin(
-    State(AppState { connection, .. }): State<AppState>,
-    jar: CookieJar,
-    Path(attempt_id): Path<String>,
-) -> impl IntoResponse {
-    // Check if sign in attempt exists
-    let mut rows = connection
+impl SigninAttempt {
+    fn is_same_browser(&self, jar: &CookieJar) -> bool {
+        jar.get(SIGNIN_BROWSER_COOKIE)
+            .is_some_and(|cookie| cookie.value() == self.browser_token)
+    }
+}
+
+async fn find_signin_attempt(state: &AppState, attempt_id: &str) -> Option<SigninAttempt> {
+    let mut rows = state
+        .connection
         .query(
-            "SELECT user_id, expires_at_utc FROM signin_attempts WHERE id = :id",
-            named_params![":id": attempt_id.clone()],
+            "SELECT user_id, browser_token FROM signin_attempts
+            WHERE id = :id AND expires_at_utc >= :now",
+            named_params![
+                ":id": attempt_id,
+                ":now": state.clock.now().unix_timestamp(),
+            ],
         )
         .await
         .unwrap();
 
-    let Some(row) = rows.next().await.unwrap() else {
+    let row = rows.next().await.unwrap()?;
+    Some(SigninAttempt {
+        user_id: row.get(0).unwrap(),
+        browser_token: row.get(1).unwrap(),
+    })
+}
+
+#[derive(Template)]
+#[template(path = "signin_confirm.html")]
+struct SigninConfirmTemplate {
+    attempt_id: String,
+    is_same_browser: bool,
+}
+
+/// Opening the link only shows a confirmation and does not use up the sign in attempt.
+/// Mail scanners and link previews that prefetch links with GET requests can't sign in or invalidate the link.
+async fn confirm_signin(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    Path(attempt_id): Path<String>,
+) -> Response {
+    let Some(attempt) = find_signin_attempt(&state, &attempt_id).await else {
         // Don't give away if the link existed or not
         return Redirect::to("/signin/expired").into_response();
     };
 
-    let expires_at: i64 = row.get(1).unwrap();
-    if expires_at < time::OffsetDateTime::now_utc().unix_timestamp() {
+    let sign_in_confirm_template = SigninConfirmTemplate {
+        is_same_browser: attempt.is_same_browser(&jar),
+        attempt_id,
+    };
+    sign_in_confirm_template.into_response()
+}
+
+/// Browsers tell with every request if it was made by another site
+fn is_cross_site(headers: &HeaderMap) -> bool {
+    headers
+        .get("sec-fetch-site")
+        .is_some_and(|site| *site == "cross-site")
+}
+
+async fn complete_signin(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
+    Path(attempt_id): Path<String>,
+) -> Response {
+    // Another site could otherwise sign the user in to an account of the attacker
+    if is_cross_site(&headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let Some(attempt) = find_signin_attempt(&state, &attempt_id).await else {
         return Redirect::to("/signin/expired").into_response();
+    };
+
+    // Someone that intercepted the link (e.g. from a forwarded email) does not have the cookie of the browser
+    // that requested the sign in. Users that opened the link on another device enter the code instead.
+    if !attempt.is_same_browser(&jar) {
+        let sign_in_confirm_template = SigninConfirmTemplate {
+            attempt_id,
+            is_same_browser: false,
+        };
+        return (StatusCode::FORBIDDEN, sign_in_confirm_template).into_response();
     }
 
-    let user_id: String = row.get(0).unwrap();
+    let jar = jar.remove(Cookie::build(SIGNIN_BROWSER_COOKIE).path("/signin"));
+    finish_signin(
+        &state,
+        jar,
+        ip_address,
+        &headers,
+        &attempt_id,
+        attempt.user_id,
+    )
+    .await
+}
 
+/// Completes the sign in attempt after the user proved they received the email and creates a session
+async fn finish_signin(
+    state: &AppState,
+    jar: CookieJar,
+    ip_address: IpAddr,
+    headers: &HeaderMap,
+    attempt_id: &str,
+    user_id: String,
+) -> Response {
     // Delete sign in attempt to prevent reusage
-    connection
+    // Only one request can delete it so concurrent requests can't create multiple sessions
+    let deleted = state
+        .connection
         .execute(
             "DELETE FROM signin_attempts WHERE id = :id",
             named_params![":id": attempt_id],
@@ -120,52 +321,102 @@ async fn complete_signin(
         .await
         .unwrap();
 
-    // Create session
+    if deleted == 0 {
+        return Redirect::to("/signin/expired").into_response();
+    }
+
+    let now = state.clock.now();
+
+    // Following the link proves that the user owns the email address
+    state
+        .connection
+        .execute(
+            "UPDATE users
+            SET email_verified_at_utc = COALESCE(email_verified_at_utc, :now)
+            WHERE id = :id",
+            named_params![
+                ":id": user_id.clone(),
+                ":now": now.unix_timestamp(),
+            ],
+        )
+        .await
+        .unwrap();
+
+    let jar = create_session(state, jar, ip_address, headers, user_id).await;
+    (jar, Redirect::to(USER_HOME_PAGE)).into_response()
+}
+
+/// Creates a session for a user that proved their identity and adds its cookie
+async fn create_session(
+    state: &AppState,
+    jar: CookieJar,
+    ip_address: IpAddr,
+    headers: &HeaderMap,
+    user_id: String,
+) -> CookieJar {
+    let now = state.clock.now();
     let session_id = nanoid!();
-    let expires_at = time::OffsetDateTime::now_utc() + SESSION_LIFETIME;
+    let expires_at = now + SESSION_LIFETIME;
 
-    connection
+    state
+        .connection
         .execute(
-            "INSERT INTO sessions (id, user_id, expires_at_utc) VALUES (:id, :user_id, :expires_at_utc)",
+            "INSERT INTO sessions (
+                id,
+                user_id,
+                created_at_utc,
+                expires_at_utc,
+                idle_expires_at_utc,
+                user_agent,
+                ip_address
+            )
+            VALUES (
+                :id,
+                :user_id,
+                :created_at_utc,
+                :expires_at_utc,
+                :idle_expires_at_utc,
+                :user_agent,
+                :ip_address
+            )",
             named_params![
                 ":id": session_id.clone(),
                 ":user_id": user_id,
+                ":created_at_utc": now.unix_timestamp(),
                 ":expires_at_utc": expires_at.unix_timestamp(),
+                ":idle_expires_at_utc": (now + SESSION_IDLE_TIMEOUT).unix_timestamp(),
+                ":user_agent": user_agent(headers),
+                ":ip_address": ip_address.to_string(),
             ],
         )
         .await
         .unwrap();
 
-    // Set session cookie
-    // The cookie does not need to be encrypted as it doesn't contain any sensitive information
-    let cookie = Cookie::build(("session", session_id))
-        .path("/")
-        .secure(true)
-        // Tell browsers to not allow JavaScript to access the cookie. Prevents some XSS attacks
-        // (JS can still indirectly find out if user is authenticated by trying to access authenticated endpoints)
-        .http_only(true)
-        // Prevents CSRF attack
-        .same_site(SameSite::Strict)
-        .expires(expires_at);
-
-    (jar.add(cookie), Redirect::to(USER_HOME_PAGE)).into_response()
+    let cookie = session_cookie(session_id, expires_at);
+    jar.add(cookie)
 }
 
 #[derive(Template)]
 #[template(path = "signup_completed.html")]
-struct SignupCompletedTemplate {}
+struct SignupCompletedTemplate {
+    /// The address the code was sent to so the user doesn't need to enter it again
+    email: Option<String>,
+}
 
 async fn signup_completed() -> impl IntoResponse {
-    let sign_up_completed_template = SignupCompletedTemplate {};
+    let sign_up_completed_template = SignupCompletedTemplate { email: None };
     sign_up_completed_template
 }
 
 #[derive(Template)]
 #[template(path = "signin_completed.html")]
-struct SigninCompletedTemplate {}
+struct SigninCompletedTemplate {
+    /// The address the code was sent to so the user doesn't need to enter it again
+    email: Option<String>,
+}
 
 async fn signin_completed() -> impl IntoResponse {
-    let sign_up_completed_template = SigninCompletedTemplate {};
+    let sign_up_completed_template = SigninCompletedTemplate { email: None };
     sign_up_completed_template
 }
 
@@ -175,70 +426,68 @@ struct SignInRequest {
 }
 
 async fn sign_in(
-    State(AppState {
-        connection,
-        configuration,
-        client,
-        secrets,
-    }): State<AppState>,
+    State(state): State<AppState>,
+    jar: CookieJar,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
     Form(request): Form<SignInRequest>,
-) -> Redirect {
+) -> impl IntoResponse {
+    let email = match Email::parse(&request.email) {
+        Ok(email) => email,
+        Err(error) => {
+            let sign_in_template = SignInTemplate {
+                error: Some(error.to_string()),
+            };
+            return (StatusCode::BAD_REQUEST, sign_in_template).into_response();
+        }
+    };
+
     // Get user id
-    let mut rows = connection
+    let mut rows = state
+        .connection
         .query(
-            "SELECT id, email_address FROM users WHERE email_address = :email",
-            named_params![":email": request.email],
+            "SELECT id, name FROM users WHERE email_address = :email",
+            named_params![":email": email.0.clone()],
         )
         .await
         .unwrap();
 
+    // Show the code form even if they don't have an account
+    // This is to prevent snooping on which emails are registered
+    // The cookie is also set for unknown addresses to not give away if they have an account
+    let browser_token = signin_browser_token(&jar);
+    let expires_at = state.clock.now() + SIGNIN_ATTEMPT_LIFETIME;
+    let jar = jar.add(signin_browser_cookie(browser_token.clone(), expires_at));
+    let signin_completed_template = SigninCompletedTemplate {
+        email: Some(email.0.clone()),
+    };
+
     let Some(row) = rows.next().await.unwrap() else {
-        // Navigate user to complete sign in even if they don't have an account
-        // This is to prevent snooping on which emails are registered
-        return Redirect::to("/signin/completed");
+        return (jar, signin_completed_template).into_response();
     };
 
-    let user_id: String = row.get(0).unwrap();
-    let email_address: String = row.get(1).unwrap();
+    let user = SigninUser {
+        id: row.get(0).unwrap(),
+        name: row.get(1).unwrap(),
+        email,
+    };
 
-    // Create signin attempt
-    let attempt_id = nanoid!();
-    let expires_at = time::OffsetDateTime::now_utc() + SIGNIN_ATTEMPT_LIFETIME;
-    let expires_at = expires_at.unix_timestamp();
+    send_signin_link(&state, user, browser_token, ip_address, &headers).await;
 
-    connection
-        .execute(
-            "INSERT INTO signin_attempts VALUES (:id, :user_id, :expires_at)",
-            named_params![
-                ":id": attempt_id.clone(),
-                ":user_id": user_id,
-                ":expires_at": expires_at,
-            ],
-        )
-        .await
-        .unwrap();
-
-    // Email address should be validated at this point
-    send_sign_in_email(
-        Email(email_address),
-        &client,
-        attempt_id,
-        configuration.server_url,
-        secrets.resend_auth_token,
-    )
-    .await
-    .unwrap();
-
-    Redirect::to("/signin/completed")
+    (jar, signin_completed_template).into_response()
 }
 
 #[derive(Template)]
 #[template(path = "sign_up.html")]
-struct SignUpTemplate {}
+struct SignUpTemplate {
+    error: Option<String>,
+}
 
 #[derive(Template)]
 #[template(path = "sign_in.html")]
-struct SignInTemplate {}
+struct SignInTemplate {
+    error: Option<String>,
+}
 
 async fn sign_up_handler(user: Option<AuthenticatedUser>) -> impl IntoResponse {
     // Check if is already authenticated and redirect to apps
@@ -247,7 +496,7 @@ async fn sign_up_handler(user: Option<AuthenticatedUser>) -> impl IntoResponse {
         return Redirect::to(USER_HOME_PAGE).into_response();
     }
 
-    let sign_up_template = SignUpTemplate {};
+    let sign_up_template = SignUpTemplate { error: None };
 
     sign_up_template.into_response()
 }
@@ -259,7 +508,7 @@ async fn sign_in_handler(user: Option<AuthenticatedUser>) -> impl IntoResponse {
         return Redirect::to(USER_HOME_PAGE).into_response();
     }
 
-    let sign_in_template = SignInTemplate {};
+    let sign_in_template = SignInTemplate { error: None };
 
     sign_in_template.into_response()
 }
@@ -274,9 +523,26 @@ async fn sign_in_expired() -> impl IntoResponse {
     sign_in_expired_template
 }
 
-async fn sign_out(jar: CookieJar) -> (CookieJar, Redirect) {
+async fn sign_out(
+    State(AppState { connection, .. }): State<AppState>,
+    jar: CookieJar,
+) -> (CookieJar, Redirect) {
+    // Revoke the session so the cookie can not be used anymore even if it was copied
+    if let Some(cookie) = jar.get(SESSION_COOKIE) {
+        connection
+            .execute(
+                "DELETE FROM sessions WHERE id = :id",
+                named_params![":id": cookie.value()],
+            )
+            .await
+            .unwrap();
+    }
+
     // This should be a no-op if the cookie doesn't exist
-    (jar.remove("session"), Redirect::to("/"))
+    (
+        jar.remove(Cookie::build(SESSION_COOKIE).path("/")),
+        Redirect::to("/"),
+    )
 }
 
 pub(crate) fn create_router() -> Router<AppState> {
@@ -285,7 +551,16 @@ pub(crate) fn create_router() -> Router<AppState> {
         .route("/signup/completed", get(signup_completed))
         .route("/signin", get(sign_in_handler).post(sign_in))
         .route("/signin/completed", get(signin_completed))
-        .route("/signin/:attempt_id", get(complete_signin))
+        .route(
+            "/signin/code",
+            get(code::get_signin_code_page).post(code::complete_signin_with_code),
+        )
+        .route(
+            "/signin/:attempt_id",
+            get(confirm_signin).post(complete_signin),
+        )
         .route("/signin/expired", get(sign_in_expired))
         .route("/signout", get(sign_out))
+        .merge(sessions::create_router())
+        .merge(passkeys::create_router())
 }
@@ -0,0 +1,423 @@
+//! Sign in with passkeys (WebAuthn) instead of an email link.
+//! Users add passkeys on the apps page after signing in with an email link, which stays available for recovery.
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    routing::post,
+    Json, Router,
+};
+use axum_extra::extract::CookieJar;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use libsql::{named_params, Connection};
+use nanoid::nanoid;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+use webauthn_rs::{
+    prelude::{
+        CreationChallengeResponse, DiscoverableAuthentication, DiscoverableKey, Passkey,
+        PasskeyRegistration, PublicKeyCredential, RegisterPublicKeyCredential,
+        RequestChallengeResponse, Url, Uuid, WebauthnError,
+    },
+    Webauthn, WebauthnBuilder,
+};
+use webauthn_rs_proto::ResidentKeyRequirement;
+
+use super::{
+    authenticated_user::VerifiedUser, create_session, sessions::format_timestamp, USER_HOME_PAGE,
+};
+use crate::{client_ip::ClientIp, AppState};
+
+/// How long the browser has to complete a registration or authentication after it was started
+const PASSKEY_CEREMONY_LIFETIME: time::Duration = time::Duration::minutes(5);
+/// Passkey names are only shown to the user to tell their passkeys apart
+const MAX_PASSKEY_NAME_LENGTH: usize = 64;
+const DEFAULT_PASSKEY_NAME: &str = "Passkey";
+
+#[derive(Debug, Error)]
+pub(crate) enum PasskeyConfigurationError {
+    #[error("The server URL needs a host to be used as the passkey relying party")]
+    NoHost,
+    #[error("The server URL is not a valid origin for passkeys")]
+    InvalidOrigin,
+    #[error("Error setting up passkeys")]
+    Webauthn(#[from] WebauthnError),
+}
+
+/// Passkeys are bound to the host of the server URL and can only be used on it
+pub(crate) fn create_webauthn(server_url: &Uri) -> Result<Webauthn, PasskeyConfigurationError> {
+    let host = server_url.host().ok_or(PasskeyConfigurationError::NoHost)?;
+    let origin = Url::parse(&server_url.to_string())
+        .map_err(|_| PasskeyConfigurationError::InvalidOrigin)?;
+
+    let webauthn = WebauthnBuilder::new(host, &origin)?
+        .rp_name("Melt")
+        .build()?;
+    Ok(webauthn)
+}
+
+#[derive(Debug, Error)]
+enum PasskeyError {
+    #[error("The passkey registration or sign in does not exist or expired. Please try again.")]
+    CeremonyExpired,
+    #[error("The passkey is not registered")]
+    UnknownPasskey,
+    #[error("The passkey can not be used without entering an email address. Please use another authenticator.")]
+    NotDiscoverable,
+    #[error("The passkey could not be verified")]
+    Verification(#[from] WebauthnError),
+}
+
+impl IntoResponse for PasskeyError {
+    fn into_response(self) -> Response {
+        tracing::warn!("Passkey ceremony failed: {:?}", self);
+        let status = match self {
+            PasskeyError::CeremonyExpired | PasskeyError::NotDiscoverable => {
+                StatusCode::BAD_REQUEST
+            }
+            PasskeyError::UnknownPasskey | PasskeyError::Verification(_) => {
+                StatusCode::UNAUTHORIZED
+            }
+        };
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// A passkey as shown on the apps page
+pub(crate) struct PasskeyInfo {
+    /// The row id and not the credential id
+    pub(crate) id: i64,
+    pub(crate) name: String,
+    pub(crate) created_at: String,
+    pub(crate) last_used_at: String,
+}
+
+pub(crate) async fn list_passkeys(connection: &Connection, user_id: &str) -> Vec<PasskeyInfo> {
+    let mut rows = connection
+        .query(
+            "SELECT rowid, name, created_at_utc, last_used_at_utc
+            FROM passkeys
+            WHERE user_id = :user_id
+            ORDER BY created_at_utc",
+            named_params![":user_id": user_id],
+        )
+        .await
+        .unwrap();
+
+    let mut passkeys = Vec::new();
+    while let Some(row) = rows.next().await.unwrap() {
+        passkeys.push(PasskeyInfo {
+            id: row.get(0).unwrap(),
+            name: row.get(1).unwrap(),
+            created_at: format_timestamp(row.get(2).unwrap()),
+            last_used_at: row
+                .get::<Option<i64>>(3)
+                .unwrap()
+                .map(format_timestamp)
+                .unwrap_or_else(|| "Never".to_owned()),
+        });
+    }
+
+    passkeys
+}
+
+/// The random id that passkeys store to identify the user without revealing the email address or user id.
+/// It is created when the user adds their first passkey.
+async fn user_handle(connection: &Connection, user_id: &str) -> Uuid {
+    let mut rows = connection
+        .query(
+            "UPDATE users
+            SET passkey_user_handle = COALESCE(passkey_user_handle, :user_handle)
+            WHERE id = :id
+            RETURNING passkey_user_handle",
+            named_params![
+                ":id": user_id,
+                ":user_handle": Uuid::new_v4().to_string(),
+            ],
+        )
+        .await
+        .unwrap();
+
+    let row = rows.next().await.unwrap().unwrap();
+    Uuid::parse_str(&row.get::<String>(0).unwrap()).unwrap()
+}
+
+/// Stores the challenge state until the browser completes the ceremony and returns its id
+async fn store_ceremony(
+    state: &AppState,
+    user_id: Option<&str>,
+    ceremony: &impl Serialize,
+) -> String {
+    let ceremony_id = nanoid!();
+    let expires_at = state.clock.now() + PASSKEY_CEREMONY_LIFETIME;
+    state
+        .connection
+        .execute(
+            "INSERT INTO passkey_ceremonies (id, user_id, state, expires_at_utc)
+            VALUES (:id, :user_id, :state, :expires_at)",
+            named_params![
+                ":id": ceremony_id.clone(),
+                ":user_id": user_id,
+                ":state": serde_json::to_string(ceremony).unwrap(),
+                ":expires_at": expires_at.unix_timestamp(),
+            ],
+        )
+        .await
+        .unwrap();
+
+    ceremony_id
+}
+
+/// Deletes the ceremony so its challenge can only be answered once and returns its state
+async fn take_ceremony<T: DeserializeOwned>(
+    state: &AppState,
+    ceremony_id: &str,
+    user_id: Option<&str>,
+) -> Result<T, PasskeyError> {
+    let mut rows = state
+        .connection
+        .query(
+            "DELETE FROM passkey_ceremonies
+            WHERE id = :id AND user_id IS :user_id AND expires_at_utc >= :now
+            RETURNING state",
+            named_params![
+                ":id": ceremony_id,
+                ":user_id": user_id,
+                ":now": state.clock.now().unix_timestamp(),
+            ],
+        )
+        .await
+        .unwrap();
+
+    let row = rows
+        .next()
+        .await
+        .unwrap()
+        .ok_or(PasskeyError::CeremonyExpired)?;
+    Ok(serde_json::from_str(&row.get::<String>(0).unwrap()).unwrap())
+}
+
+#[derive(Serialize)]
+struct CeremonyStart<T> {
+    ceremony_id: String,
+    /// Passed to the WebAuthn API of the browser
+    options: T,
+}
+
+async fn start_registration(
+    VerifiedUser(user): VerifiedUser,
+    State(state): State<AppState>,
+) -> Result<Json<CeremonyStart<CreationChallengeResponse>>, PasskeyError> {
+    let user_handle = user_handle(&state.connection, &user.id).await;
+
+    // Don't let the user register a passkey twice on the same authenticator
+    let mut rows = state
+        .connection
+        .query(
+            "SELECT passkey FROM passkeys WHERE user_id = :user_id",
+            named_params![":user_id": user.id.clone()],
+        )
+        .await
+        .unwrap();
+
+    let mut exclude_credentials = Vec::new();
+    while let Some(row) = rows.next().await.unwrap() {
+        let passkey: Passkey = serde_json::from_str(&row.get::<String>(0).unwrap()).unwrap();
+        exclude_credentials.push(passkey.cred_id().clone());
+    }
+
+    let (mut options, registration) = state.webauthn.start_passkey_registration(
+        user_handle,
+        &user.email_address,
+        &user.name,
+        Some(exclude_credentials),
+    )?;
+
+    // Sign in starts without knowing the user so the passkey has to be stored on the authenticator
+    if let Some(selection) = options.public_key.authenticator_selection.as_mut() {
+        selection.resident_key = Some(ResidentKeyRequirement::Required);
+        selection.require_resident_key = true;
+    }
+
+    let ceremony_id = store_ceremony(&state, Some(&user.id), &registration).await;
+    Ok(Json(CeremonyStart {
+        ceremony_id,
+        options,
+    }))
+}
+
+#[derive(Deserialize)]
+struct FinishRegistrationRequest {
+    ceremony_id: String,
+    name: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+async fn finish_registration(
+    VerifiedUser(user): VerifiedUser,
+    State(state): State<AppState>,
+    Json(request): Json<FinishRegistrationRequest>,
+) -> Result<StatusCode, PasskeyError> {
+    // Only the user that started the registration can finish it
+    let registration: PasskeyRegistration =
+        take_ceremony(&state, &request.ceremony_id, Some(&user.id)).await?;
+
+    // Browsers report if they could not create a discoverable credential despite it being required
+    if request
+        .credential
+        .extensions
+        .cred_props
+        .as_ref()
+        .is_some_and(|properties| !properties.rk)
+    {
+        return Err(PasskeyError::NotDiscoverable);
+    }
+    let passkey = state
+        .webauthn
+        .finish_passkey_registration(&request.credential, &registration)?;
+
+    let name = request.name.trim();
+    let name = if name.is_empty() {
+        DEFAULT_PASSKEY_NAME.to_owned()
+    } else {
+        name.chars().take(MAX_PASSKEY_NAME_LENGTH).collect()
+    };
+
+    state
+        .connection
+        .execute(
+            "INSERT INTO passkeys (id, user_id, name, passkey, created_at_utc)
+            VALUES (:id, :user_id, :name, :passkey, :created_at_utc)",
+            named_params![
+                ":id": URL_SAFE_NO_PAD.encode(passkey.cred_id()),
+                ":user_id": user.id,
+                ":name": name,
+                ":passkey": serde_json::to_string(&passkey).unwrap(),
+                ":created_at_utc": state.clock.now().unix_timestamp(),
+            ],
+        )
+        .await
+        .unwrap();
+
+    Ok(StatusCode::CREATED)
+}
+
+async fn start_authentication(
+    State(state): State<AppState>,
+) -> Result<Json<CeremonyStart<RequestChallengeResponse>>, PasskeyError> {
+    // The user is not known yet and picks the passkey in the browser
+    let (options, authentication) = state.webauthn.start_discoverable_authentication()?;
+
+    let ceremony_id = store_ceremony(&state, None, &authentication).await;
+    Ok(Json(CeremonyStart {
+        ceremony_id,
+        options,
+    }))
+}
+
+#[derive(Deserialize)]
+struct FinishAuthenticationRequest {
+    ceremony_id: String,
+    credential: PublicKeyCredential,
+}
+
+async fn finish_authentication(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    ClientIp(ip_address): ClientIp,
+    headers: HeaderMap,
+    Json(request): Json<FinishAuthenticationRequest>,
+) -> Result<Response, PasskeyError> {
+    let authentication: DiscoverableAuthentication =
+        take_ceremony(&state, &request.ceremony_id, None).await?;
+
+    let (user_handle, credential_id) = state
+        .webauthn
+        .identify_discoverable_authentication(&request.credential)?;
+    let credential_id = URL_SAFE_NO_PAD.encode(credential_id);
+
+    let mut rows = state
+        .connection
+        .query(
+            "SELECT passkeys.user_id, passkeys.passkey
+            FROM passkeys
+            JOIN users ON passkeys.user_id = users.id
+            WHERE passkeys.id = :id AND users.passkey_user_handle = :user_handle",
+            named_params![
+                ":id": credential_id.clone(),
+                ":user_handle": user_handle.to_string(),
+            ],
+        )
+        .await
+        .unwrap();
+
+    // The passkey might have been deleted on the apps page but not on the authenticator
+    let row = rows
+        .next()
+        .await
+        .unwrap()
+        .ok_or(PasskeyError::UnknownPasskey)?;
+    let user_id: String = row.get(0).unwrap();
+    let mut passkey: Passkey = serde_json::from_str(&row.get::<String>(1).unwrap()).unwrap();
+
+    let result = state.webauthn.finish_discoverable_authentication(
+        &request.credential,
+        authentication,
+        &[DiscoverableKey::from(&passkey)],
+    )?;
+
+    // Store the new signature counter which is used to detect cloned authenticators
+    passkey.update_credential(&result);
+    state
+        .connection
+        .execute(
+            "UPDATE passkeys SET passkey = :passkey, last_used_at_utc = :now WHERE id = :id",
+            named_params![
+                ":id": credential_id,
+                ":passkey": serde_json::to_string(&passkey).unwrap(),
+                ":now": state.clock.now().unix_timestamp(),
+            ],
+        )
+        .await
+        .unwrap();
+
+    let jar = create_session(&state, jar, ip_address, &headers, user_id).await;
+    Ok((jar, StatusCode::NO_CONTENT).into_response())
+}
+
+async fn delete_passkey(
+    VerifiedUser(user): VerifiedUser,
+    State(AppState { connection, .. }): State<AppState>,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    // Users can only delete their own passkeys
+    connection
+        .execute(
+            "DELETE FROM passkeys WHERE rowid = :rowid AND user_id = :user_id",
+            named_params![
+                ":rowid": id,
+                ":user_id": user.id,
+            ],
+        )
+        .await
+        .unwrap();
+
+    Redirect::to(USER_HOME_PAGE)
+}
+
+pub(crate) fn create_router() -> Router<AppState> {
+    // JSON requests from other sites are blocked by CORS and the session cookie is same site strict
+    Router::new()
+        .route(
+            "/apps/passkeys/registration/start",
+            post(start_registration),
+        )
+        .route(
+            "/apps/passkeys/registration/finish",
+            post(finish_registration),
+        )
+        .route("/apps/passkeys/:id/delete", post(delete_passkey))
+        .route("/signin/passkey/start", post(start_authentication))
+        .route("/signin/passkey/finish", post(finish_authentication))
+}
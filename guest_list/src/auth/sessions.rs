@@ -0,0 +1,159 @@
+//! Lets users see where they are signed in and revoke sessions
+
+use askama_axum::{IntoResponse, Template};
+use axum::{
+    extract::{Path, State},
+    response::Redirect,
+    routing::{get, post},
+    Router,
+};
+use axum_extra::extract::{cookie::Cookie, CookieJar};
+use libsql::named_params;
+use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
+
+use super::{
+    authenticated_user::{VerifiedUser, SESSION_COOKIE},
+    SESSION_IDLE_TIMEOUT,
+};
+use crate::AppState;
+
+const DATE_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year]-[month]-[day] [hour]:[minute] UTC");
+
+struct SessionInfo {
+    /// The row id and not the secret session id that is stored in the cookie
+    id: i64,
+    created_at: String,
+    last_seen_at: String,
+    user_agent: String,
+    ip_address: String,
+    is_current: bool,
+}
+
+#[derive(Template)]
+#[template(path = "sessions.html")]
+struct SessionsTemplate {
+    sessions: Vec<SessionInfo>,
+}
+
+pub(super) fn format_timestamp(timestamp: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(timestamp)
+        .unwrap()
+        .format(DATE_FORMAT)
+        .unwrap()
+}
+
+async fn get_sessions_page(
+    VerifiedUser(user): VerifiedUser,
+    State(AppState {
+        connection, clock, ..
+    }): State<AppState>,
+) -> impl IntoResponse {
+    let now = clock.now().unix_timestamp();
+    let mut rows = connection
+        .query(
+            "SELECT
+                rowid,
+                id,
+                created_at_utc,
+                idle_expires_at_utc,
+                user_agent,
+                ip_address
+            FROM sessions
+            WHERE user_id = :user_id
+                AND expires_at_utc > :now
+                AND idle_expires_at_utc > :now
+            ORDER BY idle_expires_at_utc DESC",
+            named_params![
+                ":user_id": user.id.clone(),
+                ":now": now,
+            ],
+        )
+        .await
+        .unwrap();
+
+    let mut sessions = Vec::new();
+    while let Some(row) = rows.next().await.unwrap() {
+        let session_id: String = row.get(1).unwrap();
+        // Activity is recorded by extending the idle expiration
+        let idle_expires_at: i64 = row.get(3).unwrap();
+        let last_seen_at = idle_expires_at - SESSION_IDLE_TIMEOUT.whole_seconds();
+
+        sessions.push(SessionInfo {
+            id: row.get(0).unwrap(),
+            created_at: format_timestamp(row.get(2).unwrap()),
+            last_seen_at: format_timestamp(last_seen_at),
+            user_agent: row
+                .get::<Option<String>>(4)
+                .unwrap()
+                .unwrap_or_else(|| "Unknown".to_owned()),
+            ip_address: row
+                .get::<Option<String>>(5)
+                .unwrap()
+                .unwrap_or_else(|| "Unknown".to_owned()),
+            is_current: session_id == user.session_id,
+        });
+    }
+
+    SessionsTemplate { sessions }
+}
+
+async fn sign_out_session(
+    VerifiedUser(user): VerifiedUser,
+    State(AppState { connection, .. }): State<AppState>,
+    jar: CookieJar,
+    Path(id): Path<i64>,
+) -> impl IntoResponse {
+    // Users can only sign out their own sessions
+    let mut rows = connection
+        .query(
+            "DELETE FROM sessions WHERE rowid = :rowid AND user_id = :user_id RETURNING id",
+            named_params![
+                ":rowid": id,
+                ":user_id": user.id,
+            ],
+        )
+        .await
+        .unwrap();
+
+    let is_current = match rows.next().await.unwrap() {
+        Some(row) => row.get::<String>(0).unwrap() == user.session_id,
+        None => false,
+    };
+    if is_current {
+        return (
+            jar.remove(Cookie::build(SESSION_COOKIE).path("/")),
+            Redirect::to("/"),
+        )
+            .into_response();
+    }
+
+    Redirect::to("/apps/sessions").into_response()
+}
+
+async fn sign_out_everywhere(
+    VerifiedUser(user): VerifiedUser,
+    State(AppState { connection, .. }): State<AppState>,
+    jar: CookieJar,
+) -> impl IntoResponse {
+    connection
+        .execute(
+            "DELETE FROM sessions WHERE user_id = :user_id",
+            named_params![":user_id": user.id],
+        )
+        .await
+        .unwrap();
+
+    (
+        jar.remove(Cookie::build(SESSION_COOKIE).path("/")),
+        Redirect::to("/"),
+    )
+}
+
+pub(crate) fn create_router() -> Router<AppState> {
+    // The session cookie is same site strict so other sites can not submit these forms on behalf of the user
+    Router::new()
+        .route("/apps/sessions", get(get_sessions_page))
+        .route("/apps/sessions/signout", post(sign_out_everywhere))
+        .route("/apps/sessions/:id/signout", post(sign_out_session))
+}
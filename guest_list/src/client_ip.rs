@@ -0,0 +1,173 @@
+//! Finds the address of the client when guest_list runs behind reverse proxies
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap, StatusCode},
+};
+
+use crate::AppState;
+
+/// Proxies that are trusted if none are configured. guest_list only listens on the loopback address
+/// so only a reverse proxy on the same machine can connect to it.
+pub(crate) const DEFAULT_TRUSTED_PROXIES: [IpAddr; 2] = [
+    IpAddr::V4(Ipv4Addr::LOCALHOST),
+    IpAddr::V6(Ipv6Addr::LOCALHOST),
+];
+
+/// The address of the client that made the request.
+/// Forwarding headers are only read from trusted proxies as anyone else can set them to anything.
+pub(crate) struct ClientIp(pub(crate) IpAddr);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        Ok(ClientIp(client_ip(
+            peer.ip(),
+            &parts.headers,
+            &state.configuration.trusted_proxies,
+        )))
+    }
+}
+
+/// Walks the chain of proxies from the closest one and returns the first address that is not trusted
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    if !trusted_proxies.contains(&client) {
+        return client;
+    }
+
+    // The standard header takes precedence over the older de facto standard
+    let forwarded = forwarded_for(headers).unwrap_or_else(|| x_forwarded_for(headers));
+    for address in forwarded.iter().rev() {
+        // Obfuscated or unknown addresses can't be followed further
+        let Some(address) = address else {
+            break;
+        };
+
+        client = *address;
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+    }
+
+    client
+}
+
+/// Addresses from X-Forwarded-For headers, from the client to the closest proxy
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(parse_address)
+        .collect()
+}
+
+/// Addresses from the `for` parameters of Forwarded headers (RFC 7239) or None if there are none
+fn forwarded_for(headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let addresses = headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_address(value))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    (!addresses.is_empty()).then_some(addresses)
+}
+
+/// Parses an address that might be quoted, in brackets or have a port
+fn parse_address(address: &str) -> Option<IpAddr> {
+    let address = address.trim().trim_matches('"');
+    address
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| {
+            address
+                .parse::<SocketAddr>()
+                .ok()
+                .map(|address| address.ip())
+        })
+        .or_else(|| {
+            address
+                .strip_prefix('[')
+                .and_then(|address| address.strip_suffix(']'))
+                .and_then(|address| address.parse().ok())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use axum::http::HeaderMap;
+
+    use super::{client_ip, DEFAULT_TRUSTED_PROXIES};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let headers = headers(&[("x-forwarded-for", "203.0.113.1")]);
+        let client = client_ip(ip("198.51.100.7"), &headers, &DEFAULT_TRUSTED_PROXIES);
+        assert_eq!(client, ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn uses_first_untrusted_address_from_the_right() {
+        // The client can put anything at the start of the header
+        let headers = headers(&[("x-forwarded-for", "192.0.2.9, 203.0.113.1, 127.0.0.1")]);
+        let client = client_ip(ip("127.0.0.1"), &headers, &DEFAULT_TRUSTED_PROXIES);
+        assert_eq!(client, ip("203.0.113.1"));
+    }
+
+    #[test]
+    fn reads_forwarded_header() {
+        let headers = headers(&[
+            (
+                "forwarded",
+                "for=192.0.2.60;proto=https, for=\"[2001:db8::1]:4711\"",
+            ),
+            ("x-forwarded-for", "203.0.113.1"),
+        ]);
+        let client = client_ip(ip("::1"), &headers, &DEFAULT_TRUSTED_PROXIES);
+        assert_eq!(client, ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn uses_peer_without_headers() {
+        let client = client_ip(ip("127.0.0.1"), &HeaderMap::new(), &DEFAULT_TRUSTED_PROXIES);
+        assert_eq!(client, ip("127.0.0.1"));
+    }
+}
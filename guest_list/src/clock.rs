@@ -0,0 +1,18 @@
+//! Source of the current time.
+//! Code that depends on time takes a clock so it can be run deterministically with a fixed time.
+
+use time::OffsetDateTime;
+
+pub(crate) trait Clock: Send + Sync {
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// The actual time of the system
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
@@ -18,15 +18,27 @@ async fn create_database() -> Database {
         .expect("Failed to connect to database")
 }
 
+/// Changes to the tables in the order they were made.
+/// The database stores how many of them were applied as its user version so each one runs once.
+/// Only add new migrations to the end and never change applied ones.
+const MIGRATIONS: &[&str] = &[
+    include_str!("./migrations/0001_expiry_indexes.sql"),
+    include_str!("./migrations/0002_session_idle_timeout.sql"),
+    include_str!("./migrations/0003_session_details.sql"),
+    include_str!("./migrations/0004_unique_email_addresses.sql"),
+    include_str!("./migrations/0005_email_verification.sql"),
+    include_str!("./migrations/0006_signin_codes.sql"),
+    include_str!("./migrations/0007_signin_browser.sql"),
+    include_str!("./migrations/0008_passkeys.sql"),
+];
+
 /// Creates the database and initializes it with the tables
 pub(super) async fn initialize_database() -> Connection {
     let database = create_database().await;
 
     let connection = database.connect().unwrap();
 
-    let query = include_str!("./create_tables.sql");
-    connection
-        .execute_batch(&query)
+    create_tables(&connection)
         .await
         .expect("Failed to create tables");
 
@@ -34,3 +46,30 @@ pub(super) async fn initialize_database() -> Connection {
 
     connection
 }
+
+/// Creates the initial tables if they don't exist and migrates them to the latest version
+pub(crate) async fn create_tables(connection: &Connection) -> Result<(), libsql::Error> {
+    let query = include_str!("./create_tables.sql");
+    connection.execute_batch(query).await?;
+
+    let mut rows = connection.query("PRAGMA user_version", ()).await?;
+    let version: i64 = match rows.next().await? {
+        Some(row) => row.get(0)?,
+        None => 0,
+    };
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let version = index + 1;
+        tracing::info!("Migrating database to version {}", version);
+
+        // The version is updated in the same transaction so a failed migration leaves no partial changes
+        let transaction = connection.transaction().await?;
+        transaction.execute_batch(migration).await?;
+        transaction
+            .execute_batch(&format!("PRAGMA user_version = {version};"))
+            .await?;
+        transaction.commit().await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,74 @@
+//! Texts of emails in the languages users can receive them in.
+//! New languages are added as a variant with their texts.
+
+/// Language of the emails sent to a user
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) enum Locale {
+    #[default]
+    English,
+}
+
+pub(crate) struct SignInEmailText {
+    pub(crate) subject: &'static str,
+    pub(crate) greeting: &'static str,
+    pub(crate) instructions: &'static str,
+    pub(crate) button: &'static str,
+    pub(crate) code: &'static str,
+    pub(crate) expires: &'static str,
+    pub(crate) requested_from: &'static str,
+    pub(crate) ip_address: &'static str,
+    pub(crate) device: &'static str,
+    pub(crate) unknown_device: &'static str,
+    pub(crate) not_me: &'static str,
+}
+
+const ENGLISH_SIGN_IN_EMAIL: SignInEmailText = SignInEmailText {
+    subject: "Sign in to your account",
+    greeting: "Hi",
+    instructions: "Use the link below in the browser you requested the sign in from.",
+    button: "Sign in",
+    code: "Or enter this code on the sign in page of that browser:",
+    expires: "The link and the code can be used once and expire at",
+    requested_from: "The sign in was requested from",
+    ip_address: "IP address",
+    device: "Device",
+    unknown_device: "Unknown",
+    not_me: "If this wasn't you, you can ignore this email. The link and the code only work in the browser that requested the sign in.",
+};
+
+impl Locale {
+    /// Picks the first supported language of an Accept-Language header.
+    /// Browsers list the languages in order of preference so the weights are not needed.
+    pub(crate) fn from_accept_language(header: Option<&str>) -> Self {
+        header
+            .into_iter()
+            .flat_map(|header| header.split(','))
+            .filter_map(|language| {
+                let tag = language.split(';').next()?.trim();
+                let primary = tag.split('-').next()?;
+                Self::from_language(primary)
+            })
+            .next()
+            .unwrap_or_default()
+    }
+
+    fn from_language(language: &str) -> Option<Self> {
+        match language.to_ascii_lowercase().as_str() {
+            "en" => Some(Self::English),
+            _ => None,
+        }
+    }
+
+    /// The language tag for the lang attribute of HTML emails
+    pub(crate) fn language(self) -> &'static str {
+        match self {
+            Self::English => "en",
+        }
+    }
+
+    pub(crate) fn sign_in_email(self) -> &'static SignInEmailText {
+        match self {
+            Self::English => &ENGLISH_SIGN_IN_EMAIL,
+        }
+    }
+}
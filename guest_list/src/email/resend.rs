@@ -0,0 +1,57 @@
+//! Sends emails through the resend.com API
+
+use std::sync::Arc;
+
+use axum::async_trait;
+use reqwest::Client;
+use serde::Serialize;
+
+use super::{EmailMessage, EmailSender, SendEmailError};
+
+#[derive(Serialize, Debug)]
+struct SendEmailRequest {
+    to: String,
+    from: String,
+    subject: String,
+    html: String,
+    text: String,
+}
+
+pub(crate) struct ResendSender {
+    client: Client,
+    auth_token: Arc<str>,
+    from: String,
+}
+
+impl ResendSender {
+    pub(crate) fn new(client: Client, auth_token: Arc<str>, from: String) -> Self {
+        Self {
+            client,
+            auth_token,
+            from,
+        }
+    }
+}
+
+#[async_trait]
+impl EmailSender for ResendSender {
+    async fn send(&self, message: EmailMessage) -> Result<(), SendEmailError> {
+        let request = SendEmailRequest {
+            to: message.to.0,
+            from: self.from.clone(),
+            subject: message.subject,
+            html: message.html,
+            text: message.text,
+        };
+
+        self.client
+            .post("https://api.resend.com/emails")
+            .bearer_auth(&self.auth_token)
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+//! Keeps emails local instead of sending them, to run without network and to inspect emails during development
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::async_trait;
+use lettre::{message::Mailbox, AsyncFileTransport, AsyncTransport, Tokio1Executor};
+
+use super::{EmailMessage, EmailSender, SendEmailError};
+
+/// Writes every email as an .eml file into a directory
+pub(crate) struct DirectorySender {
+    transport: AsyncFileTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl DirectorySender {
+    pub(crate) async fn new(directory: PathBuf, from: Mailbox) -> Result<Self, std::io::Error> {
+        tokio::fs::create_dir_all(&directory).await?;
+        Ok(Self {
+            transport: AsyncFileTransport::new(directory),
+            from,
+        })
+    }
+}
+
+#[async_trait]
+impl EmailSender for DirectorySender {
+    async fn send(&self, message: EmailMessage) -> Result<(), SendEmailError> {
+        let message = message.build(self.from.clone())?;
+        let id = self.transport.send(message).await?;
+        tracing::debug!("Wrote email {}", id);
+        Ok(())
+    }
+}
+
+/// Emails kept by the log sender. Older emails are dropped so it doesn't grow forever.
+const MAX_LOGGED_EMAILS: usize = 100;
+
+/// Logs that an email was sent and keeps it in memory so tests can inspect it.
+/// The content is not logged as it contains sign in links and codes.
+#[derive(Clone, Default)]
+pub(crate) struct LogSender {
+    sent: Arc<Mutex<Vec<EmailMessage>>>,
+}
+
+impl LogSender {
+    /// The emails that were sent, oldest first
+    #[cfg(test)]
+    pub(crate) fn sent(&self) -> Vec<EmailMessage> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl EmailSender for LogSender {
+    async fn send(&self, message: EmailMessage) -> Result<(), SendEmailError> {
+        tracing::info!(
+            "Email to {} with subject {:?}",
+            message.to.0,
+            message.subject
+        );
+
+        let mut sent = self.sent.lock().unwrap();
+        if sent.len() == MAX_LOGGED_EMAILS {
+            sent.remove(0);
+        }
+        sent.push(message);
+        Ok(())
+    }
+}
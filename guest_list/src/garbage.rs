@@ -0,0 +1,293 @@
+//! Deletes expired sessions, sign in attempts, passkey ceremonies, sign in code failures and unverified accounts which would otherwise be kept forever
+
+use std::{sync::Arc, time::Duration};
+
+use libsql::{named_params, Connection};
+use time::OffsetDateTime;
+use tokio::time::MissedTickBehavior;
+
+use crate::{auth::CODE_FAILURE_WINDOW, clock::Clock};
+
+/// How often garbage is collected if no interval is configured
+pub(crate) const DEFAULT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Rows deleted per query so a large backlog does not block the database for long
+const BATCH_SIZE: u64 = 500;
+
+/// Accounts whose email address was not verified within this time are deleted.
+/// This frees up addresses that were signed up by someone else than the owner.
+const UNVERIFIED_ACCOUNT_LIFETIME: time::Duration = time::Duration::days(1);
+
+const DELETE_EXPIRED_SESSIONS: &str = "DELETE FROM sessions WHERE id IN (
+    SELECT id FROM sessions
+    WHERE expires_at_utc < :before OR idle_expires_at_utc < :before
+    LIMIT :batch_size
+)";
+
+const DELETE_EXPIRED_SIGNIN_ATTEMPTS: &str = "DELETE FROM signin_attempts WHERE id IN (
+    SELECT id FROM signin_attempts WHERE expires_at_utc < :before LIMIT :batch_size
+)";
+
+const DELETE_EXPIRED_PASSKEY_CEREMONIES: &str = "DELETE FROM passkey_ceremonies WHERE id IN (
+    SELECT id FROM passkey_ceremonies WHERE expires_at_utc < :before LIMIT :batch_size
+)";
+
+const DELETE_OLD_SIGNIN_CODE_FAILURES: &str = "DELETE FROM signin_code_failures WHERE rowid IN (
+    SELECT rowid FROM signin_code_failures WHERE failed_at_utc < :before LIMIT :batch_size
+)";
+
+const DELETE_SIGNIN_ATTEMPTS_OF_UNVERIFIED_USERS: &str = "DELETE FROM signin_attempts WHERE id IN (
+    SELECT signin_attempts.id FROM signin_attempts
+    JOIN users ON signin_attempts.user_id = users.id
+    WHERE users.email_verified_at_utc IS NULL AND users.created_at_utc < :before
+    LIMIT :batch_size
+)";
+
+const DELETE_UNVERIFIED_USERS: &str = "DELETE FROM users WHERE id IN (
+    SELECT id FROM users
+    WHERE email_verified_at_utc IS NULL AND created_at_utc < :before
+    LIMIT :batch_size
+)";
+
+/// Number of rows deleted by a collection
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct CollectedGarbage {
+    pub(crate) sessions: u64,
+    pub(crate) signin_attempts: u64,
+    pub(crate) passkey_ceremonies: u64,
+    pub(crate) signin_code_failures: u64,
+    pub(crate) unverified_users: u64,
+}
+
+/// Runs forever and cleans up expired app data every interval
+pub(crate) async fn collect_garbage(
+    connection: Connection,
+    clock: Arc<dyn Clock>,
+    period: Duration,
+) {
+    // It is not important that it cleans exactly on time, but it is important that it happens regularly
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+
+        match collect_expired(&connection, clock.now()).await {
+            Ok(collected) => tracing::debug!(
+                "Deleted {} expired sessions, {} expired sign in attempts, {} expired passkey ceremonies, {} old sign in code failures and {} unverified users",
+                collected.sessions,
+                collected.signin_attempts,
+                collected.passkey_ceremonies,
+                collected.signin_code_failures,
+                collected.unverified_users
+            ),
+            Err(error) => tracing::error!("Error collecting garbage: {:?}", error),
+        }
+    }
+}
+
+/// Deletes all sessions, sign in attempts and passkey ceremonies that expired before now,
+/// sign in code failures that no longer count towards the limits and accounts that were not verified in time
+pub(crate) async fn collect_expired(
+    connection: &Connection,
+    now: OffsetDateTime,
+) -> Result<CollectedGarbage, libsql::Error> {
+    let unverified_before = (now - UNVERIFIED_ACCOUNT_LIFETIME).unix_timestamp();
+    let failures_before = (now - CODE_FAILURE_WINDOW).unix_timestamp();
+    let now = now.unix_timestamp();
+    let sessions = delete_in_batches(connection, DELETE_EXPIRED_SESSIONS, now).await?;
+    let mut signin_attempts =
+        delete_in_batches(connection, DELETE_EXPIRED_SIGNIN_ATTEMPTS, now).await?;
+
+    let passkey_ceremonies =
+        delete_in_batches(connection, DELETE_EXPIRED_PASSKEY_CEREMONIES, now).await?;
+    let signin_code_failures =
+        delete_in_batches(connection, DELETE_OLD_SIGNIN_CODE_FAILURES, failures_before).await?;
+
+    // Unverified users can't have sessions or passkeys as completing a sign in verifies the address
+    signin_attempts += delete_in_batches(
+        connection,
+        DELETE_SIGNIN_ATTEMPTS_OF_UNVERIFIED_USERS,
+        unverified_before,
+    )
+    .await?;
+    let unverified_users =
+        delete_in_batches(connection, DELETE_UNVERIFIED_USERS, unverified_before).await?;
+
+    Ok(CollectedGarbage {
+        sessions,
+        signin_attempts,
+        passkey_ceremonies,
+        signin_code_failures,
+        unverified_users,
+    })
+}
+
+async fn delete_in_batches(
+    connection: &Connection,
+    query: &str,
+    before: i64,
+) -> Result<u64, libsql::Error> {
+    let mut deleted = 0;
+    loop {
+        let count = connection
+            .execute(
+                query,
+                named_params![
+                    ":before": before,
+                    ":batch_size": BATCH_SIZE as i64,
+                ],
+            )
+            .await?;
+        deleted += count;
+
+        if count < BATCH_SIZE {
+            return Ok(deleted);
+        }
+
+        // Let requests use the connection between batches
+        tokio::task::yield_now().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libsql::{named_params, Builder, Connection};
+    use time::OffsetDateTime;
+
+    use super::{collect_expired, BATCH_SIZE, UNVERIFIED_ACCOUNT_LIFETIME};
+    use crate::database::create_tables;
+
+    const NOW: i64 = 1_700_000_000;
+
+    async fn connect() -> Connection {
+        let database = Builder::new_local(":memory:").build().await.unwrap();
+        let connection = database.connect().unwrap();
+        create_tables(&connection).await.unwrap();
+        connection
+    }
+
+    async fn insert_user(connection: &Connection, id: &str, created_at: i64, verified: bool) {
+        connection
+            .execute(
+                "INSERT INTO users (id, name, email_address, created_at_utc, email_verified_at_utc)
+                VALUES (:id, :id, :id || '@example.com', :created_at, :verified_at)",
+                named_params![
+                    ":id": id,
+                    ":created_at": created_at,
+                    ":verified_at": verified.then_some(created_at),
+                ],
+            )
+            .await
+            .unwrap();
+    }
+
+    /// Inserts sessions with ids that start with the prefix
+    async fn insert_sessions(
+        connection: &Connection,
+        prefix: &str,
+        count: u64,
+        expires_at: i64,
+        idle_expires_at: i64,
+    ) {
+        connection
+            .execute(
+                "WITH RECURSIVE numbers(number) AS (
+                    SELECT 1 UNION ALL SELECT number + 1 FROM numbers WHERE number < :count
+                )
+                INSERT INTO sessions (id, user_id, created_at_utc, expires_at_utc, idle_expires_at_utc)
+                SELECT :prefix || number, 'user', 0, :expires_at, :idle_expires_at FROM numbers",
+                named_params![
+                    ":prefix": prefix,
+                    ":count": count as i64,
+                    ":expires_at": expires_at,
+                    ":idle_expires_at": idle_expires_at,
+                ],
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn insert_signin_attempt(
+        connection: &Connection,
+        id: &str,
+        user_id: &str,
+        expires_at: i64,
+    ) {
+        connection
+            .execute(
+                "INSERT INTO signin_attempts (id, user_id, browser_token, code, expires_at_utc)
+                VALUES (:id, :user_id, 'browser', '000000', :expires_at)",
+                named_params![
+                    ":id": id,
+                    ":user_id": user_id,
+                    ":expires_at": expires_at,
+                ],
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn count(connection: &Connection, table: &str) -> u64 {
+        let mut rows = connection
+            .query(&format!("SELECT COUNT(*) FROM {table}"), ())
+            .await
+            .unwrap();
+        rows.next().await.unwrap().unwrap().get(0).unwrap()
+    }
+
+    fn now() -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(NOW).unwrap()
+    }
+
+    #[tokio::test]
+    async fn keeps_rows_that_expire_exactly_now() {
+        let connection = connect().await;
+        insert_user(&connection, "user", NOW, true).await;
+        insert_sessions(&connection, "expired", 1, NOW - 1, NOW + 60).await;
+        insert_sessions(&connection, "idle", 1, NOW + 60, NOW - 1).await;
+        insert_sessions(&connection, "now", 1, NOW, NOW).await;
+        insert_signin_attempt(&connection, "expired", "user", NOW - 1).await;
+        insert_signin_attempt(&connection, "now", "user", NOW).await;
+
+        let collected = collect_expired(&connection, now()).await.unwrap();
+
+        assert_eq!(collected.sessions, 2);
+        assert_eq!(collected.signin_attempts, 1);
+        assert_eq!(count(&connection, "sessions").await, 1);
+        assert_eq!(count(&connection, "signin_attempts").await, 1);
+    }
+
+    #[tokio::test]
+    async fn deletes_across_batch_boundaries() {
+        for expired in [BATCH_SIZE - 1, BATCH_SIZE, BATCH_SIZE + 1, 2 * BATCH_SIZE] {
+            let connection = connect().await;
+            insert_user(&connection, "user", NOW, true).await;
+            insert_sessions(&connection, "expired", expired, NOW - 1, NOW - 1).await;
+            insert_sessions(&connection, "valid", 3, NOW + 60, NOW + 60).await;
+
+            let collected = collect_expired(&connection, now()).await.unwrap();
+
+            assert_eq!(
+                collected.sessions, expired,
+                "with {expired} expired sessions"
+            );
+            assert_eq!(count(&connection, "sessions").await, 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn deletes_users_that_did_not_verify_in_time() {
+        let connection = connect().await;
+        let lifetime = UNVERIFIED_ACCOUNT_LIFETIME.whole_seconds();
+        insert_user(&connection, "late", NOW - lifetime - 1, false).await;
+        insert_user(&connection, "exactly", NOW - lifetime, false).await;
+        insert_user(&connection, "verified", NOW - lifetime - 1, true).await;
+        insert_signin_attempt(&connection, "late", "late", NOW + 60).await;
+
+        let collected = collect_expired(&connection, now()).await.unwrap();
+
+        assert_eq!(collected.unverified_users, 1);
+        assert_eq!(collected.signin_attempts, 1);
+        assert_eq!(count(&connection, "users").await, 2);
+    }
+}
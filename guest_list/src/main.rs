@@ -1,12 +1,22 @@
 use std::env::VarError;
-use std::{env, net::Ipv4Addr, time::Duration};
-
-use crate::auth::authenticated_user::AuthenticatedUser;
+use std::num::{NonZeroU64, ParseIntError};
+use std::{
+    env,
+    net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::auth::authenticated_user::VerifiedUser;
+use crate::auth::passkeys::{self, PasskeyInfo};
 use crate::auth::USER_HOME_PAGE;
-use crate::secrets::Secrets;
+use crate::clock::{Clock, SystemClock};
+use crate::email::EmailSender;
 use askama_axum::{IntoResponse, Template};
 use axum::http::uri::InvalidUri;
-use axum::{extract::State, http, http::Uri, response::Redirect, routing::get, Form, Router};
+use axum::{
+    extract::State, http, http::Uri, middleware, response::Redirect, routing::get, Form, Router,
+};
 use dotenv::dotenv;
 use libsql::{named_params, Builder, Connection, Database};
 use serde::{self, Deserialize};
@@ -14,25 +24,41 @@ use thiserror::Error;
 use tower_http::services::ServeDir;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use webauthn_rs::Webauthn;
 
 mod auth;
+mod client_ip;
+mod clock;
 mod database;
 mod email;
+mod garbage;
 mod routes;
 mod secrets;
 
 const GUEST_LIST_URL_KEY: &str = "GUEST_LIST_URL";
+/// Comma separated addresses of reverse proxies whose forwarding headers are trusted. Defaults to the loopback addresses.
+const TRUSTED_PROXIES_KEY: &str = "TRUSTED_PROXIES";
+/// Seconds between deleting expired sessions and sign in attempts. Defaults to 5 minutes.
+const GARBAGE_COLLECTION_INTERVAL_KEY: &str = "GARBAGE_COLLECTION_INTERVAL";
 
 #[derive(Debug, Error)]
 enum Error {
     #[error("GUEST_LIST_URL environment variable must be set")]
     NoGuestListUrl(#[from] VarError),
-    #[error("Error getting secrets")]
-    GetSecretsError(#[from] secrets::GetSecretsError),
+    #[error("Error setting up email")]
+    EmailConfigurationError(#[from] email::EmailConfigurationError),
+    #[error("Error setting up passkeys")]
+    PasskeyConfigurationError(#[from] passkeys::PasskeyConfigurationError),
     #[error("Error serving")]
     ServeError(#[from] std::io::Error),
     #[error("Invalid URL in GUEST_LIST_URL environment variable")]
     InvalidUrl(#[from] InvalidUri),
+    #[error(
+        "GARBAGE_COLLECTION_INTERVAL environment variable must be a positive number of seconds"
+    )]
+    InvalidGarbageCollectionInterval(#[from] ParseIntError),
+    #[error("TRUSTED_PROXIES environment variable must be a comma separated list of IP addresses")]
+    InvalidTrustedProxy(#[from] AddrParseError),
 }
 
 #[tokio::main]
@@ -45,13 +71,32 @@ async fn main() -> Result<(), Error> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let secrets = secrets::get_secrets().await?;
+    #[cfg(debug_assertions)]
+    {
+        // Use .env files only for debug convenience
+        let result = dotenv();
+        if let Err(error) = result {
+            tracing::warn!("No dotenv loaded in debug mode: {}", error);
+        }
+    }
+
+    let email_sender = email::sender_from_env().await?;
 
     // Set up database
     let connection = database::initialize_database().await;
 
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+
     // Set up background workers
-    let _handle = tokio::spawn(collect_garbage(connection.clone()));
+    let garbage_collection_interval = match env::var(GARBAGE_COLLECTION_INTERVAL_KEY) {
+        Ok(seconds) => Duration::from_secs(seconds.parse::<NonZeroU64>()?.get()),
+        Err(_) => garbage::DEFAULT_INTERVAL,
+    };
+    let _handle = tokio::spawn(garbage::collect_garbage(
+        connection.clone(),
+        clock.clone(),
+        garbage_collection_interval,
+    ));
 
     // Configuration
     //TODO implement fallback to localhost
@@ -60,14 +105,25 @@ async fn main() -> Result<(), Error> {
 
     let url = Uri::try_from(url)?;
     let port = url.port().map(|port| port.as_u16()).unwrap_or(80);
-    let configuration = Configuration { server_url: url };
+    let webauthn = Arc::new(passkeys::create_webauthn(&url)?);
+    let trusted_proxies = match env::var(TRUSTED_PROXIES_KEY) {
+        Ok(proxies) => proxies
+            .split(',')
+            .map(|proxy| proxy.trim().parse())
+            .collect::<Result<Vec<IpAddr>, _>>()?,
+        Err(_) => client_ip::DEFAULT_TRUSTED_PROXIES.to_vec(),
+    };
+    let configuration = Configuration {
+        server_url: url,
+        trusted_proxies,
+    };
 
-    let client = reqwest::Client::new();
     let app_state = AppState {
         connection,
-        client,
         configuration,
-        secrets,
+        email_sender,
+        webauthn,
+        clock,
     };
 
     let auth_routes = auth::create_router();
@@ -77,6 +133,11 @@ async fn main() -> Result<(), Error> {
         .route("/", get(routes::index::get_page))
         .route(USER_HOME_PAGE, get(get_apps_page))
         .merge(auth_routes)
+        // Only routes need the user and not the files
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::authenticated_user::load_session,
+        ))
         // If the route could not be matched it might be a file
         .fallback_service(ServeDir::new("public"))
         .with_state(app_state);
@@ -87,7 +148,12 @@ async fn main() -> Result<(), Error> {
         .unwrap();
 
     tracing::debug!("listening on http://{}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await?;
+    // The address of the client is stored with sessions
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
@@ -97,35 +163,30 @@ pub(crate) struct Configuration {
     /// The server URL under which the server can be reached publicly for clients.
     /// A user clicking an email link will be brought to this URL.
     server_url: Uri,
+    /// Reverse proxies that are allowed to tell the address of the client
+    trusted_proxies: Vec<IpAddr>,
 }
 
 #[derive(Clone)]
 pub(crate) struct AppState {
     connection: Connection,
-    client: reqwest::Client,
     configuration: Configuration,
-    secrets: Secrets,
+    email_sender: Arc<dyn EmailSender>,
+    webauthn: Arc<Webauthn>,
+    clock: Arc<dyn Clock>,
 }
 
 #[derive(Template)]
 #[template(path = "apps.html")]
-struct AppsTemplate {}
+struct AppsTemplate {
+    passkeys: Vec<PasskeyInfo>,
+}
 
 async fn get_apps_page(
-    user: AuthenticatedUser,
+    VerifiedUser(user): VerifiedUser,
     State(app_state): State<AppState>,
 ) -> impl IntoResponse {
-    let apps_template = AppsTemplate {};
+    let passkeys = passkeys::list_passkeys(&app_state.connection, &user.id).await;
+    let apps_template = AppsTemplate { passkeys };
     apps_template
 }
-
-/// Runs forever and cleans up expired app data about every 5 minutes
-async fn collect_garbage(connection: Connection) {
-    // It is not important that it cleans exactly every 5 minutes, but it is important that it happens regularly
-    // Duration from minutes is experimental currently
-    let mut interval = tokio::time::interval(Duration::from_secs(5 * 60));
-    loop {
-        interval.tick().await;
-        let now = time::OffsetDateTime::now_utc().unix_timestamp();
-    }
-}
@@ -0,0 +1,3 @@
+-- Expired rows are looked up by the garbage collection
+CREATE INDEX IF NOT EXISTS sessions_expires_at_utc ON sessions(expires_at_utc);
+CREATE INDEX IF NOT EXISTS signin_attempts_expires_at_utc ON signin_attempts(expires_at_utc);
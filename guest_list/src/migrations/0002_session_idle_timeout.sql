@@ -0,0 +1,7 @@
+-- Sessions expire earlier if they are not used
+ALTER TABLE sessions ADD COLUMN idle_expires_at_utc INTEGER NOT NULL DEFAULT 0;
+
+-- Existing sessions were not tracked for activity and keep their lifetime
+UPDATE sessions SET idle_expires_at_utc = expires_at_utc;
+
+CREATE INDEX IF NOT EXISTS sessions_idle_expires_at_utc ON sessions(idle_expires_at_utc);
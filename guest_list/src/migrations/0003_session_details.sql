@@ -0,0 +1,4 @@
+-- Shown to users to recognize their sessions
+ALTER TABLE sessions ADD COLUMN created_at_utc INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE sessions ADD COLUMN user_agent TEXT;
+ALTER TABLE sessions ADD COLUMN ip_address TEXT;
@@ -0,0 +1,20 @@
+-- Addresses are normalized before they are stored so each address can only have one account.
+-- Rust lowercases all characters while SQLite only lowercases ASCII which covers practically all addresses.
+UPDATE users SET email_address = lower(trim(email_address));
+
+-- Only the oldest account of an address could be signed in to after normalizing
+DELETE FROM sessions WHERE user_id IN (
+    SELECT id FROM users WHERE rowid NOT IN (
+        SELECT min(rowid) FROM users GROUP BY email_address
+    )
+);
+DELETE FROM signin_attempts WHERE user_id IN (
+    SELECT id FROM users WHERE rowid NOT IN (
+        SELECT min(rowid) FROM users GROUP BY email_address
+    )
+);
+DELETE FROM users WHERE rowid NOT IN (
+    SELECT min(rowid) FROM users GROUP BY email_address
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_address ON users(email_address);
@@ -0,0 +1,9 @@
+ALTER TABLE users ADD COLUMN created_at_utc INTEGER NOT NULL DEFAULT 0;
+-- Accounts are only activated after the user followed a link sent to their address
+ALTER TABLE users ADD COLUMN email_verified_at_utc INTEGER;
+
+-- The creation time of existing accounts was not tracked
+UPDATE users SET created_at_utc = CAST(strftime('%s', 'now') AS INTEGER);
+
+-- Existing accounts were all created through a link sent to their address
+UPDATE users SET email_verified_at_utc = created_at_utc;
@@ -0,0 +1,20 @@
+-- Existing attempts have no code and expire within minutes
+DELETE FROM signin_attempts;
+
+-- Alternative to the link for users that read their email on another device
+ALTER TABLE signin_attempts ADD COLUMN code TEXT NOT NULL DEFAULT '';
+
+-- Codes are looked up by the email address of the user
+CREATE INDEX IF NOT EXISTS signin_attempts_user_id ON signin_attempts(user_id);
+
+-- Wrong codes are limited per account and per IP address over a time window instead of per attempt,
+-- as requesting a new attempt would otherwise reset the limit
+CREATE TABLE IF NOT EXISTS signin_code_failures (
+    -- Null if the email address has no account
+    user_id TEXT,
+    ip_address TEXT NOT NULL,
+    failed_at_utc INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS signin_code_failures_user_id ON signin_code_failures(user_id, failed_at_utc);
+CREATE INDEX IF NOT EXISTS signin_code_failures_ip_address ON signin_code_failures(ip_address, failed_at_utc);
+CREATE INDEX IF NOT EXISTS signin_code_failures_failed_at_utc ON signin_code_failures(failed_at_utc);
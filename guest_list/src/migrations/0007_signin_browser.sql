@@ -0,0 +1,5 @@
+-- Existing attempts are not bound to a browser and expire within minutes
+DELETE FROM signin_attempts;
+
+-- Random token of the cookie of the browser that requested the sign in
+ALTER TABLE signin_attempts ADD COLUMN browser_token TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,28 @@
+-- Random UUID that passkeys identify the user with. Set when the first passkey is added
+ALTER TABLE users ADD COLUMN passkey_user_handle TEXT;
+CREATE UNIQUE INDEX IF NOT EXISTS users_passkey_user_handle ON users(passkey_user_handle);
+
+CREATE TABLE IF NOT EXISTS passkeys (
+    -- Base64 URL encoded credential id chosen by the authenticator
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    -- Shown to users to tell their passkeys apart
+    name TEXT NOT NULL,
+    -- Serialized passkey with the public key and signature counter
+    passkey TEXT NOT NULL,
+    created_at_utc INTEGER NOT NULL,
+    last_used_at_utc INTEGER,
+    FOREIGN KEY(user_id) REFERENCES users(id)
+);
+CREATE INDEX IF NOT EXISTS passkeys_user_id ON passkeys(user_id);
+
+-- Challenges of started passkey registrations and sign ins
+CREATE TABLE IF NOT EXISTS passkey_ceremonies (
+    id TEXT PRIMARY KEY,
+    -- Only set for registrations as the user is not known before a sign in
+    user_id TEXT,
+    state TEXT NOT NULL,
+    expires_at_utc INTEGER NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES users(id)
+);
+CREATE INDEX IF NOT EXISTS passkey_ceremonies_expires_at_utc ON passkey_ceremonies(expires_at_utc);
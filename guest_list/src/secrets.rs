@@ -5,7 +5,6 @@ use bitwarden::secrets_manager::secrets::{
     SecretGetRequest, SecretIdentifiersRequest, SecretIdentifiersResponse,
 };
 use bitwarden::Client;
-use dotenv::dotenv;
 use std::sync::Arc;
 use thiserror::Error;
 use uuid::{uuid, Uuid};
@@ -35,15 +34,6 @@ pub(super) async fn get_secrets() -> Result<Secrets, GetSecretsError> {
     let mut client = Client::new(None);
 
     // Set up machine account token
-    #[cfg(debug_assertions)]
-    {
-        // Use .env files only for debug convenience
-        let result = dotenv();
-        if let Err(error) = result {
-            tracing::warn!("No dotenv loaded in debug mode: {}", error);
-        }
-    }
-
     let token = std::env::var("BWS_TOKEN").map_err(GetSecretsError::NoBwsToken)?;
     let token = AccessTokenLoginRequest {
         access_token: token,
@@ -0,0 +1,149 @@
+use std::{
+    io::SeekFrom,
+    ops::Range,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use axum::{async_trait, body::Body};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tokio_util::io::ReaderStream;
+
+use super::{BlobError, BlobId, BlobStorage, BlobUpload};
+
+/// Stores blobs as files in a directory.
+/// Uploads are written to a separate directory first and moved into place once they are complete
+/// so readers never see partial content.
+/// The index with owners and expiry is stored next to them so blobs survive restarts.
+pub(crate) struct FilesystemStorage {
+    blobs_directory: PathBuf,
+    uploads_directory: PathBuf,
+    index_path: PathBuf,
+    upload_counter: AtomicU64,
+}
+
+impl FilesystemStorage {
+    pub(crate) async fn new(root: PathBuf) -> Result<Self, std::io::Error> {
+        let blobs_directory = root.join("blobs");
+        let uploads_directory = root.join("uploads");
+
+        fs::create_dir_all(&blobs_directory).await?;
+        fs::create_dir_all(&uploads_directory).await?;
+
+        // Uploads that were interrupted by a crash can't be resumed
+        let mut uploads = fs::read_dir(&uploads_directory).await?;
+        while let Some(entry) = uploads.next_entry().await? {
+            let path = entry.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "part")
+            {
+                fs::remove_file(&path).await?;
+            }
+        }
+
+        Ok(Self {
+            blobs_directory,
+            uploads_directory,
+            index_path: root.join("index"),
+            upload_counter: AtomicU64::new(0),
+        })
+    }
+
+    fn blob_path(&self, id: &BlobId) -> PathBuf {
+        self.blobs_directory.join(id.as_str())
+    }
+}
+
+#[async_trait]
+impl BlobStorage for FilesystemStorage {
+    async fn start_upload(&self) -> Result<Box<dyn BlobUpload>, BlobError> {
+        let number = self.upload_counter.fetch_add(1, Ordering::Relaxed);
+        let path = self.uploads_directory.join(format!("{}.part", number));
+        let file = File::create(&path).await?;
+
+        Ok(Box::new(FilesystemUpload {
+            file,
+            path,
+            blobs_directory: self.blobs_directory.clone(),
+        }))
+    }
+
+    async fn read(&self, id: &BlobId, range: Range<u64>) -> Result<Body, BlobError> {
+        let mut file = match File::open(self.blob_path(id)).await {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Err(BlobError::NotFound)
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        file.seek(SeekFrom::Start(range.start)).await?;
+        let stream = ReaderStream::new(file.take(range.end - range.start));
+        Ok(Body::from_stream(stream))
+    }
+
+    async fn delete(&self, id: &BlobId) -> Result<(), BlobError> {
+        match fs::remove_file(self.blob_path(id)).await {
+            Ok(()) => Ok(()),
+            // Already deleted is fine
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn load_index(&self) -> Result<Option<Vec<u8>>, BlobError> {
+        match fs::read(&self.index_path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Replaces the index atomically so a crash while writing does not lose the previous one
+    async fn save_index(&self, index: &[u8]) -> Result<(), BlobError> {
+        let temporary_path = self.index_path.with_extension("tmp");
+        let mut file = File::create(&temporary_path).await?;
+        file.write_all(index).await?;
+        file.sync_all().await?;
+        fs::rename(&temporary_path, &self.index_path).await?;
+        Ok(())
+    }
+}
+
+struct FilesystemUpload {
+    file: File,
+    path: PathBuf,
+    blobs_directory: PathBuf,
+}
+
+#[async_trait]
+impl BlobUpload for FilesystemUpload {
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), BlobError> {
+        self.file.write_all(chunk).await?;
+        Ok(())
+    }
+
+    async fn commit(mut self: Box<Self>, id: &BlobId) -> Result<(), BlobError> {
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+
+        let result = fs::rename(&self.path, self.blobs_directory.join(id.as_str())).await;
+        if let Err(error) = result {
+            let _ = fs::remove_file(&self.path).await;
+            return Err(error.into());
+        }
+
+        Ok(())
+    }
+
+    async fn abort(self: Box<Self>) {
+        drop(self.file);
+        if let Err(error) = fs::remove_file(&self.path).await {
+            tracing::warn!("Error removing aborted upload: {:?}", error);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Encodes the blob index so owners and expiry survive restarts of storages that outlive the process
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tls_codec::{Deserialize, Serialize, TlsDeserialize, TlsSerialize, TlsSize, VLBytes};
+use tokio::time::Instant;
+
+use super::{BlobError, BlobId, BlobIndex, BlobMetadata};
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+struct StoredIndex {
+    blobs: Vec<StoredBlob>,
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+struct StoredBlob {
+    id: VLBytes,
+    size: u64,
+    references: Vec<StoredReference>,
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+struct StoredReference {
+    owner: VLBytes,
+    /// Instants can't be stored so the expiry is stored as milliseconds since the unix epoch
+    expires_at: u64,
+}
+
+pub(super) fn encode(index: &BlobIndex) -> Result<Vec<u8>, BlobError> {
+    let now = Instant::now();
+    let system_now = SystemTime::now();
+
+    let blobs = index
+        .blobs
+        .iter()
+        .map(|(id, metadata)| StoredBlob {
+            id: id.as_str().as_bytes().into(),
+            size: metadata.size,
+            references: metadata
+                .expires_at_by_owner
+                .iter()
+                .map(|(owner, expires_at)| {
+                    let expires_at = system_now + expires_at.saturating_duration_since(now);
+                    StoredReference {
+                        owner: owner.as_bytes().into(),
+                        expires_at: expires_at
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_millis() as u64,
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+
+    Ok(StoredIndex { blobs }.tls_serialize_detached()?)
+}
+
+pub(super) fn decode(mut data: &[u8]) -> Result<BlobIndex, BlobError> {
+    let now = Instant::now();
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let stored = StoredIndex::tls_deserialize(&mut data)?;
+    let mut index = BlobIndex::default();
+    for blob in stored.blobs {
+        let id = std::str::from_utf8(blob.id.as_slice())
+            .map_err(|_| BlobError::InvalidIndex)
+            .and_then(|id| BlobId::parse(id).map_err(|_| BlobError::InvalidIndex))?;
+
+        let mut expires_at_by_owner = HashMap::new();
+        for reference in blob.references {
+            let owner = String::from_utf8(reference.owner.as_slice().to_vec())
+                .map_err(|_| BlobError::InvalidIndex)?;
+            // References that expired while the server was down are collected on the next run
+            let remaining = Duration::from_millis(reference.expires_at).saturating_sub(since_epoch);
+
+            *index.usage_by_owner.entry(owner.clone()).or_default() += blob.size;
+            expires_at_by_owner.insert(owner, now + remaining);
+        }
+
+        index.blobs.insert(
+            id,
+            BlobMetadata {
+                size: blob.size,
+                expires_at_by_owner,
+            },
+        );
+    }
+
+    Ok(index)
+}
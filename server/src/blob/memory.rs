@@ -0,0 +1,58 @@
+use std::{collections::HashMap, ops::Range, sync::Arc};
+
+use axum::{async_trait, body::Body};
+use bytes::{Bytes, BytesMut};
+use tokio::sync::Mutex;
+
+use super::{BlobError, BlobId, BlobStorage, BlobUpload};
+
+/// Keeps blobs in memory. Useful for development and testing as nothing survives a restart.
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+    blobs: Arc<Mutex<HashMap<BlobId, Bytes>>>,
+}
+
+#[async_trait]
+impl BlobStorage for MemoryStorage {
+    async fn start_upload(&self) -> Result<Box<dyn BlobUpload>, BlobError> {
+        Ok(Box::new(MemoryUpload {
+            content: BytesMut::new(),
+            blobs: self.blobs.clone(),
+        }))
+    }
+
+    async fn read(&self, id: &BlobId, range: Range<u64>) -> Result<Body, BlobError> {
+        let blobs = self.blobs.lock().await;
+        let blob = blobs.get(id).ok_or(BlobError::NotFound)?;
+        // Slicing is cheap as it only increases the reference count
+        let content = blob.slice(range.start as usize..range.end as usize);
+        Ok(Body::from(content))
+    }
+
+    async fn delete(&self, id: &BlobId) -> Result<(), BlobError> {
+        let mut blobs = self.blobs.lock().await;
+        blobs.remove(id);
+        Ok(())
+    }
+}
+
+struct MemoryUpload {
+    content: BytesMut,
+    blobs: Arc<Mutex<HashMap<BlobId, Bytes>>>,
+}
+
+#[async_trait]
+impl BlobUpload for MemoryUpload {
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), BlobError> {
+        self.content.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    async fn commit(self: Box<Self>, id: &BlobId) -> Result<(), BlobError> {
+        let mut blobs = self.blobs.lock().await;
+        blobs.insert(id.clone(), self.content.freeze());
+        Ok(())
+    }
+
+    async fn abort(self: Box<Self>) {}
+}
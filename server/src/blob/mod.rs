@@ -0,0 +1,592 @@
+use std::{collections::HashMap, ops::Range, sync::Arc, time::Duration};
+
+use axum::{
+    async_trait,
+    body::{Body, Bytes},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::prelude::*;
+use futures_util::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::{sync::Mutex, time::Instant};
+
+pub(crate) use routes::create_router;
+
+pub(crate) mod filesystem;
+mod index;
+pub(crate) mod memory;
+mod routes;
+
+/// The SHA-256 digest of the blob content encoded as URL safe base64 without padding.
+/// Blobs are content addressed so the same content is only stored once.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct BlobId(String);
+
+impl BlobId {
+    /// Length of a SHA-256 digest encoded in base64 without padding
+    const LENGTH: usize = 43;
+
+    fn from_digest(digest: &[u8]) -> Self {
+        Self(BASE64_URL_SAFE_NO_PAD.encode(digest))
+    }
+
+    /// Parses an id from user input.
+    /// This is important as the id is used as file name by the file system storage.
+    pub(crate) fn parse(id: &str) -> Result<Self, BlobError> {
+        if id.len() != Self::LENGTH {
+            return Err(BlobError::InvalidId);
+        }
+
+        let is_valid = id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+
+        if !is_valid {
+            return Err(BlobError::InvalidId);
+        }
+
+        Ok(Self(id.to_owned()))
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum BlobError {
+    #[error("Blob not found")]
+    NotFound,
+    #[error("Invalid blob id")]
+    InvalidId,
+    #[error("Blob exceeds the maximum blob size")]
+    TooLarge,
+    #[error("Blob exceeds the storage quota of the user")]
+    QuotaExceeded,
+    #[error("Requested range can not be satisfied")]
+    RangeNotSatisfiable { size: u64 },
+    #[error("Error reading request body")]
+    BodyError(#[from] axum::Error),
+    #[error("Error accessing blob storage")]
+    IoError(#[from] std::io::Error),
+    #[error("Error encoding or decoding the blob index")]
+    IndexCodecError(#[from] tls_codec::Error),
+    #[error("The blob index contains invalid data")]
+    InvalidIndex,
+}
+
+impl IntoResponse for BlobError {
+    fn into_response(self) -> Response {
+        match self {
+            BlobError::NotFound => StatusCode::NOT_FOUND.into_response(),
+            BlobError::InvalidId => StatusCode::BAD_REQUEST.into_response(),
+            BlobError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+            BlobError::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE.into_response(),
+            BlobError::RangeNotSatisfiable { size } => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+            )
+                .into_response(),
+            BlobError::BodyError(error) => {
+                tracing::debug!("Error reading blob upload: {:?}", error);
+                StatusCode::BAD_REQUEST.into_response()
+            }
+            BlobError::IoError(error) => {
+                tracing::error!("Error accessing blob storage: {:?}", error);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            error @ (BlobError::IndexCodecError(_) | BlobError::InvalidIndex) => {
+                tracing::error!("Error with the blob index: {:?}", error);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+/// A backend that stores the blob content.
+/// Metadata like ownership and expiry is handled by the [`BlobStore`] so backends only need to deal with bytes.
+#[async_trait]
+pub(crate) trait BlobStorage: Send + Sync {
+    /// Starts a new upload. The content can only be read after the upload is committed.
+    async fn start_upload(&self) -> Result<Box<dyn BlobUpload>, BlobError>;
+
+    /// Reads the bytes in the range of a committed blob.
+    /// The range is expected to be within the bounds of the blob.
+    async fn read(&self, id: &BlobId, range: Range<u64>) -> Result<Body, BlobError>;
+
+    async fn delete(&self, id: &BlobId) -> Result<(), BlobError>;
+
+    /// Reads the index saved with [`BlobStorage::save_index`].
+    /// Storages that don't outlive the process have nothing to restore.
+    async fn load_index(&self) -> Result<Option<Vec<u8>>, BlobError> {
+        Ok(None)
+    }
+
+    /// Persists the encoded index of owners and expiry next to the content
+    async fn save_index(&self, _index: &[u8]) -> Result<(), BlobError> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+pub(crate) trait BlobUpload: Send {
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), BlobError>;
+
+    /// Makes the uploaded content available under the id.
+    /// Replaces existing content with the same id which is fine as it is the same content.
+    async fn commit(self: Box<Self>, id: &BlobId) -> Result<(), BlobError>;
+
+    /// Discards the uploaded content
+    async fn abort(self: Box<Self>);
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BlobLimits {
+    pub(crate) max_blob_size: u64,
+    /// The total size of blobs a user can store at the same time
+    pub(crate) quota_per_owner: u64,
+    pub(crate) default_ttl: Duration,
+    pub(crate) max_ttl: Duration,
+}
+
+impl Default for BlobLimits {
+    fn default() -> Self {
+        Self {
+            max_blob_size: 64 * 1024 * 1024,
+            quota_per_owner: 256 * 1024 * 1024,
+            default_ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            max_ttl: Duration::from_secs(30 * 24 * 60 * 60),
+        }
+    }
+}
+
+struct BlobMetadata {
+    size: u64,
+    /// The owners referencing the blob and when their reference expires.
+    /// A blob is deleted once all references expired.
+    expires_at_by_owner: HashMap<String, Instant>,
+}
+
+#[derive(Default)]
+struct BlobIndex {
+    blobs: HashMap<BlobId, BlobMetadata>,
+    usage_by_owner: HashMap<String, u64>,
+}
+
+impl BlobIndex {
+    fn usage(&self, owner: &str) -> u64 {
+        self.usage_by_owner.get(owner).copied().unwrap_or(0)
+    }
+
+    fn release(&mut self, owner: &str, size: u64) {
+        let Some(usage) = self.usage_by_owner.get_mut(owner) else {
+            return;
+        };
+
+        *usage = usage.saturating_sub(size);
+        if *usage == 0 {
+            self.usage_by_owner.remove(owner);
+        }
+    }
+}
+
+/// Stores blobs like attachments, avatars and group information that are too large to be sent as messages
+#[derive(Clone)]
+pub(crate) struct BlobStore {
+    storage: Arc<dyn BlobStorage>,
+    index: Arc<Mutex<BlobIndex>>,
+    limits: BlobLimits,
+}
+
+pub(crate) struct StoredBlob {
+    pub(crate) id: BlobId,
+    pub(crate) size: u64,
+    pub(crate) ttl: Duration,
+}
+
+impl BlobStore {
+    /// Restores the index of the storage so blobs stored before a restart keep their owners and expiry
+    pub(crate) async fn load(
+        storage: Arc<dyn BlobStorage>,
+        limits: BlobLimits,
+    ) -> Result<Self, BlobError> {
+        let index = match storage.load_index().await? {
+            Some(data) => index::decode(&data)?,
+            None => BlobIndex::default(),
+        };
+
+        Ok(Self {
+            storage,
+            index: Arc::new(Mutex::new(index)),
+            limits,
+        })
+    }
+
+    /// Persists the index after it changed. Called with the lock held so saves don't overtake each other.
+    async fn save_index(&self, index: &BlobIndex) {
+        let result = match index::encode(index) {
+            Ok(data) => self.storage.save_index(&data).await,
+            Err(error) => Err(error),
+        };
+
+        // The content is stored already and the index is saved again with the next change
+        if let Err(error) = result {
+            tracing::error!("Error saving blob index: {:?}", error);
+        }
+    }
+
+    /// Streams the content into the storage without buffering it and computes the id on the way
+    pub(crate) async fn upload<S>(
+        &self,
+        owner: &str,
+        ttl: Option<Duration>,
+        mut content: S,
+    ) -> Result<StoredBlob, BlobError>
+    where
+        S: Stream<Item = Result<Bytes, axum::Error>> + Unpin,
+    {
+        let ttl = ttl
+            .unwrap_or(self.limits.default_ttl)
+            .min(self.limits.max_ttl);
+
+        // Check early to not waste bandwidth on uploads that can not be stored anyway
+        let remaining = {
+            let index = self.index.lock().await;
+            self.limits
+                .quota_per_owner
+                .saturating_sub(index.usage(owner))
+        };
+
+        let mut upload = self.storage.start_upload().await?;
+        let mut hasher = Sha256::new();
+        let mut size: u64 = 0;
+        while let Some(chunk) = content.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(error) => {
+                    upload.abort().await;
+                    return Err(error.into());
+                }
+            };
+
+            size += chunk.len() as u64;
+            if size > self.limits.max_blob_size {
+                upload.abort().await;
+                return Err(BlobError::TooLarge);
+            }
+
+            if size > remaining {
+                upload.abort().await;
+                return Err(BlobError::QuotaExceeded);
+            }
+
+            hasher.update(&chunk);
+            if let Err(error) = upload.write(&chunk).await {
+                upload.abort().await;
+                return Err(error);
+            }
+        }
+
+        let id = BlobId::from_digest(&hasher.finalize());
+        let expires_at = Instant::now() + ttl;
+
+        // Hold the lock while committing so garbage collection can not delete the blob in between
+        let mut index = self.index.lock().await;
+        let index = &mut *index;
+        if let Some(metadata) = index.blobs.get_mut(&id) {
+            // Content already exists
+            upload.abort().await;
+
+            let is_new_owner = !metadata.expires_at_by_owner.contains_key(owner);
+            if is_new_owner {
+                // Quota might have changed while uploading
+                if index.usage_by_owner.get(owner).copied().unwrap_or(0) + size
+                    > self.limits.quota_per_owner
+                {
+                    return Err(BlobError::QuotaExceeded);
+                }
+
+                *index.usage_by_owner.entry(owner.to_owned()).or_default() += size;
+            }
+
+            let owner_expires_at = metadata
+                .expires_at_by_owner
+                .entry(owner.to_owned())
+                .or_insert(expires_at);
+            *owner_expires_at = expires_at.max(*owner_expires_at);
+
+            self.save_index(index).await;
+            return Ok(StoredBlob { id, size, ttl });
+        }
+
+        if index.usage(owner) + size > self.limits.quota_per_owner {
+            upload.abort().await;
+            return Err(BlobError::QuotaExceeded);
+        }
+
+        upload.commit(&id).await?;
+
+        *index.usage_by_owner.entry(owner.to_owned()).or_default() += size;
+        index.blobs.insert(
+            id.clone(),
+            BlobMetadata {
+                size,
+                expires_at_by_owner: HashMap::from([(owner.to_owned(), expires_at)]),
+            },
+        );
+
+        self.save_index(index).await;
+        Ok(StoredBlob { id, size, ttl })
+    }
+
+    pub(crate) async fn size(&self, id: &BlobId) -> Result<u64, BlobError> {
+        let index = self.index.lock().await;
+        let metadata = index.blobs.get(id).ok_or(BlobError::NotFound)?;
+        Ok(metadata.size)
+    }
+
+    pub(crate) async fn read(&self, id: &BlobId, range: Range<u64>) -> Result<Body, BlobError> {
+        self.storage.read(id, range).await
+    }
+
+    /// Removes the reference of the owner to the blob and deletes the blob if nobody else references it
+    pub(crate) async fn delete(&self, owner: &str, id: &BlobId) -> Result<(), BlobError> {
+        let mut index = self.index.lock().await;
+        let Some(metadata) = index.blobs.get_mut(id) else {
+            return Err(BlobError::NotFound);
+        };
+
+        if metadata.expires_at_by_owner.remove(owner).is_none() {
+            // Don't give away that the blob exists
+            return Err(BlobError::NotFound);
+        }
+
+        let size = metadata.size;
+        let is_orphaned = metadata.expires_at_by_owner.is_empty();
+        index.release(owner, size);
+
+        if is_orphaned {
+            index.blobs.remove(id);
+            self.delete_content(id).await;
+        }
+
+        self.save_index(&index).await;
+        Ok(())
+    }
+
+    /// Removes expired references and deletes blobs that are no longer referenced
+    pub(crate) async fn remove_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut index = self.index.lock().await;
+        let index = &mut *index;
+
+        let mut expired_blobs = Vec::new();
+        let mut expired_references = 0;
+        for (id, metadata) in index.blobs.iter_mut() {
+            let size = metadata.size;
+            metadata.expires_at_by_owner.retain(|owner, expires_at| {
+                if *expires_at > now {
+                    return true;
+                }
+
+                expired_references += 1;
+
+                let Some(usage) = index.usage_by_owner.get_mut(owner) else {
+                    return false;
+                };
+
+                *usage = usage.saturating_sub(size);
+                if *usage == 0 {
+                    index.usage_by_owner.remove(owner);
+                }
+
+                false
+            });
+
+            if metadata.expires_at_by_owner.is_empty() {
+                expired_blobs.push(id.clone());
+            }
+        }
+
+        for id in expired_blobs.iter() {
+            index.blobs.remove(id);
+            self.delete_content(id).await;
+        }
+
+        // Expired references of blobs that are still referenced by others change the index too
+        if expired_references > 0 {
+            self.save_index(index).await;
+        }
+
+        expired_blobs.len()
+    }
+
+    /// Deletes the content of a blob that was removed from the index.
+    /// Failing to delete only leaves unreferenced content behind so the index is still saved afterwards,
+    /// otherwise the blob would be back with its owners after a restart.
+    async fn delete_content(&self, id: &BlobId) {
+        if let Err(error) = self.storage.delete(id).await {
+            tracing::error!("Error deleting blob {}: {:?}", id.as_str(), error);
+        }
+    }
+}
+
+/// Runs forever and deletes expired blobs about every minute
+pub(crate) async fn collect_expired_blobs(store: BlobStore) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let count = store.remove_expired().await;
+        if count > 0 {
+            tracing::debug!("Deleted {} expired blobs", count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures_util::stream;
+
+    use super::{memory::MemoryStorage, *};
+
+    const LIMITS: BlobLimits = BlobLimits {
+        max_blob_size: 1024,
+        quota_per_owner: 10,
+        default_ttl: Duration::from_secs(60),
+        max_ttl: Duration::from_secs(60),
+    };
+
+    fn content(data: &'static [u8]) -> impl Stream<Item = Result<Bytes, axum::Error>> + Unpin {
+        stream::iter([Ok(Bytes::from_static(data))])
+    }
+
+    async fn memory_store() -> BlobStore {
+        BlobStore::load(Arc::new(MemoryStorage::default()), LIMITS)
+            .await
+            .unwrap()
+    }
+
+    /// Keeps the content in memory but fails to delete it
+    #[derive(Default)]
+    struct UndeletableStorage {
+        content: MemoryStorage,
+        index: Arc<Mutex<Option<Vec<u8>>>>,
+    }
+
+    #[async_trait]
+    impl BlobStorage for UndeletableStorage {
+        async fn start_upload(&self) -> Result<Box<dyn BlobUpload>, BlobError> {
+            self.content.start_upload().await
+        }
+
+        async fn read(&self, id: &BlobId, range: Range<u64>) -> Result<Body, BlobError> {
+            self.content.read(id, range).await
+        }
+
+        async fn delete(&self, _id: &BlobId) -> Result<(), BlobError> {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied).into())
+        }
+
+        async fn save_index(&self, index: &[u8]) -> Result<(), BlobError> {
+            self.index.lock().await.replace(index.to_vec());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn deleting_releases_quota() {
+        let store = memory_store().await;
+        let blob = store
+            .upload("alice", None, content(b"0123456789"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.upload("alice", None, content(b"a")).await,
+            Err(BlobError::QuotaExceeded)
+        ));
+
+        store.delete("alice", &blob.id).await.unwrap();
+
+        assert!(matches!(
+            store.size(&blob.id).await,
+            Err(BlobError::NotFound)
+        ));
+        store
+            .upload("alice", None, content(b"0123456789"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn expiry_releases_quota() {
+        let store = memory_store().await;
+        let blob = store
+            .upload("alice", Some(Duration::ZERO), content(b"0123456789"))
+            .await
+            .unwrap();
+
+        assert_eq!(store.remove_expired().await, 1);
+
+        assert!(matches!(
+            store.size(&blob.id).await,
+            Err(BlobError::NotFound)
+        ));
+        store
+            .upload("alice", None, content(b"0123456789"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn same_content_is_shared_by_owners() {
+        let store = memory_store().await;
+        let alice = store
+            .upload("alice", None, content(b"shared"))
+            .await
+            .unwrap();
+        let bob = store
+            .upload("bob", Some(Duration::ZERO), content(b"shared"))
+            .await
+            .unwrap();
+        assert_eq!(alice.id, bob.id);
+
+        // The reference of bob expired but alice still references the content
+        assert_eq!(store.remove_expired().await, 0);
+        assert_eq!(store.size(&alice.id).await.unwrap(), 6);
+        assert_eq!(store.index.lock().await.usage("bob"), 0);
+
+        // Others can't delete the content of alice
+        assert!(matches!(
+            store.delete("bob", &alice.id).await,
+            Err(BlobError::NotFound)
+        ));
+
+        store.delete("alice", &alice.id).await.unwrap();
+        assert!(matches!(
+            store.size(&alice.id).await,
+            Err(BlobError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn index_is_saved_when_content_can_not_be_deleted() {
+        let storage = Arc::new(UndeletableStorage::default());
+        let saved_index = storage.index.clone();
+        let store = BlobStore::load(storage, LIMITS).await.unwrap();
+        let blob = store
+            .upload("alice", None, content(b"content"))
+            .await
+            .unwrap();
+
+        store.delete("alice", &blob.id).await.unwrap();
+
+        let saved_index = saved_index.lock().await.clone().unwrap();
+        let restored = index::decode(&saved_index).unwrap();
+        assert!(restored.blobs.is_empty());
+        assert_eq!(restored.usage("alice"), 0);
+    }
+}
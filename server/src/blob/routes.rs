@@ -0,0 +1,238 @@
+use std::{ops::Range, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{BlobError, BlobId};
+use crate::{signed_request::SignedIdentity, AppState};
+
+#[derive(Deserialize, Debug)]
+struct UploadQuery {
+    /// How long the blob should be kept. Capped by the server.
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct UploadResponse {
+    id: String,
+    size: u64,
+    ttl_seconds: u64,
+}
+
+async fn upload_blob(
+    State(state): State<AppState>,
+    signed: SignedIdentity,
+    Query(query): Query<UploadQuery>,
+    body: Body,
+) -> Result<impl IntoResponse, BlobError> {
+    let ttl = query.ttl_seconds.map(Duration::from_secs);
+    // Content that was not signed is discarded before it is committed
+    let body = signed.verify_body(body);
+    let blob = state
+        .blobs
+        .upload(&signed.identity, ttl, body.into_data_stream())
+        .await?;
+
+    tracing::debug!("Stored blob of size {}", blob.size);
+    let location = format!("/blobs/{}", blob.id.as_str());
+    let response = UploadResponse {
+        id: blob.id.as_str().to_owned(),
+        size: blob.size,
+        ttl_seconds: blob.ttl.as_secs(),
+    };
+
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        Json(response),
+    ))
+}
+
+/// Parses a single range of the `bytes` unit. Returns `None` if the whole content should be sent.
+/// Multiple ranges are not supported and like unknown units the header is ignored in that case as allowed by RFC 9110.
+fn parse_range(headers: &HeaderMap, size: u64) -> Result<Option<Range<u64>>, BlobError> {
+    let Some(value) = headers.get(header::RANGE) else {
+        return Ok(None);
+    };
+
+    let Some(range) = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("bytes="))
+    else {
+        return Ok(None);
+    };
+
+    if range.contains(',') {
+        return Ok(None);
+    }
+
+    let Some((start, end)) = range.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    let not_satisfiable = BlobError::RangeNotSatisfiable { size };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=start-end with inclusive end. The end can be u64::MAX so adding one must not overflow.
+        (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(size),
+        // A range that ends before it starts is invalid
+        (Ok(_), Ok(_)) => return Err(not_satisfiable),
+        // bytes=start-
+        (Ok(start), Err(_)) if end.is_empty() => start..size,
+        // bytes=-suffix_length
+        (Err(_), Ok(suffix_length)) if start.is_empty() => {
+            if suffix_length == 0 {
+                return Err(not_satisfiable);
+            }
+
+            size.saturating_sub(suffix_length)..size
+        }
+        _ => return Ok(None),
+    };
+
+    if range.start >= size {
+        return Err(not_satisfiable);
+    }
+
+    Ok(Some(range))
+}
+
+async fn download_blob(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, BlobError> {
+    let id = BlobId::parse(&id)?;
+    let size = state.blobs.size(&id).await?;
+    let range = parse_range(&headers, size)?;
+
+    let (status, range) = match range {
+        Some(range) => (StatusCode::PARTIAL_CONTENT, range),
+        None => (StatusCode::OK, 0..size),
+    };
+
+    let content_length = range.end - range.start;
+    let content_range = format!(
+        "bytes {}-{}/{}",
+        range.start,
+        range.end.saturating_sub(1),
+        size
+    );
+    let body = state.blobs.read(&id, range).await?;
+
+    let mut response = (
+        status,
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_owned()),
+            (header::CONTENT_LENGTH, content_length.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_owned()),
+            // Content addressed blobs never change
+            (header::ETAG, format!("\"{}\"", id.as_str())),
+            (
+                header::CACHE_CONTROL,
+                "private, max-age=31536000, immutable".to_owned(),
+            ),
+        ],
+        body,
+    )
+        .into_response();
+
+    if status == StatusCode::PARTIAL_CONTENT {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+    }
+
+    Ok(response)
+}
+
+async fn delete_blob(
+    State(state): State<AppState>,
+    SignedIdentity { identity, .. }: SignedIdentity,
+    Path((_, id)): Path<(String, String)>,
+) -> Result<StatusCode, BlobError> {
+    let id = BlobId::parse(&id)?;
+    state.blobs.delete(&identity, &id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) fn create_router() -> Router<AppState> {
+    Router::new()
+        .route("/:identity/blobs", post(upload_blob))
+        .route("/:identity/blobs/:id", delete(delete_blob))
+        .route("/blobs/:id", get(download_blob))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn range(value: &'static str, size: u64) -> Result<Option<Range<u64>>, BlobError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, HeaderValue::from_static(value));
+        parse_range(&headers, size)
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range(&HeaderMap::new(), 10).unwrap(), None);
+        assert_eq!(range("bytes=0-4", 10).unwrap(), Some(0..5));
+        assert_eq!(range("bytes=5-", 10).unwrap(), Some(5..10));
+        // The end is capped at the size
+        assert_eq!(range("bytes=5-100", 10).unwrap(), Some(5..10));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(range("bytes=-3", 10).unwrap(), Some(7..10));
+        // Suffixes longer than the content select all of it
+        assert_eq!(range("bytes=-100", 10).unwrap(), Some(0..10));
+        assert!(matches!(
+            range("bytes=-0", 10),
+            Err(BlobError::RangeNotSatisfiable { size: 10 })
+        ));
+    }
+
+    #[test]
+    fn does_not_overflow_at_the_largest_end() {
+        assert_eq!(
+            range("bytes=0-18446744073709551615", 10).unwrap(),
+            Some(0..10)
+        );
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        for value in ["bytes=10-", "bytes=10-20", "bytes=5-4"] {
+            assert!(
+                matches!(
+                    range(value, 10),
+                    Err(BlobError::RangeNotSatisfiable { size: 10 })
+                ),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_unsupported_ranges() {
+        for value in [
+            "items=0-4",
+            "bytes=0-1,3-4",
+            "bytes=a-b",
+            "bytes=18446744073709551616-",
+        ] {
+            assert_eq!(range(value, 10).unwrap(), None, "{}", value);
+        }
+    }
+}
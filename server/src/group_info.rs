@@ -0,0 +1,241 @@
+//! Published group infos let clients join a group with an external commit instead of waiting for an invitation
+
+use std::{io::Read, net::SocketAddr};
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, FromRequest, Path, Request, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, put},
+    Router,
+};
+use base64::prelude::*;
+use openmls::prelude::{
+    Extensions, LeafNodeIndex, MlsMessageIn, MlsMessageInBody, ProposalStore, PublicGroup,
+};
+use openmls_rust_crypto::OpenMlsRustCrypto;
+use tls_codec::{Deserialize, Size, TlsDeserialize, TlsSize, VLBytes};
+
+use crate::{signed_request::SignedIdentity, tls_body::TlsBody, AppState};
+
+/// The fields of an MLS message with a group info (RFC 9420 12.4.3).
+/// openmls does not expose the fields of unverified group infos so they are read from the encoding.
+#[derive(TlsDeserialize, TlsSize)]
+struct GroupInfoFields {
+    _message_version: u16,
+    _wire_format: u16,
+    _context_version: u16,
+    _ciphersuite: u16,
+    group_id: VLBytes,
+    epoch: u64,
+    _tree_hash: VLBytes,
+    _confirmed_transcript_hash: VLBytes,
+    _context_extensions: Extensions,
+    extensions: Extensions,
+    _confirmation_tag: VLBytes,
+    signer: u32,
+    _signature: VLBytes,
+}
+
+/// Copies everything read from the inner reader
+struct RecordingReader<'a, R> {
+    inner: &'a mut R,
+    recorded: Vec<u8>,
+}
+
+impl<R: Read> Read for RecordingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// A verified group info message along with its encoding, which is stored and served as is
+struct PublishedGroupInfo {
+    group_id: Vec<u8>,
+    epoch: u64,
+    /// Identity in the credential of the member that signed the group info
+    signer_identity: Vec<u8>,
+    encoded: Vec<u8>,
+}
+
+fn decoding_error(message: &str) -> tls_codec::Error {
+    tls_codec::Error::DecodingError(message.to_owned())
+}
+
+impl Size for PublishedGroupInfo {
+    fn tls_serialized_len(&self) -> usize {
+        self.encoded.len()
+    }
+}
+
+impl Deserialize for PublishedGroupInfo {
+    fn tls_deserialize<R: Read>(bytes: &mut R) -> Result<Self, tls_codec::Error> {
+        let mut reader = RecordingReader {
+            inner: bytes,
+            recorded: Vec::new(),
+        };
+        let message = MlsMessageIn::tls_deserialize(&mut reader)?;
+        let MlsMessageInBody::GroupInfo(group_info) = message.extract() else {
+            return Err(decoding_error("Expected a group info"));
+        };
+
+        let encoded = reader.recorded;
+        let fields = GroupInfoFields::tls_deserialize(&mut encoded.as_slice())?;
+        let ratchet_tree = fields
+            .extensions
+            .ratchet_tree()
+            .ok_or_else(|| decoding_error("Expected the ratchet tree in the group info"))?
+            .ratchet_tree()
+            .clone();
+
+        // Checks the tree and that the group info is signed by the member at the signer leaf
+        let (group, _) = PublicGroup::from_external(
+            &OpenMlsRustCrypto::default(),
+            ratchet_tree,
+            group_info,
+            ProposalStore::default(),
+        )
+        .map_err(|error| {
+            tracing::debug!("Error verifying group info: {:?}", error);
+            decoding_error("Invalid group info")
+        })?;
+        let signer = group
+            .leaf(LeafNodeIndex::new(fields.signer))
+            .ok_or_else(|| decoding_error("Signer is not a member"))?;
+
+        Ok(Self {
+            group_id: fields.group_id.as_slice().to_vec(),
+            epoch: fields.epoch,
+            signer_identity: signer.credential().identity().to_vec(),
+            encoded,
+        })
+    }
+}
+
+/// The epoch of a stored group info, which was verified when it was published
+fn stored_epoch(encoded: &[u8]) -> Option<u64> {
+    let fields = GroupInfoFields::tls_deserialize(&mut &encoded[..]).ok()?;
+    Some(fields.epoch)
+}
+
+/// Replaces the group info of the group.
+/// Members publish a new group info after every commit as external commits are only valid for the current epoch.
+/// Only members of the group can publish its group info.
+async fn put_group_info(
+    State(state): State<AppState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    signed: SignedIdentity,
+    Path((_, group_id)): Path<(String, String)>,
+    request: Request,
+) -> Result<StatusCode, Response> {
+    // Checked before reading the body so limited clients can't make the server receive and parse it
+    state
+        .rate_limits
+        .group_infos
+        .check(&address.ip())
+        .map_err(IntoResponse::into_response)?;
+
+    let request = request.map(|body| signed.verify_body(body));
+    let TlsBody(group_info) = TlsBody::<PublishedGroupInfo>::from_request(request, &state)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    // Otherwise the group info of one group could be served to clients joining another
+    if BASE64_URL_SAFE_NO_PAD.encode(&group_info.group_id) != group_id {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
+
+    // Anyone can take a group info served by the server, so the signer has to be the one publishing it
+    if BASE64_URL_SAFE_NO_PAD.encode(&group_info.signer_identity) != signed.identity {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let mut group_infos = state.group_infos_by_group.lock().await;
+    // Old group infos can't be used to join anymore, so they must not replace the current one
+    let current_epoch = group_infos
+        .get(&group_id)
+        .and_then(|encoded| stored_epoch(encoded));
+    if current_epoch.is_some_and(|epoch| group_info.epoch < epoch) {
+        return Err(StatusCode::CONFLICT.into_response());
+    }
+
+    group_infos.insert(group_id, group_info.encoded.into());
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_group_info(
+    State(state): State<AppState>,
+    Path(group_id): Path<String>,
+) -> Result<Bytes, StatusCode> {
+    let group_infos = state.group_infos_by_group.lock().await;
+    group_infos
+        .get(&group_id)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+pub(crate) fn create_router() -> Router<AppState> {
+    Router::new()
+        .route("/groups/:id/info", get(get_group_info))
+        .route("/:identity/groups/:id/info", put(put_group_info))
+}
+
+#[cfg(test)]
+mod tests {
+    use openmls::prelude::*;
+    use openmls_basic_credential::SignatureKeyPair;
+    use openmls_rust_crypto::OpenMlsRustCrypto;
+    use tls_codec::Serialize;
+
+    use super::*;
+
+    fn exported_group_info(with_ratchet_tree: bool) -> (MlsGroup, Vec<u8>) {
+        let ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
+        let backend = OpenMlsRustCrypto::default();
+        let signer = SignatureKeyPair::new(ciphersuite.signature_algorithm()).unwrap();
+        let credential = CredentialWithKey {
+            credential: Credential::new(b"alice".to_vec(), CredentialType::Basic).unwrap(),
+            signature_key: signer.public().into(),
+        };
+        let configuration = MlsGroupConfigBuilder::default()
+            .crypto_config(CryptoConfig::with_default_version(ciphersuite))
+            .build();
+        let group = MlsGroup::new(&backend, &signer, &configuration, credential).unwrap();
+
+        let encoded = group
+            .export_group_info(&backend, &signer, with_ratchet_tree)
+            .unwrap()
+            .tls_serialize_detached()
+            .unwrap();
+        (group, encoded)
+    }
+
+    #[test]
+    fn reads_exported_group_info() {
+        let (group, encoded) = exported_group_info(true);
+        let group_info = PublishedGroupInfo::tls_deserialize(&mut encoded.as_slice()).unwrap();
+
+        assert_eq!(group_info.group_id, group.group_id().as_slice());
+        assert_eq!(group_info.epoch, 0);
+        assert_eq!(group_info.signer_identity, b"alice");
+        assert_eq!(group_info.encoded, encoded);
+        assert_eq!(stored_epoch(&encoded), Some(0));
+    }
+
+    #[test]
+    fn rejects_group_info_without_ratchet_tree() {
+        let (_, encoded) = exported_group_info(false);
+        assert!(PublishedGroupInfo::tls_deserialize(&mut encoded.as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_group_info_with_invalid_signature() {
+        let (_, mut encoded) = exported_group_info(true);
+        // The signature is at the end of the encoding
+        *encoded.last_mut().unwrap() ^= 1;
+        assert!(PublishedGroupInfo::tls_deserialize(&mut encoded.as_slice()).is_err());
+    }
+}
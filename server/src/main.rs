@@ -1,34 +1,91 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, env, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use axum::{
-    body::Body,
-    extract::{ws::WebSocket, Path, State, WebSocketUpgrade},
-    http::{HeaderValue, Method, StatusCode},
+    body::{Body, Bytes},
+    extract::{
+        ws::WebSocket, ConnectInfo, FromRef, FromRequest, Path, Query, Request, State,
+        WebSocketUpgrade,
+    },
+    http::{HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     routing::get,
     Json, Router,
 };
 use base64::prelude::*;
-use key_package::KeyPackage;
-use mls_message::MlsMessage;
+use blob::{
+    filesystem::FilesystemStorage, memory::MemoryStorage, BlobLimits, BlobStorage, BlobStore,
+};
+use metrics::Metrics;
+use offline_queue::OfflineQueue;
 use openmls::prelude::*;
 use openmls::{key_packages::KeyPackageIn, prelude::TlsSerializeTrait};
+use openmls_rust_crypto::RustCrypto;
+use origin::{AllowedOrigins, CheckedOrigin};
+use protocol::{Protocol, PROTOCOL_V1};
+use rate_limit::RateLimits;
+use registry::ConnectionRegistry;
+use serde::Deserialize;
+use shutdown::Shutdown;
+use signed_request::SignedIdentity;
+use snapshot::RestoredState;
+use tls::TlsFiles;
+use tls_body::{TlsBody, TlsBodyLimit};
 use tokio::sync::Mutex;
-use tower_http::cors::CorsLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use user_actor::UserActorHandle;
+use user_actor::{Client, UserActorHandle};
 
-mod key_package;
-mod mls_message;
+mod blob;
+mod group_info;
+mod metrics;
+mod offline_queue;
+mod origin;
+mod protocol;
+mod rate_limit;
+mod registry;
+mod shutdown;
+mod signed_request;
+mod snapshot;
+mod tls;
+mod tls_body;
 mod user_actor;
 mod websocket_actor;
 
 #[derive(Clone)]
 struct AppState {
     key_packages_by_identity: Arc<Mutex<HashMap<String, KeyPackageIn>>>,
-    user_actors: Arc<Mutex<Vec<UserActorHandle>>>,
+    /// Serialized group info messages by base64 encoded group id
+    group_infos_by_group: Arc<Mutex<HashMap<String, Bytes>>>,
+    connections: ConnectionRegistry,
+    offline_queue: OfflineQueue,
+    blobs: BlobStore,
+    tls_body_limit: TlsBodyLimit,
+    shutdown: Shutdown,
+    rate_limits: RateLimits,
+    allowed_origins: AllowedOrigins,
+    metrics: Metrics,
+}
+
+impl FromRef<AppState> for TlsBodyLimit {
+    fn from_ref(state: &AppState) -> Self {
+        state.tls_body_limit
+    }
+}
+
+impl FromRef<AppState> for AllowedOrigins {
+    fn from_ref(state: &AppState) -> Self {
+        state.allowed_origins.clone()
+    }
 }
 
+/// Directory to store blobs in. Blobs are kept in memory if it is not set.
+const BLOB_DIRECTORY_KEY: &str = "BLOB_DIRECTORY";
+/// File the key packages and offline queue are persisted in across restarts. Nothing is persisted if it is not set.
+const STATE_FILE_KEY: &str = "STATE_FILE";
+/// Address to listen on. Defaults to 127.0.0.1:3000.
+const BIND_ADDRESS_KEY: &str = "BIND_ADDRESS";
+/// How long to wait for connections to close on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::registry()
@@ -39,6 +96,48 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let storage: Arc<dyn BlobStorage> = match env::var(BLOB_DIRECTORY_KEY) {
+        Ok(directory) => Arc::new(
+            FilesystemStorage::new(PathBuf::from(directory))
+                .await
+                .expect("Failed to set up blob directory"),
+        ),
+        Err(_) => {
+            tracing::warn!("{} not set. Blobs are kept in memory", BLOB_DIRECTORY_KEY);
+            Arc::new(MemoryStorage::default())
+        }
+    };
+    let blobs = BlobStore::load(storage, BlobLimits::default())
+        .await
+        .expect("Failed to load blob index");
+
+    let state_file = env::var(STATE_FILE_KEY).ok().map(PathBuf::from);
+    if state_file.is_none() {
+        tracing::warn!("{} not set. State is lost on restart", STATE_FILE_KEY);
+    }
+
+    let offline_queue = OfflineQueue::default();
+    let restored = match &state_file {
+        Some(path) => snapshot::load(path, &offline_queue)
+            .await
+            .expect("Failed to load state"),
+        None => RestoredState::default(),
+    };
+    let key_packages_by_identity = Arc::new(Mutex::new(restored.key_packages));
+    let group_infos_by_group = Arc::new(Mutex::new(restored.group_infos));
+
+    let shutdown = Shutdown::default();
+    let rate_limits = RateLimits::from_env().expect("Failed to read rate limits");
+    let allowed_origins = AllowedOrigins::from_env().expect("Failed to read allowed origins");
+    let tls_files = TlsFiles::from_env().expect("Failed to read TLS configuration");
+    if tls_files.is_none() {
+        tracing::warn!("TLS not configured. Serving plain HTTP");
+    }
+
+    // Set up background workers
+    let _handle = tokio::spawn(blob::collect_expired_blobs(blobs.clone()));
+    let _handle = tokio::spawn(rate_limit::collect_full_buckets(rate_limits.clone()));
+
     let app = Router::new()
         .route(
             "/packages",
@@ -46,40 +145,124 @@ async fn main() {
         )
         .route("/packages/:identity", get(get_key_package))
         .route("/:identity/messages", get(websocket_handler))
-        .layer(
-            CorsLayer::new()
-                .allow_origin("*".parse::<HeaderValue>().unwrap())
-                // .allow_origin("localhost:1420".parse::<HeaderValue>().unwrap())
-                // .allow_origin("localhost:1421".parse::<HeaderValue>().unwrap())
-                .allow_methods([Method::GET]),
-        )
+        .merge(blob::create_router())
+        .merge(group_info::create_router())
+        .merge(metrics::create_router())
+        .layer(allowed_origins.cors_layer())
         .with_state(AppState {
-            key_packages_by_identity: Default::default(),
-            user_actors: Default::default(),
+            key_packages_by_identity: key_packages_by_identity.clone(),
+            group_infos_by_group: group_infos_by_group.clone(),
+            connections: Default::default(),
+            offline_queue: offline_queue.clone(),
+            blobs,
+            tls_body_limit: TlsBodyLimit::default(),
+            shutdown: shutdown.clone(),
+            rate_limits,
+            allowed_origins,
+            metrics: Metrics::default(),
         });
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
-        .await
-        .unwrap();
+    let address: SocketAddr = env::var(BIND_ADDRESS_KEY)
+        .unwrap_or_else(|_| "127.0.0.1:3000".to_owned())
+        .parse()
+        .expect("Invalid bind address");
+
+    let handle = axum_server::Handle::new();
+    let signal = shutdown.clone();
+    let server_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown::wait_for_signal().await;
+        tracing::info!("Shutting down");
+        signal.trigger();
+        // Stops accepting connections
+        server_handle.graceful_shutdown(Some(SHUTDOWN_TIMEOUT));
+    });
+
+    // The address of the client is needed for rate limiting
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls_files {
+        Some(files) => {
+            let config = files.load().await.expect("Failed to load TLS certificate");
+            let _handle = tokio::spawn(tls::reload_certificates(files, config.clone()));
 
-    tracing::debug!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+            tracing::debug!("listening on https://{}", address);
+            axum_server::bind_rustls(address, config)
+                .handle(handle)
+                .serve(service)
+                .await
+                .unwrap();
+        }
+        None => {
+            tracing::debug!("listening on http://{}", address);
+            axum_server::bind(address)
+                .handle(handle)
+                .serve(service)
+                .await
+                .unwrap();
+        }
+    }
+
+    // Actors move their undelivered messages to the offline queue before they stop
+    shutdown.drain(SHUTDOWN_TIMEOUT).await;
+
+    if let Some(path) = state_file {
+        let key_packages = key_packages_by_identity.lock().await;
+        let group_infos = group_infos_by_group.lock().await;
+        match snapshot::save(&path, &key_packages, &group_infos, &offline_queue).await {
+            Ok(()) => tracing::info!("Saved state to {}", path.display()),
+            Err(error) => tracing::error!("Error saving state: {:?}", error),
+        }
+    }
 }
 
 async fn create_key_package(
     State(state): State<AppState>,
-    KeyPackage(package): KeyPackage,
-) -> Result<(), StatusCode> {
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request,
+) -> Result<(), Response> {
+    // Checked before reading the body so limited clients can't make the server receive and parse it
+    state
+        .rate_limits
+        .key_packages
+        .check(&address.ip())
+        .map_err(IntoResponse::into_response)?;
+
+    let TlsBody(package) = TlsBody::<KeyPackageIn>::from_request(request, &state)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
     tracing::debug!("Received key package");
-    let mut key_packages = state.key_packages_by_identity.lock().await;
+    // The signature key is only trusted to authenticate the identity if the key package was signed with it
+    if let Err(error) = package
+        .clone()
+        .validate(&RustCrypto::default(), ProtocolVersion::Mls10)
+    {
+        tracing::debug!("Rejected invalid key package: {:?}", error);
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    }
 
     let credential = package.unverified_credential().credential;
     let identity = credential.identity();
 
     let Ok(identity) = std::str::from_utf8(identity) else {
-        return Err(StatusCode::BAD_REQUEST);
+        return Err(StatusCode::BAD_REQUEST.into_response());
     };
 
+    let mut key_packages = state.key_packages_by_identity.lock().await;
+    // The signature key of the first key package authenticates the identity from then on.
+    // Anyone else could otherwise take over the identity by publishing a key package with their own key.
+    if let Some(existing) = key_packages.get(identity) {
+        if existing.unverified_credential().signature_key
+            != package.unverified_credential().signature_key
+        {
+            tracing::warn!(
+                "Rejected key package with another signature key for {}",
+                identity
+            );
+            return Err(StatusCode::FORBIDDEN.into_response());
+        }
+    }
+
     key_packages.insert(identity.to_string(), package);
     Ok(())
 }
@@ -105,17 +288,65 @@ async fn get_key_package(
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+#[derive(Deserialize, Debug)]
+struct ConnectionQuery {
+    /// Distinguishes the devices of a user. Clients without a device id share one session.
+    device: Option<String>,
+}
+
+/// The identity is the one the server tells recipients a message is from,
+/// so the client has to prove it owns it by signing the upgrade request.
 async fn websocket_handler(
-    Path(identity): Path<String>,
+    SignedIdentity { identity, .. }: SignedIdentity,
+    Query(query): Query<ConnectionQuery>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    // Browsers don't apply CORS to websockets so any website could otherwise connect on behalf of the user
+    _origin: CheckedOrigin,
+    headers: HeaderMap,
     websocket: WebSocketUpgrade,
     state: State<AppState>,
-) -> impl IntoResponse {
-    websocket.on_upgrade(move |socket| create_actor(socket, state, identity))
+) -> Response {
+    let Some(protocol) = Protocol::negotiate(&headers) else {
+        return (StatusCode::BAD_REQUEST, "Unsupported websocket protocol").into_response();
+    };
+
+    if let Err(limited) = state.rate_limits.connections.check(address.ip(), &identity) {
+        return limited.into_response();
+    }
+
+    let client = Client {
+        identity,
+        device: query.device.unwrap_or_else(|| "default".to_owned()),
+        address: address.ip(),
+    };
+    websocket
+        .protocols([PROTOCOL_V1])
+        .on_upgrade(move |socket| create_actor(socket, state, client, protocol))
 }
 
 // 2/3e, duck2duck encryption, melt
-async fn create_actor(stream: WebSocket, State(state): State<AppState>, identity: String) {
-    let actor = UserActorHandle::new(identity, stream, state.user_actors.clone());
-    let mut actors_guild = state.user_actors.lock().await;
-    actors_guild.push(actor);
+async fn create_actor(
+    stream: WebSocket,
+    State(state): State<AppState>,
+    client: Client,
+    protocol: Protocol,
+) {
+    UserActorHandle::spawn(
+        client,
+        protocol,
+        stream,
+        state.connections.clone(),
+        state.offline_queue.clone(),
+        state.shutdown.clone(),
+        state.rate_limits.messages.clone(),
+        state.metrics.clone(),
+    )
+    .await;
+
+    let metrics = state.connections.metrics();
+    tracing::debug!(
+        "Connected users: {} with {} sessions",
+        metrics.connected_identities,
+        metrics.connected_sessions
+    );
 }
@@ -0,0 +1,180 @@
+//! Health checks and metrics in the Prometheus text format
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+
+use crate::AppState;
+
+#[derive(Default)]
+struct Counters {
+    messages_routed: AtomicU64,
+    messages_dropped: AtomicU64,
+    messages_rate_limited: AtomicU64,
+    slow_consumers: AtomicU64,
+    websocket_errors: AtomicU64,
+}
+
+/// Counts events across all connections.
+/// Current values like the number of connections are read from the components that own them when scraped.
+#[derive(Clone, Default)]
+pub(crate) struct Metrics(Arc<Counters>);
+
+impl Metrics {
+    pub(crate) fn message_routed(&self) {
+        self.0.messages_routed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A message could not be handed to a recipient that was connected when routing
+    pub(crate) fn message_dropped(&self) {
+        self.0.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn message_rate_limited(&self) {
+        self.0.messages_rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn slow_consumer_disconnected(&self) {
+        self.0.slow_consumers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn websocket_error(&self) {
+        self.0.websocket_errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+fn write_metric(output: &mut String, name: &str, help: &str, kind: Kind, value: u64) {
+    let kind = match kind {
+        Kind::Counter => "counter",
+        Kind::Gauge => "gauge",
+    };
+
+    // Writing to a string can not fail
+    let _ = writeln!(output, "# HELP {name} {help}");
+    let _ = writeln!(output, "# TYPE {name} {kind}");
+    let _ = writeln!(output, "{name} {value}");
+}
+
+async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let connections = state.connections.metrics();
+    let offline_queue = state.offline_queue.metrics();
+    let key_packages = state.key_packages_by_identity.lock().await.len();
+    let counters = &state.metrics.0;
+
+    let mut output = String::new();
+    let metrics = [
+        (
+            "mealt_connected_identities",
+            "Identities with at least one connected device",
+            Kind::Gauge,
+            connections.connected_identities as u64,
+        ),
+        (
+            "mealt_connected_sessions",
+            "Connected devices, each served by one actor",
+            Kind::Gauge,
+            connections.connected_sessions as u64,
+        ),
+        (
+            "mealt_offline_queue_devices",
+            "Devices with messages in the offline queue",
+            Kind::Gauge,
+            offline_queue.queued_devices as u64,
+        ),
+        (
+            "mealt_offline_queue_messages",
+            "Messages waiting in the offline queue",
+            Kind::Gauge,
+            offline_queue.queued_messages as u64,
+        ),
+        (
+            "mealt_offline_queue_dropped_messages_total",
+            "Messages dropped because the offline queue of a device was full",
+            Kind::Counter,
+            offline_queue.dropped_messages,
+        ),
+        (
+            "mealt_key_packages",
+            "Identities with a published key package",
+            Kind::Gauge,
+            key_packages as u64,
+        ),
+        (
+            "mealt_messages_routed_total",
+            "Messages received from clients and routed to recipients",
+            Kind::Counter,
+            counters.messages_routed.load(Ordering::Relaxed),
+        ),
+        (
+            "mealt_messages_dropped_total",
+            "Messages dropped because the recipient disconnected while routing",
+            Kind::Counter,
+            counters.messages_dropped.load(Ordering::Relaxed),
+        ),
+        (
+            "mealt_messages_rate_limited_total",
+            "Messages rejected because the sender exceeded the rate limit",
+            Kind::Counter,
+            counters.messages_rate_limited.load(Ordering::Relaxed),
+        ),
+        (
+            "mealt_slow_consumers_total",
+            "Connections closed because they did not receive messages fast enough",
+            Kind::Counter,
+            counters.slow_consumers.load(Ordering::Relaxed),
+        ),
+        (
+            "mealt_websocket_errors_total",
+            "Errors reading from or writing to websockets",
+            Kind::Counter,
+            counters.websocket_errors.load(Ordering::Relaxed),
+        ),
+    ];
+
+    for (name, help, kind, value) in metrics {
+        write_metric(&mut output, name, help, kind, value);
+    }
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        output,
+    )
+}
+
+/// The process is running
+async fn get_health() -> StatusCode {
+    StatusCode::OK
+}
+
+/// The server accepts new connections. Fails once shutdown started so load balancers stop sending traffic.
+async fn get_readiness(State(state): State<AppState>) -> StatusCode {
+    if state.shutdown.is_triggered() {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    StatusCode::OK
+}
+
+pub(crate) fn create_router() -> Router<AppState> {
+    Router::new()
+        .route("/healthz", get(get_health))
+        .route("/readyz", get(get_readiness))
+        .route("/metrics", get(get_metrics))
+}
@@ -0,0 +1,85 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::protocol::RoutedMessage;
+
+/// The maximum number of messages kept for a device. The oldest messages are dropped first.
+const MAX_MESSAGES_PER_DEVICE: usize = 1024;
+
+/// Messages are queued per identity and device
+type DeviceKey = (String, String);
+
+/// Holds messages for devices that were disconnected before the messages could be delivered.
+/// The messages are delivered when the device connects again.
+#[derive(Clone, Default)]
+pub(crate) struct OfflineQueue {
+    queues: Arc<Mutex<HashMap<DeviceKey, VecDeque<RoutedMessage>>>>,
+    /// Messages dropped because a queue was full
+    dropped: Arc<AtomicU64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct OfflineQueueMetrics {
+    pub(crate) queued_devices: usize,
+    pub(crate) queued_messages: usize,
+    pub(crate) dropped_messages: u64,
+}
+
+impl OfflineQueue {
+    /// Puts messages in front of the queue of the device as they are older than the ones already queued
+    pub(crate) fn requeue(&self, identity: &str, device: &str, messages: Vec<RoutedMessage>) {
+        let mut queues = self.queues.lock().unwrap();
+        let queue = queues
+            .entry((identity.to_owned(), device.to_owned()))
+            .or_default();
+
+        for message in messages.into_iter().rev() {
+            queue.push_front(message);
+        }
+
+        if queue.len() > MAX_MESSAGES_PER_DEVICE {
+            let dropped = queue.len() - MAX_MESSAGES_PER_DEVICE;
+            tracing::warn!("Offline queue full. Dropping {} oldest messages", dropped);
+            queue.drain(..dropped);
+            self.dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Removes and returns the queued messages of the device
+    pub(crate) fn take(&self, identity: &str, device: &str) -> Vec<RoutedMessage> {
+        let mut queues = self.queues.lock().unwrap();
+        queues
+            .remove(&(identity.to_owned(), device.to_owned()))
+            .map(Vec::from)
+            .unwrap_or_default()
+    }
+
+    /// Copies the queued messages of every device without removing them
+    pub(crate) fn queued(&self) -> Vec<(String, String, Vec<RoutedMessage>)> {
+        let queues = self.queues.lock().unwrap();
+        queues
+            .iter()
+            .map(|((identity, device), queue)| {
+                (
+                    identity.clone(),
+                    device.clone(),
+                    queue.iter().cloned().collect(),
+                )
+            })
+            .collect()
+    }
+
+    pub(crate) fn metrics(&self) -> OfflineQueueMetrics {
+        let queues = self.queues.lock().unwrap();
+        OfflineQueueMetrics {
+            queued_devices: queues.len(),
+            queued_messages: queues.values().map(VecDeque::len).sum(),
+            dropped_messages: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Restricts which web origins can use the server.
+//! Browsers send cookies and open websockets cross-site so the origin is checked for websocket upgrades as well as through CORS.
+
+use std::{env, sync::Arc};
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{header, request::Parts, HeaderValue, Method, StatusCode},
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::signed_request::{SIGNATURE_HEADER, TIMESTAMP_HEADER};
+
+/// Comma separated list of origins allowed to use the server, e.g. "https://example.com,tauri://localhost"
+const ALLOWED_ORIGINS_KEY: &str = "ALLOWED_ORIGINS";
+
+/// The Vite dev server and the origins Tauri serves the app from on the different platforms
+const DEFAULT_ORIGINS: [&str; 4] = [
+    "http://localhost:1420",
+    "tauri://localhost",
+    "http://tauri.localhost",
+    "https://tauri.localhost",
+];
+
+#[derive(Clone, Debug)]
+pub(crate) struct AllowedOrigins(Arc<Vec<HeaderValue>>);
+
+impl AllowedOrigins {
+    /// Reads the origins from the environment and falls back to the origins of the app if they are not set
+    pub(crate) fn from_env() -> Result<Self, String> {
+        let origins = match env::var(ALLOWED_ORIGINS_KEY) {
+            Ok(origins) => origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                // Origins never have a trailing slash but it is easy to add one by accident
+                .map(|origin| origin.trim_end_matches('/').to_owned())
+                .collect::<Vec<_>>(),
+            Err(_) => DEFAULT_ORIGINS.map(str::to_owned).to_vec(),
+        };
+
+        let origins = origins
+            .into_iter()
+            .map(|origin| {
+                HeaderValue::from_str(&origin).map_err(|_| format!("Invalid origin {:?}", origin))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self(Arc::new(origins)))
+    }
+
+    pub(crate) fn is_allowed(&self, origin: &HeaderValue) -> bool {
+        self.0.contains(origin)
+    }
+
+    pub(crate) fn cors_layer(&self) -> CorsLayer {
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(self.0.iter().cloned()))
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+            .allow_headers([
+                header::CONTENT_TYPE,
+                header::RANGE,
+                SIGNATURE_HEADER,
+                TIMESTAMP_HEADER,
+            ])
+            .expose_headers([
+                header::LOCATION,
+                header::ETAG,
+                header::CONTENT_RANGE,
+                header::ACCEPT_RANGES,
+                header::RETRY_AFTER,
+            ])
+    }
+}
+
+/// Rejects requests from origins that are not allowed.
+/// Requests without an origin are not made by a website on behalf of the user and are let through.
+pub(crate) struct CheckedOrigin;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for CheckedOrigin
+where
+    AllowedOrigins: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Some(origin) = parts.headers.get(header::ORIGIN) else {
+            return Ok(Self);
+        };
+
+        if !AllowedOrigins::from_ref(state).is_allowed(origin) {
+            tracing::warn!("Rejected request from origin {:?}", origin);
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        Ok(Self)
+    }
+}
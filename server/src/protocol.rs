@@ -0,0 +1,301 @@
+//! Framing of the messages sent over the websocket.
+//! Frames are TLS encoded like the MLS messages they carry.
+//!
+//! The version is negotiated as websocket subprotocol so new versions can be added without breaking old clients.
+//! Clients that don't request a subprotocol are rejected as bare MLS messages can't be addressed to recipients.
+
+use std::io::{self, Read};
+
+use axum::http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap};
+use bytes::Bytes;
+use tls_codec::{Deserialize, Serialize, TlsDeserialize, TlsSerialize, TlsSize, VLBytes};
+
+pub(crate) const PROTOCOL_V1: &str = "mealt.v1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    V1,
+}
+
+impl Protocol {
+    /// Picks a supported protocol from the subprotocols requested by the client
+    pub(crate) fn negotiate(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(|protocol| match protocol.trim() {
+                PROTOCOL_V1 => Some(Protocol::V1),
+                _ => None,
+            })
+    }
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct Frame {
+    /// Chosen by the sender of a request and echoed in the acknowledgement or error for it.
+    /// Zero for frames that don't expect a response.
+    pub(crate) request_id: u64,
+    pub(crate) body: FrameBody,
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+#[repr(u8)]
+pub(crate) enum FrameBody {
+    #[tls_codec(discriminant = 1)]
+    Mls(MlsFrame),
+    #[tls_codec(discriminant = 2)]
+    Ack(AckFrame),
+    #[tls_codec(discriminant = 3)]
+    Subscribe(SubscribeFrame),
+    #[tls_codec(discriminant = 4)]
+    Ping(PingFrame),
+    #[tls_codec(discriminant = 5)]
+    Error(ErrorFrame),
+}
+
+/// An MLS message routed to the recipients and the subscribers of the group.
+/// It is sent to everyone if neither recipients nor group are set.
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct MlsFrame {
+    /// Identities to deliver the message to, e.g. the new member of a welcome message
+    pub(crate) recipients: Vec<VLBytes>,
+    /// Identity of the connection the message was received from.
+    /// Set by the server and ignored when sent by clients.
+    pub(crate) sender: VLBytes,
+    /// Id of the group the message belongs to. Empty if it does not belong to a group.
+    pub(crate) group_id: VLBytes,
+    pub(crate) message: VLBytes,
+}
+
+/// Confirms a request was handled
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct AckFrame {
+    /// Recipients of a message that were not connected
+    pub(crate) undelivered: Vec<VLBytes>,
+}
+
+/// Subscribes the connection to the messages of the groups
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct SubscribeFrame {
+    pub(crate) group_ids: Vec<VLBytes>,
+}
+
+/// Checks if the other side is still there. Answered with an acknowledgement.
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct PingFrame {}
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct ErrorFrame {
+    pub(crate) code: u16,
+    /// Human readable description for debugging
+    pub(crate) message: VLBytes,
+}
+
+pub(crate) const ERROR_MALFORMED_FRAME: u16 = 1;
+pub(crate) const ERROR_UNSUPPORTED_FRAME: u16 = 2;
+pub(crate) const ERROR_NO_RECIPIENTS: u16 = 3;
+pub(crate) const ERROR_RATE_LIMITED: u16 = 4;
+
+/// Reads from the encoded frame but fails instead of returning fewer bytes than requested.
+/// The codec reads vectors with a single call and expects them to be complete,
+/// so a frame that ends early has to be an error instead of a short read.
+struct ExactReader<'a>(&'a [u8]);
+
+impl Read for ExactReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_exact(buf)?;
+        Ok(buf.len())
+    }
+}
+
+impl Frame {
+    /// Decodes a frame that has to span the whole data, like a websocket message
+    pub(crate) fn decode(data: &[u8]) -> Result<Self, tls_codec::Error> {
+        let mut reader = ExactReader(data);
+        let frame = Self::tls_deserialize(&mut reader)?;
+        if !reader.0.is_empty() {
+            return Err(tls_codec::Error::TrailingData);
+        }
+
+        Ok(frame)
+    }
+
+    pub(crate) fn ack(request_id: u64, undelivered: Vec<VLBytes>) -> Self {
+        Self {
+            request_id,
+            body: FrameBody::Ack(AckFrame { undelivered }),
+        }
+    }
+
+    pub(crate) fn error(request_id: u64, code: u16, message: &str) -> Self {
+        Self {
+            request_id,
+            body: FrameBody::Error(ErrorFrame {
+                code,
+                message: message.as_bytes().into(),
+            }),
+        }
+    }
+}
+
+/// An MLS message on its way to the recipients.
+/// It is encoded once for every protocol and then shared between all recipients.
+#[derive(Clone, Debug)]
+pub(crate) struct RoutedMessage {
+    framed: Bytes,
+}
+
+impl RoutedMessage {
+    pub(crate) fn new(
+        sender: &str,
+        group_id: &[u8],
+        message: Bytes,
+    ) -> Result<Self, tls_codec::Error> {
+        let frame = Frame {
+            request_id: 0,
+            body: FrameBody::Mls(MlsFrame {
+                recipients: Vec::new(),
+                sender: sender.as_bytes().into(),
+                group_id: group_id.into(),
+                message: message.as_ref().into(),
+            }),
+        };
+
+        Ok(Self {
+            framed: frame.tls_serialize_detached()?.into(),
+        })
+    }
+
+    pub(crate) fn encoded(&self, protocol: Protocol) -> &Bytes {
+        match protocol {
+            Protocol::V1 => &self.framed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn round_trip(frame: &Frame) -> Frame {
+        let encoded = frame.tls_serialize_detached().unwrap();
+        Frame::decode(&encoded).unwrap()
+    }
+
+    #[test]
+    fn mls_frame_round_trips() {
+        let frame = Frame {
+            request_id: 7,
+            body: FrameBody::Mls(MlsFrame {
+                recipients: vec![b"bob".as_slice().into()],
+                sender: b"alice".as_slice().into(),
+                group_id: b"group".as_slice().into(),
+                message: vec![1, 2, 3].into(),
+            }),
+        };
+
+        let Frame {
+            request_id,
+            body: FrameBody::Mls(mls),
+        } = round_trip(&frame)
+        else {
+            panic!("Expected an MLS frame");
+        };
+        assert_eq!(request_id, 7);
+        assert_eq!(mls.recipients, vec![VLBytes::from(b"bob".as_slice())]);
+        assert_eq!(mls.sender.as_slice(), b"alice");
+        assert_eq!(mls.group_id.as_slice(), b"group");
+        assert_eq!(mls.message.as_slice(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn control_frames_round_trip() {
+        let ack = round_trip(&Frame::ack(1, vec![b"bob".as_slice().into()]));
+        assert!(
+            matches!(ack.body, FrameBody::Ack(AckFrame { undelivered }) if undelivered.len() == 1)
+        );
+
+        let error = round_trip(&Frame::error(2, ERROR_RATE_LIMITED, "slow down"));
+        assert!(matches!(
+            error.body,
+            FrameBody::Error(ErrorFrame { code: ERROR_RATE_LIMITED, message })
+                if message.as_slice() == b"slow down"
+        ));
+
+        let subscribe = round_trip(&Frame {
+            request_id: 3,
+            body: FrameBody::Subscribe(SubscribeFrame {
+                group_ids: vec![b"group".as_slice().into()],
+            }),
+        });
+        assert!(
+            matches!(subscribe.body, FrameBody::Subscribe(SubscribeFrame { group_ids }) if group_ids.len() == 1)
+        );
+
+        let ping = round_trip(&Frame {
+            request_id: 4,
+            body: FrameBody::Ping(PingFrame {}),
+        });
+        assert_eq!(ping.request_id, 4);
+        assert!(matches!(ping.body, FrameBody::Ping(_)));
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let encoded = Frame {
+            request_id: 1,
+            body: FrameBody::Ping(PingFrame {}),
+        }
+        .tls_serialize_detached()
+        .unwrap();
+
+        assert!(Frame::decode(&encoded).is_ok());
+        // Missing body
+        assert!(Frame::decode(&encoded[..8]).is_err());
+        // Unknown frame type
+        let mut unknown = encoded.clone();
+        unknown[8] = 6;
+        assert!(Frame::decode(&unknown).is_err());
+        // Data after the frame
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(Frame::decode(&trailing).is_err());
+        // Message shorter than its length prefix
+        let truncated = [0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 5, 1];
+        assert!(Frame::decode(&truncated).is_err());
+    }
+
+    #[test]
+    fn routed_message_is_framed_with_sender() {
+        let message = RoutedMessage::new("alice", b"group", Bytes::from_static(&[1, 2])).unwrap();
+        let encoded = message.encoded(Protocol::V1);
+
+        let frame = Frame::decode(encoded).unwrap();
+        let FrameBody::Mls(mls) = frame.body else {
+            panic!("Expected an MLS frame");
+        };
+        assert!(mls.recipients.is_empty());
+        assert_eq!(mls.sender.as_slice(), b"alice");
+        assert_eq!(mls.group_id.as_slice(), b"group");
+        assert_eq!(mls.message.as_slice(), [1, 2]);
+    }
+
+    #[test]
+    fn negotiates_only_supported_protocols() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Protocol::negotiate(&headers), None);
+
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("mqtt"));
+        assert_eq!(Protocol::negotiate(&headers), None);
+
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("mqtt, mealt.v1"),
+        );
+        assert_eq!(Protocol::negotiate(&headers), Some(Protocol::V1));
+    }
+}
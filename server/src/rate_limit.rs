@@ -0,0 +1,342 @@
+//! Token bucket rate limits applied per peer address and, for clients that signed their requests, per identity.
+//! Identities are only used once the signature proved them, otherwise anyone could use up the tokens of someone else
+//! or spread their requests over made up identities.
+//! The per identity limit keeps a client from spreading its requests over many addresses.
+
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    env,
+    hash::Hash,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tokio::time::Instant;
+
+/// Limits for key package uploads, e.g. "10/60" for 10 uploads per minute
+const RATE_LIMIT_KEY_PACKAGES_KEY: &str = "RATE_LIMIT_KEY_PACKAGES";
+/// Limits for messages sent over the websocket
+const RATE_LIMIT_MESSAGES_KEY: &str = "RATE_LIMIT_MESSAGES";
+/// Limits for opening websocket connections
+const RATE_LIMIT_CONNECTIONS_KEY: &str = "RATE_LIMIT_CONNECTIONS";
+/// Limits for publishing group infos
+const RATE_LIMIT_GROUP_INFOS_KEY: &str = "RATE_LIMIT_GROUP_INFOS";
+
+/// Allows a burst of `requests` after which the tokens refill evenly over the period
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RateLimit {
+    pub(crate) requests: u32,
+    pub(crate) period: Duration,
+}
+
+impl RateLimit {
+    fn tokens_per_second(&self) -> f64 {
+        f64::from(self.requests) / self.period.as_secs_f64()
+    }
+}
+
+/// Parses limits in the format `<requests>/<seconds>`
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid rate limit {:?}. Expected <requests>/<seconds>",
+                value
+            )
+        };
+        let (requests, seconds) = value.split_once('/').ok_or_else(invalid)?;
+        let requests = requests.trim().parse().map_err(|_| invalid())?;
+        let seconds: u64 = seconds.trim().parse().map_err(|_| invalid())?;
+        if requests == 0 || seconds == 0 {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            requests,
+            period: Duration::from_secs(seconds),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * limit.tokens_per_second()).min(f64::from(limit.requests));
+        self.updated_at = now;
+    }
+
+    /// Time until the bucket has a token again
+    fn retry_after(&self, limit: &RateLimit) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64((1.0 - self.tokens) / limit.tokens_per_second())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimited {
+    pub(crate) retry_after: Duration,
+}
+
+impl IntoResponse for RateLimited {
+    fn into_response(self) -> Response {
+        // Retry-After only supports whole seconds
+        let seconds = self.retry_after.as_secs_f64().ceil() as u64;
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, seconds.to_string())],
+        )
+            .into_response()
+    }
+}
+
+/// Applies the limit to each key, like a peer address, separately
+#[derive(Clone)]
+pub(crate) struct RateLimiter<K = IpAddr> {
+    limit: RateLimit,
+    buckets: Arc<Mutex<HashMap<K, TokenBucket>>>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Default::default(),
+        }
+    }
+
+    pub(crate) fn check<Q>(&self, key: &Q) -> Result<(), RateLimited>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ToOwned<Owned = K> + ?Sized,
+    {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at<Q>(&self, key: &Q, now: Instant) -> Result<(), RateLimited>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ToOwned<Owned = K> + ?Sized,
+    {
+        let mut buckets = self.buckets.lock().unwrap();
+        // Looked up by reference first so keys are only copied for new buckets
+        if !buckets.contains_key(key) {
+            buckets.insert(
+                key.to_owned(),
+                TokenBucket {
+                    tokens: f64::from(self.limit.requests),
+                    updated_at: now,
+                },
+            );
+        }
+        let bucket = buckets.get_mut(key).expect("bucket was inserted");
+        bucket.refill(&self.limit, now);
+
+        let retry_after = bucket.retry_after(&self.limit);
+        if !retry_after.is_zero() {
+            return Err(RateLimited { retry_after });
+        }
+
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Forgets buckets that are full again as they behave the same as new buckets
+    fn remove_full(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, bucket| {
+            bucket.refill(&self.limit, now);
+            bucket.tokens < f64::from(self.limit.requests)
+        });
+    }
+}
+
+/// Applies the same limit to the peer address and to the identity the client signed for
+#[derive(Clone)]
+pub(crate) struct ClientRateLimiter {
+    by_address: RateLimiter,
+    by_identity: RateLimiter<String>,
+}
+
+impl ClientRateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            by_address: RateLimiter::new(limit),
+            by_identity: RateLimiter::new(limit),
+        }
+    }
+
+    pub(crate) fn check(&self, address: IpAddr, identity: &str) -> Result<(), RateLimited> {
+        self.by_address.check(&address)?;
+        self.by_identity.check(identity)
+    }
+
+    fn remove_full(&self, now: Instant) {
+        self.by_address.remove_full(now);
+        self.by_identity.remove_full(now);
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RateLimits {
+    pub(crate) key_packages: RateLimiter,
+    pub(crate) messages: ClientRateLimiter,
+    pub(crate) connections: ClientRateLimiter,
+    pub(crate) group_infos: RateLimiter,
+}
+
+impl RateLimits {
+    /// Reads the limits from the environment and falls back to defaults for the ones that are not set
+    pub(crate) fn from_env() -> Result<Self, String> {
+        let limit = |key: &str, default: RateLimit| match env::var(key) {
+            Ok(value) => value.parse::<RateLimit>(),
+            Err(_) => Ok(default),
+        };
+
+        Ok(Self {
+            key_packages: RateLimiter::new(limit(
+                RATE_LIMIT_KEY_PACKAGES_KEY,
+                RateLimit {
+                    requests: 10,
+                    period: Duration::from_secs(60),
+                },
+            )?),
+            messages: ClientRateLimiter::new(limit(
+                RATE_LIMIT_MESSAGES_KEY,
+                RateLimit {
+                    requests: 60,
+                    period: Duration::from_secs(10),
+                },
+            )?),
+            connections: ClientRateLimiter::new(limit(
+                RATE_LIMIT_CONNECTIONS_KEY,
+                RateLimit {
+                    requests: 10,
+                    period: Duration::from_secs(60),
+                },
+            )?),
+            // Published after every commit
+            group_infos: RateLimiter::new(limit(
+                RATE_LIMIT_GROUP_INFOS_KEY,
+                RateLimit {
+                    requests: 30,
+                    period: Duration::from_secs(60),
+                },
+            )?),
+        })
+    }
+}
+
+/// Periodically forgets idle clients so the buckets don't grow forever
+pub(crate) async fn collect_full_buckets(limits: RateLimits) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        limits.key_packages.remove_full(now);
+        limits.messages.remove_full(now);
+        limits.connections.remove_full(now);
+        limits.group_infos.remove_full(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Refills one token per second
+    const LIMIT: RateLimit = RateLimit {
+        requests: 2,
+        period: Duration::from_secs(2),
+    };
+    const ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[test]
+    fn rejects_requests_over_the_limit() {
+        let limiter = RateLimiter::new(LIMIT);
+        let now = Instant::now();
+
+        assert!(limiter.check_at(&ADDRESS, now).is_ok());
+        assert!(limiter.check_at(&ADDRESS, now).is_ok());
+        let limited = limiter.check_at(&ADDRESS, now).unwrap_err();
+        assert_eq!(limited.retry_after, Duration::from_secs(1));
+
+        // Other addresses have their own bucket
+        assert!(limiter
+            .check_at(&IpAddr::V4(Ipv4Addr::UNSPECIFIED), now)
+            .is_ok());
+    }
+
+    #[test]
+    fn refills_tokens_over_the_period() {
+        let limiter = RateLimiter::new(LIMIT);
+        let now = Instant::now();
+        limiter.check_at(&ADDRESS, now).unwrap();
+        limiter.check_at(&ADDRESS, now).unwrap();
+
+        let limited = limiter
+            .check_at(&ADDRESS, now + Duration::from_millis(500))
+            .unwrap_err();
+        assert_eq!(limited.retry_after, Duration::from_millis(500));
+        assert!(limiter
+            .check_at(&ADDRESS, now + Duration::from_secs(1))
+            .is_ok());
+
+        // Tokens don't accumulate over the burst size
+        let later = now + Duration::from_secs(60);
+        assert!(limiter.check_at(&ADDRESS, later).is_ok());
+        assert!(limiter.check_at(&ADDRESS, later).is_ok());
+        assert!(limiter.check_at(&ADDRESS, later).is_err());
+    }
+
+    #[test]
+    fn removes_only_full_buckets() {
+        let limiter = RateLimiter::<String>::new(LIMIT);
+        let now = Instant::now();
+        limiter.check_at("alice", now).unwrap();
+        limiter
+            .check_at("bob", now + Duration::from_secs(1))
+            .unwrap();
+
+        limiter.remove_full(now + Duration::from_millis(1500));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.contains_key("alice"));
+        assert!(buckets.contains_key("bob"));
+    }
+
+    #[test]
+    fn limits_identity_across_addresses() {
+        let limiter = ClientRateLimiter::new(LIMIT);
+
+        assert!(limiter.check(ADDRESS, "alice").is_ok());
+        assert!(limiter
+            .check(Ipv4Addr::new(10, 0, 0, 1).into(), "alice")
+            .is_ok());
+        assert!(limiter
+            .check(Ipv4Addr::new(10, 0, 0, 2).into(), "alice")
+            .is_err());
+        assert!(limiter
+            .check(Ipv4Addr::new(10, 0, 0, 2).into(), "bob")
+            .is_ok());
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use bytes::Bytes;
+
+use crate::user_actor::UserActorHandle;
+
+/// Uniquely identifies a websocket connection for the lifetime of the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct ConnectionId(u64);
+
+struct Session {
+    device: String,
+    actor: UserActorHandle,
+    /// Ids of the groups the session subscribed to
+    groups: HashSet<Bytes>,
+}
+
+/// The identity of each subscribed connection
+type Subscribers = HashMap<ConnectionId, String>;
+
+#[derive(Default)]
+struct Registry {
+    sessions_by_identity: HashMap<String, HashMap<ConnectionId, Session>>,
+    subscribers_by_group: HashMap<Bytes, Subscribers>,
+    session_count: usize,
+}
+
+impl Registry {
+    fn unsubscribe(&mut self, connection_id: ConnectionId, groups: HashSet<Bytes>) {
+        for group in groups {
+            let Some(subscribers) = self.subscribers_by_group.get_mut(&group) else {
+                continue;
+            };
+
+            subscribers.remove(&connection_id);
+            if subscribers.is_empty() {
+                self.subscribers_by_group.remove(&group);
+            }
+        }
+    }
+}
+
+/// Keeps track of the connected users and their sessions.
+/// An identity can be connected from multiple devices but only has one session per device.
+///
+/// The lock is never held across an await so sending to actors can not block the registry.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionRegistry {
+    registry: Arc<RwLock<Registry>>,
+    next_connection_id: Arc<AtomicU64>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RegistryMetrics {
+    pub(crate) connected_identities: usize,
+    pub(crate) connected_sessions: usize,
+}
+
+impl ConnectionRegistry {
+    pub(crate) fn next_connection_id(&self) -> ConnectionId {
+        ConnectionId(self.next_connection_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Adds the session and returns the session it replaced if the device was already connected
+    pub(crate) fn register(
+        &self,
+        identity: String,
+        device: String,
+        connection_id: ConnectionId,
+        actor: UserActorHandle,
+    ) -> Option<UserActorHandle> {
+        let mut registry = self.registry.write().unwrap();
+        let sessions = registry.sessions_by_identity.entry(identity).or_default();
+
+        let replaced_id = sessions
+            .iter()
+            .find(|(_, session)| session.device == device)
+            .map(|(id, _)| *id);
+        let replaced = replaced_id.and_then(|id| sessions.remove(&id).map(|session| (id, session)));
+
+        sessions.insert(
+            connection_id,
+            Session {
+                device,
+                actor,
+                groups: HashSet::new(),
+            },
+        );
+
+        let Some((replaced_id, replaced)) = replaced else {
+            registry.session_count += 1;
+            return None;
+        };
+
+        registry.unsubscribe(replaced_id, replaced.groups);
+        Some(replaced.actor)
+    }
+
+    /// Removes the session if it is still registered and returns its device.
+    /// Does nothing if the session was already removed or replaced.
+    pub(crate) fn unregister(&self, identity: &str, connection_id: ConnectionId) -> Option<String> {
+        let mut registry = self.registry.write().unwrap();
+        let sessions = registry.sessions_by_identity.get_mut(identity)?;
+        let session = sessions.remove(&connection_id)?;
+
+        if sessions.is_empty() {
+            registry.sessions_by_identity.remove(identity);
+        }
+
+        registry.unsubscribe(connection_id, session.groups);
+        registry.session_count -= 1;
+        tracing::debug!("Unregistered connection {:?}", connection_id);
+        Some(session.device)
+    }
+
+    /// Adds the groups to the subscriptions of the session
+    pub(crate) fn subscribe(
+        &self,
+        identity: &str,
+        connection_id: ConnectionId,
+        group_ids: Vec<Bytes>,
+    ) {
+        let mut registry = self.registry.write().unwrap();
+        let registry = &mut *registry;
+        let Some(session) = registry
+            .sessions_by_identity
+            .get_mut(identity)
+            .and_then(|sessions| sessions.get_mut(&connection_id))
+        else {
+            return;
+        };
+
+        for group_id in group_ids {
+            registry
+                .subscribers_by_group
+                .entry(group_id.clone())
+                .or_default()
+                .insert(connection_id, identity.to_owned());
+            session.groups.insert(group_id);
+        }
+    }
+
+    /// Gets the actors of all sessions subscribed to the group
+    pub(crate) fn subscribers(
+        &self,
+        group_id: &[u8],
+    ) -> Vec<(String, ConnectionId, UserActorHandle)> {
+        let registry = self.registry.read().unwrap();
+        let Some(subscribers) = registry.subscribers_by_group.get(group_id) else {
+            return Vec::new();
+        };
+
+        subscribers
+            .iter()
+            .filter_map(|(connection_id, identity)| {
+                let session = registry
+                    .sessions_by_identity
+                    .get(identity)?
+                    .get(connection_id)?;
+                Some((identity.clone(), *connection_id, session.actor.clone()))
+            })
+            .collect()
+    }
+
+    /// Gets the actors of all sessions of the identity
+    pub(crate) fn sessions_of(&self, identity: &str) -> Vec<(ConnectionId, UserActorHandle)> {
+        let registry = self.registry.read().unwrap();
+        let Some(sessions) = registry.sessions_by_identity.get(identity) else {
+            return Vec::new();
+        };
+
+        sessions
+            .iter()
+            .map(|(id, session)| (*id, session.actor.clone()))
+            .collect()
+    }
+
+    /// Gets the actors of all sessions except the one with the connection id
+    pub(crate) fn sessions_except(
+        &self,
+        connection_id: ConnectionId,
+    ) -> Vec<(String, ConnectionId, UserActorHandle)> {
+        let registry = self.registry.read().unwrap();
+        registry
+            .sessions_by_identity
+            .iter()
+            .flat_map(|(identity, sessions)| {
+                sessions
+                    .iter()
+                    .filter(move |(id, _)| **id != connection_id)
+                    .map(move |(id, session)| (identity.clone(), *id, session.actor.clone()))
+            })
+            .collect()
+    }
+
+    pub(crate) fn metrics(&self) -> RegistryMetrics {
+        let registry = self.registry.read().unwrap();
+        RegistryMetrics {
+            connected_identities: registry.sessions_by_identity.len(),
+            connected_sessions: registry.session_count,
+        }
+    }
+}
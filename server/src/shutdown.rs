@@ -0,0 +1,75 @@
+use std::{future::Future, time::Duration};
+
+use tokio::signal;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// Coordinates the graceful shutdown of the server.
+/// Websocket connections outlive the requests that upgraded them so the server has to wait for their actors itself.
+#[derive(Clone, Default)]
+pub(crate) struct Shutdown {
+    token: CancellationToken,
+    tasks: TaskTracker,
+}
+
+impl Shutdown {
+    /// Spawns a task that is waited for when draining
+    pub(crate) fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.spawn(task);
+    }
+
+    pub(crate) fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    pub(crate) fn is_triggered(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Completes when the shutdown was triggered. Completes immediately if it already was.
+    pub(crate) async fn triggered(&self) {
+        self.token.cancelled().await;
+    }
+
+    /// Waits for the spawned tasks to finish or the timeout to pass
+    pub(crate) async fn drain(&self, timeout: Duration) {
+        self.tasks.close();
+        tracing::debug!("Waiting for {} tasks to finish", self.tasks.len());
+        if tokio::time::timeout(timeout, self.tasks.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "{} tasks did not finish before the shutdown timeout",
+                self.tasks.len()
+            );
+        }
+    }
+}
+
+/// Completes on Ctrl+C or SIGTERM
+pub(crate) async fn wait_for_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
@@ -0,0 +1,316 @@
+//! Authenticates requests that act on behalf of an identity.
+//! The client signs the method, path, a timestamp and a digest of the body with the signature key of the key package it published,
+//! so only the owner of the identity can act as it.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequestParts, Path},
+    http::{request::Parts, HeaderName, StatusCode},
+};
+use base64::prelude::*;
+use futures_util::{stream, StreamExt};
+use openmls::prelude::{SignContent, SignatureScheme};
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::crypto::OpenMlsCrypto;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tls_codec::Serialize;
+
+use crate::AppState;
+
+/// Base64 URL encoded signature of the request
+pub(crate) const SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-mealt-signature");
+/// Seconds since the unix epoch when the request was signed
+pub(crate) const TIMESTAMP_HEADER: HeaderName = HeaderName::from_static("x-mealt-timestamp");
+/// Base64 URL encoded SHA-256 digest of the request body, which is empty for requests without body
+pub(crate) const CONTENT_DIGEST_HEADER: HeaderName =
+    HeaderName::from_static("x-mealt-content-sha256");
+
+/// Label of the signed content so request signatures can't be mistaken for MLS signatures
+const SIGNATURE_LABEL: &str = "mealt request";
+/// How far the timestamp can be off. Limits how long a captured request can be replayed.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+/// The signature scheme of the ciphersuite clients use (MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519)
+const SIGNATURE_SCHEME: SignatureScheme = SignatureScheme::ED25519;
+
+/// The `:identity` of the path after the signature of the request was checked against the key package of the identity.
+/// Like for the websocket, the identity in the path is the URL safe base64 encoding of the identity in the credential.
+pub(crate) struct SignedIdentity {
+    pub(crate) identity: String,
+    /// Digest of the body the client signed. The body is only checked against it while it is read.
+    content_digest: [u8; 32],
+}
+
+#[derive(Debug, Error)]
+#[error("Request body does not match the signed digest")]
+struct ContentDigestMismatch;
+
+impl SignedIdentity {
+    /// Wraps the body so reading it fails at the end if it is not the body the client signed.
+    /// Handlers that use the body have to read it through this.
+    pub(crate) fn verify_body(&self, body: Body) -> Body {
+        let expected = self.content_digest;
+        let chunks = stream::unfold(
+            Some((body.into_data_stream(), Sha256::new())),
+            move |reading| async move {
+                let (mut chunks, mut hasher) = reading?;
+                match chunks.next().await {
+                    Some(Ok(chunk)) => {
+                        hasher.update(&chunk);
+                        Some((Ok(chunk), Some((chunks, hasher))))
+                    }
+                    Some(Err(error)) => Some((Err(error), None)),
+                    None if hasher.finalize()[..] == expected => None,
+                    None => Some((Err(axum::Error::new(ContentDigestMismatch)), None)),
+                }
+            },
+        );
+
+        Body::from_stream(chunks)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for SignedIdentity {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Path(parameters) = Path::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+        let identity = parameters
+            .get("identity")
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        // Key packages are stored by the identity of their credential
+        let credential_identity = BASE64_URL_SAFE_NO_PAD
+            .decode(identity)
+            .ok()
+            .and_then(|identity| String::from_utf8(identity).ok())
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        let signature_key = {
+            let key_packages = state.key_packages_by_identity.lock().await;
+            // Identities without a key package have no key to check against
+            let package = key_packages
+                .get(&credential_identity)
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+            package.unverified_credential().signature_key
+        };
+
+        let content_digest = verify(parts, signature_key.as_slice(), SystemTime::now())
+            .inspect_err(|_| {
+                tracing::warn!("Rejected request with invalid signature for {}", identity);
+            })?;
+
+        Ok(Self {
+            identity: identity.to_owned(),
+            content_digest,
+        })
+    }
+}
+
+/// Checks that the request was signed recently with the signature key.
+/// Returns the signed digest of the body.
+fn verify(parts: &Parts, signature_key: &[u8], now: SystemTime) -> Result<[u8; 32], StatusCode> {
+    let timestamp = parts
+        .headers
+        .get(TIMESTAMP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    if now.abs_diff(timestamp) > MAX_CLOCK_SKEW.as_secs() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let content_digest: [u8; 32] = parts
+        .headers
+        .get(CONTENT_DIGEST_HEADER)
+        .and_then(|value| BASE64_URL_SAFE_NO_PAD.decode(value.as_bytes()).ok())
+        .and_then(|digest| digest.try_into().ok())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let signature = parts
+        .headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| BASE64_URL_SAFE_NO_PAD.decode(value.as_bytes()).ok())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let content = signed_content(parts.method.as_str(), path, timestamp, &content_digest);
+    let payload = SignContent::new(SIGNATURE_LABEL, content.into_bytes().into())
+        .tls_serialize_detached()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    RustCrypto::default()
+        .verify_signature(SIGNATURE_SCHEME, &payload, signature_key, &signature)
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    Ok(content_digest)
+}
+
+/// The content clients sign. The query is part of the path so parameters like the blob TTL can't be changed.
+fn signed_content(method: &str, path: &str, timestamp: u64, content_digest: &[u8]) -> String {
+    format!(
+        "{} {}\n{}\n{}",
+        method,
+        path,
+        timestamp,
+        BASE64_URL_SAFE_NO_PAD.encode(content_digest)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::Request};
+    use openmls::prelude::SignatureScheme;
+    use openmls_basic_credential::SignatureKeyPair;
+    use openmls_traits::signatures::Signer;
+
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+    const BODY: &[u8] = b"content";
+
+    /// Signs the request like clients do
+    fn signed_parts(
+        signer: &SignatureKeyPair,
+        signed_path: &str,
+        path: &str,
+        timestamp: u64,
+    ) -> Parts {
+        let content_digest = Sha256::digest(BODY);
+        let content = signed_content("POST", signed_path, timestamp, &content_digest);
+        let payload = SignContent::new(SIGNATURE_LABEL, content.into_bytes().into())
+            .tls_serialize_detached()
+            .unwrap();
+        let signature = signer.sign(&payload).unwrap();
+
+        let (parts, ()) = Request::post(path)
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(
+                CONTENT_DIGEST_HEADER,
+                BASE64_URL_SAFE_NO_PAD.encode(content_digest),
+            )
+            .header(SIGNATURE_HEADER, BASE64_URL_SAFE_NO_PAD.encode(signature))
+            .body(())
+            .unwrap()
+            .into_parts();
+        parts
+    }
+
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(NOW)
+    }
+
+    #[test]
+    fn accepts_signature_of_the_key() {
+        let signer = SignatureKeyPair::new(SignatureScheme::ED25519).unwrap();
+        let path = "/alice/blobs?ttl_seconds=60";
+        let parts = signed_parts(&signer, path, path, NOW);
+
+        assert_eq!(
+            verify(&parts, signer.public(), now()),
+            Ok(Sha256::digest(BODY).into())
+        );
+    }
+
+    #[tokio::test]
+    async fn only_reads_the_signed_body() {
+        let signed = SignedIdentity {
+            identity: "alice".to_owned(),
+            content_digest: Sha256::digest(BODY).into(),
+        };
+
+        let body = signed.verify_body(Body::from(BODY));
+        assert_eq!(to_bytes(body, usize::MAX).await.unwrap(), BODY);
+
+        let body = signed.verify_body(Body::from("other content"));
+        assert!(to_bytes(body, usize::MAX).await.is_err());
+    }
+
+    #[test]
+    fn rejects_signature_of_another_body() {
+        let signer = SignatureKeyPair::new(SignatureScheme::ED25519).unwrap();
+        let mut parts = signed_parts(&signer, "/alice/blobs", "/alice/blobs", NOW);
+        let other_digest = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(b"other content"));
+        parts
+            .headers
+            .insert(CONTENT_DIGEST_HEADER, other_digest.parse().unwrap());
+
+        assert_eq!(
+            verify(&parts, signer.public(), now()),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_of_the_allowed_skew() {
+        let signer = SignatureKeyPair::new(SignatureScheme::ED25519).unwrap();
+        let skew = MAX_CLOCK_SKEW.as_secs() + 1;
+
+        for timestamp in [NOW - skew, NOW + skew] {
+            let parts = signed_parts(&signer, "/alice/blobs", "/alice/blobs", timestamp);
+            assert_eq!(
+                verify(&parts, signer.public(), now()),
+                Err(StatusCode::UNAUTHORIZED)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_signature_of_another_key() {
+        let signer = SignatureKeyPair::new(SignatureScheme::ED25519).unwrap();
+        let other = SignatureKeyPair::new(SignatureScheme::ED25519).unwrap();
+        let parts = signed_parts(&other, "/alice/blobs", "/alice/blobs", NOW);
+
+        assert_eq!(
+            verify(&parts, signer.public(), now()),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn rejects_signature_of_another_path() {
+        let signer = SignatureKeyPair::new(SignatureScheme::ED25519).unwrap();
+        let parts = signed_parts(
+            &signer,
+            "/alice/blobs?ttl_seconds=60",
+            "/alice/blobs?ttl_seconds=3600",
+            NOW,
+        );
+
+        assert_eq!(
+            verify(&parts, signer.public(), now()),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn rejects_requests_without_signature() {
+        let signer = SignatureKeyPair::new(SignatureScheme::ED25519).unwrap();
+        let (parts, ()) = Request::post("/alice/blobs")
+            .header(TIMESTAMP_HEADER, NOW.to_string())
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        assert_eq!(
+            verify(&parts, signer.public(), now()),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+}
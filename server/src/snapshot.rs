@@ -0,0 +1,187 @@
+//! Persists the in-memory state of the server across restarts.
+//! The state is written on graceful shutdown and read once on startup.
+
+use std::{collections::HashMap, path::Path};
+
+use bytes::Bytes;
+use openmls::key_packages::KeyPackageIn;
+use thiserror::Error;
+use tls_codec::{Deserialize, Serialize, TlsDeserialize, TlsSerialize, TlsSize, VLBytes};
+
+use crate::{
+    offline_queue::OfflineQueue,
+    protocol::{Frame, FrameBody, Protocol, RoutedMessage},
+};
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+struct Snapshot {
+    key_packages: Vec<StoredKeyPackage>,
+    group_infos: Vec<StoredGroupInfo>,
+    queues: Vec<StoredQueue>,
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+struct StoredKeyPackage {
+    identity: VLBytes,
+    key_package: KeyPackageIn,
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+struct StoredGroupInfo {
+    group_id: VLBytes,
+    group_info: VLBytes,
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+struct StoredQueue {
+    identity: VLBytes,
+    device: VLBytes,
+    /// Stored as frames as they also contain the group id of the message
+    messages: Vec<VLBytes>,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum SnapshotError {
+    #[error("Error accessing the state file")]
+    IoError(#[from] std::io::Error),
+    #[error("Error encoding or decoding the state")]
+    CodecError(#[from] tls_codec::Error),
+    #[error("The state file contains invalid data")]
+    InvalidData,
+}
+
+/// The state besides the offline queue which is restored in place
+#[derive(Default)]
+pub(crate) struct RestoredState {
+    pub(crate) key_packages: HashMap<String, KeyPackageIn>,
+    pub(crate) group_infos: HashMap<String, Bytes>,
+}
+
+/// Writes the state to the file
+pub(crate) async fn save(
+    path: &Path,
+    key_packages: &HashMap<String, KeyPackageIn>,
+    group_infos: &HashMap<String, Bytes>,
+    offline_queue: &OfflineQueue,
+) -> Result<(), SnapshotError> {
+    let key_packages = key_packages
+        .iter()
+        .map(|(identity, key_package)| StoredKeyPackage {
+            identity: identity.as_bytes().into(),
+            key_package: key_package.clone(),
+        })
+        .collect();
+
+    let group_infos = group_infos
+        .iter()
+        .map(|(group_id, group_info)| StoredGroupInfo {
+            group_id: group_id.as_bytes().into(),
+            group_info: group_info.as_ref().into(),
+        })
+        .collect();
+
+    let queues = offline_queue
+        .queued()
+        .into_iter()
+        .map(|(identity, device, messages)| StoredQueue {
+            identity: identity.as_bytes().into(),
+            device: device.as_bytes().into(),
+            messages: messages
+                .iter()
+                .map(|message| message.encoded(Protocol::V1).as_ref().into())
+                .collect(),
+        })
+        .collect();
+
+    let snapshot = Snapshot {
+        key_packages,
+        group_infos,
+        queues,
+    };
+    write(path, &snapshot).await
+}
+
+/// Replaces the file atomically so a failed write does not destroy the previous state
+async fn write(path: &Path, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+    let data = snapshot.tls_serialize_detached()?;
+    let temporary_path = path.with_extension("tmp");
+    tokio::fs::write(&temporary_path, data).await?;
+    tokio::fs::rename(&temporary_path, path).await?;
+    Ok(())
+}
+
+/// Reads the state from the file and moves the queued messages into the offline queue.
+/// The messages are removed from the file first so they are not delivered twice if the server does not shut down gracefully.
+/// Key packages and group infos stay in the file so they survive that case.
+pub(crate) async fn load(
+    path: &Path,
+    offline_queue: &OfflineQueue,
+) -> Result<RestoredState, SnapshotError> {
+    let data = match tokio::fs::read(path).await {
+        Ok(data) => data,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(RestoredState::default())
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    let mut snapshot = Snapshot::tls_deserialize(&mut data.as_slice())?;
+
+    let mut key_packages = HashMap::new();
+    for stored in &snapshot.key_packages {
+        let identity = String::from_utf8(stored.identity.as_slice().to_vec())
+            .map_err(|_| SnapshotError::InvalidData)?;
+        key_packages.insert(identity, stored.key_package.clone());
+    }
+
+    let mut group_infos = HashMap::new();
+    for stored in &snapshot.group_infos {
+        let group_id = String::from_utf8(stored.group_id.as_slice().to_vec())
+            .map_err(|_| SnapshotError::InvalidData)?;
+        group_infos.insert(
+            group_id,
+            Bytes::copy_from_slice(stored.group_info.as_slice()),
+        );
+    }
+
+    let mut queues = Vec::new();
+    for queue in std::mem::take(&mut snapshot.queues) {
+        let identity = String::from_utf8(queue.identity.as_slice().to_vec())
+            .map_err(|_| SnapshotError::InvalidData)?;
+        let device = String::from_utf8(queue.device.as_slice().to_vec())
+            .map_err(|_| SnapshotError::InvalidData)?;
+        let messages = queue
+            .messages
+            .iter()
+            .map(|message| decode_message(message.as_slice()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        queues.push((identity, device, messages));
+    }
+
+    write(path, &snapshot).await?;
+    for (identity, device, messages) in queues {
+        offline_queue.requeue(&identity, &device, messages);
+    }
+
+    Ok(RestoredState {
+        key_packages,
+        group_infos,
+    })
+}
+
+fn decode_message(data: &[u8]) -> Result<RoutedMessage, SnapshotError> {
+    let frame = Frame::decode(data)?;
+    let FrameBody::Mls(frame) = frame.body else {
+        return Err(SnapshotError::InvalidData);
+    };
+
+    let sender =
+        std::str::from_utf8(frame.sender.as_slice()).map_err(|_| SnapshotError::InvalidData)?;
+    let message = Bytes::copy_from_slice(frame.message.as_slice());
+    Ok(RoutedMessage::new(
+        sender,
+        frame.group_id.as_slice(),
+        message,
+    )?)
+}
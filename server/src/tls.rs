@@ -0,0 +1,83 @@
+//! Optional TLS termination so the server can be self-hosted without a reverse proxy
+
+use std::{
+    env, io,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use axum_server::tls_rustls::RustlsConfig;
+
+/// Path to the PEM encoded certificate chain. TLS is enabled if this and the private key are set.
+const TLS_CERTIFICATE_KEY: &str = "TLS_CERTIFICATE";
+/// Path to the PEM encoded private key of the certificate
+const TLS_PRIVATE_KEY_KEY: &str = "TLS_PRIVATE_KEY";
+/// How often the certificate files are checked for changes, e.g. after a renewal
+const RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub(crate) struct TlsFiles {
+    certificate: PathBuf,
+    private_key: PathBuf,
+}
+
+impl TlsFiles {
+    /// Returns `None` if TLS is not configured
+    pub(crate) fn from_env() -> Result<Option<Self>, String> {
+        match (env::var(TLS_CERTIFICATE_KEY), env::var(TLS_PRIVATE_KEY_KEY)) {
+            (Ok(certificate), Ok(private_key)) => Ok(Some(Self {
+                certificate: certificate.into(),
+                private_key: private_key.into(),
+            })),
+            (Err(_), Err(_)) => Ok(None),
+            _ => Err(format!(
+                "{} and {} have to be set together",
+                TLS_CERTIFICATE_KEY, TLS_PRIVATE_KEY_KEY
+            )),
+        }
+    }
+
+    pub(crate) async fn load(&self) -> io::Result<RustlsConfig> {
+        RustlsConfig::from_pem_file(&self.certificate, &self.private_key).await
+    }
+
+    /// The latest modification of either file
+    async fn modified(&self) -> io::Result<SystemTime> {
+        let certificate = tokio::fs::metadata(&self.certificate).await?.modified()?;
+        let private_key = tokio::fs::metadata(&self.private_key).await?.modified()?;
+        Ok(certificate.max(private_key))
+    }
+}
+
+/// Reloads the certificate when the files change so renewed certificates are used without a restart.
+/// Existing connections keep the certificate they were established with.
+pub(crate) async fn reload_certificates(files: TlsFiles, config: RustlsConfig) {
+    let mut last_modified = files.modified().await.ok();
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        let modified = match files.modified().await {
+            Ok(modified) => modified,
+            Err(error) => {
+                tracing::error!("Error checking certificate files: {:?}", error);
+                continue;
+            }
+        };
+
+        if last_modified == Some(modified) {
+            continue;
+        }
+
+        // Keep the previous certificate if the new one can not be loaded, e.g. while the files are still being written
+        match config
+            .reload_from_pem_file(&files.certificate, &files.private_key)
+            .await
+        {
+            Ok(()) => {
+                tracing::info!("Reloaded TLS certificate");
+                last_modified = Some(modified);
+            }
+            Err(error) => tracing::error!("Error reloading TLS certificate: {:?}", error),
+        }
+    }
+}
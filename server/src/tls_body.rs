@@ -0,0 +1,245 @@
+use std::io::{self, Read};
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRef, FromRequest, Request},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use thiserror::Error;
+use tls_codec::Deserialize;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+/// Extracts a TLS encoded value like a key package or MLS message from the request body.
+/// The body is deserialized while it is being received instead of buffering it first.
+pub(crate) struct TlsBody<T>(pub(crate) T);
+
+/// The maximum size of a TLS encoded request body in bytes
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TlsBodyLimit(pub(crate) usize);
+
+impl Default for TlsBodyLimit {
+    fn default() -> Self {
+        // Key packages and messages are small. Larger content should be uploaded as blob.
+        Self(64 * 1024)
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum TlsBodyRejection {
+    #[error("Request body exceeds the maximum size of {0} bytes")]
+    TooLarge(usize),
+    #[error("Error reading request body")]
+    BodyError(#[source] io::Error),
+    #[error("Error deserializing request body")]
+    DeserializeError(#[source] tls_codec::Error),
+    #[error("Request body contains data after the deserialized value")]
+    TrailingData,
+    #[error("Deserialization task failed")]
+    TaskError(#[from] tokio::task::JoinError),
+}
+
+impl IntoResponse for TlsBodyRejection {
+    fn into_response(self) -> Response {
+        match self {
+            TlsBodyRejection::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+            TlsBodyRejection::BodyError(error) => {
+                tracing::debug!("Error reading TLS body: {:?}", error);
+                StatusCode::BAD_REQUEST.into_response()
+            }
+            // Codec errors only describe the structure and don't contain the content
+            TlsBodyRejection::DeserializeError(error) => {
+                tracing::debug!("Error deserializing TLS body: {:?}", error);
+                StatusCode::BAD_REQUEST.into_response()
+            }
+            TlsBodyRejection::TrailingData => StatusCode::BAD_REQUEST.into_response(),
+            TlsBodyRejection::TaskError(error) => {
+                tracing::error!("Error running deserialization: {:?}", error);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+type BodyStream = Box<dyn Stream<Item = Result<Bytes, io::Error>> + Send + Unpin>;
+
+/// Reads the body on the blocking thread the codec runs on.
+/// The codec turns read errors into its own errors so the reason is kept to reject the request with.
+struct BodyReader {
+    inner: SyncIoBridge<StreamReader<BodyStream, Bytes>>,
+    limit: usize,
+    received: usize,
+    rejection: Option<TlsBodyRejection>,
+}
+
+impl BodyReader {
+    fn new(body: Body, limit: usize) -> Self {
+        let stream: BodyStream = Box::new(
+            body.into_data_stream()
+                .map(|chunk| chunk.map_err(io::Error::other)),
+        );
+        Self {
+            inner: SyncIoBridge::new(StreamReader::new(stream)),
+            limit,
+            received: 0,
+            rejection: None,
+        }
+    }
+
+    fn deserialize<T: Deserialize>(mut self) -> Result<T, TlsBodyRejection> {
+        let value = match T::tls_deserialize(&mut self) {
+            Ok(value) => value,
+            Err(error) => {
+                return Err(self
+                    .rejection
+                    .unwrap_or(TlsBodyRejection::DeserializeError(error)))
+            }
+        };
+
+        // Make sure the body only contains the value
+        match self.read_available(&mut [0]) {
+            Ok(0) => Ok(value),
+            Ok(_) => Err(TlsBodyRejection::TrailingData),
+            Err(error) => Err(self.rejection.unwrap_or(TlsBodyRejection::BodyError(error))),
+        }
+    }
+
+    /// Reads what was received so far and enforces the limit
+    fn read_available(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = loop {
+            match self.inner.read(buf) {
+                Ok(read) => break read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    let message = error.to_string();
+                    self.rejection = Some(TlsBodyRejection::BodyError(error));
+                    return Err(io::Error::other(message));
+                }
+            }
+        };
+
+        // The content length can be missing or wrong so the limit is also enforced while reading
+        self.received += read;
+        if self.received > self.limit {
+            self.rejection = Some(TlsBodyRejection::TooLarge(self.limit));
+            return Err(io::Error::other("Request body too large"));
+        }
+
+        Ok(read)
+    }
+}
+
+impl Read for BodyReader {
+    /// Fills the whole buffer or fails.
+    /// The codec reads vectors with a single call and expects them to be complete,
+    /// so a body that ends early is an error instead of a short read.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read_available(&mut buf[filled..])? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                read => filled += read,
+            }
+        }
+
+        Ok(filled)
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for TlsBody<T>
+where
+    T: Deserialize + Send + 'static,
+    TlsBodyLimit: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = TlsBodyRejection;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let TlsBodyLimit(limit) = TlsBodyLimit::from_ref(state);
+
+        // Reject early if the client announces a body that is too large
+        let content_length = request
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        if content_length.is_some_and(|length| length > limit) {
+            return Err(TlsBodyRejection::TooLarge(limit));
+        }
+
+        // The TLS codec reads synchronously so it runs on a blocking thread that waits for the body to arrive
+        let reader = BodyReader::new(request.into_body(), limit);
+        let value = tokio::task::spawn_blocking(move || reader.deserialize::<T>()).await??;
+        Ok(TlsBody(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+    use tls_codec::VLBytes;
+
+    use super::*;
+
+    const LIMIT: TlsBodyLimit = TlsBodyLimit(8);
+
+    /// Sends the body in single bytes without announcing its length
+    fn streamed(content: &'static [u8]) -> Request {
+        let chunks = stream::iter(
+            content
+                .chunks(1)
+                .map(|chunk| Ok::<_, io::Error>(Bytes::from_static(chunk))),
+        );
+        Request::new(Body::from_stream(chunks))
+    }
+
+    async fn status(request: Request) -> StatusCode {
+        match TlsBody::<VLBytes>::from_request(request, &LIMIT).await {
+            Ok(_) => StatusCode::OK,
+            Err(rejection) => rejection.into_response().status(),
+        }
+    }
+
+    #[tokio::test]
+    async fn deserializes_value_received_in_parts() {
+        let TlsBody(value) = TlsBody::<VLBytes>::from_request(streamed(&[3, 1, 2, 3]), &LIMIT)
+            .await
+            .unwrap();
+        assert_eq!(value.as_slice(), [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn rejects_body_over_the_limit() {
+        let content = &[9, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+        assert_eq!(
+            status(streamed(content)).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        let announced = Request::builder()
+            .header(header::CONTENT_LENGTH, content.len())
+            .body(Body::from(&content[..]))
+            .unwrap();
+        assert_eq!(status(announced).await, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn rejects_trailing_data() {
+        assert_eq!(status(streamed(&[1, 1, 2])).await, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_body() {
+        // The length prefix announces more than the body contains
+        assert_eq!(status(streamed(&[3, 1])).await, StatusCode::BAD_REQUEST);
+        // Lengths have to be encoded with as few bytes as possible
+        assert_eq!(
+            status(streamed(&[0x40, 1, 0])).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -1,24 +1,72 @@
-use std::sync::Arc;
-
-use axum::{
-    body::Bytes,
-    extract::ws::{Message, WebSocket},
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::{Arc, Mutex},
 };
-use openmls::framing::MlsMessageIn;
+
+use axum::extract::ws::{close_code, Message, WebSocket};
+use bytes::Bytes;
+use tls_codec::Serialize;
 use tokio::sync::{
-    mpsc::{self, error::SendError},
-    Mutex,
+    mpsc::{self, error::TrySendError},
+    oneshot, Notify,
+};
+
+use crate::{
+    metrics::Metrics,
+    offline_queue::OfflineQueue,
+    protocol::{
+        Frame, FrameBody, MlsFrame, Protocol, RoutedMessage, ERROR_MALFORMED_FRAME,
+        ERROR_NO_RECIPIENTS, ERROR_RATE_LIMITED, ERROR_UNSUPPORTED_FRAME,
+    },
+    rate_limit::ClientRateLimiter,
+    registry::{ConnectionId, ConnectionRegistry},
+    shutdown::Shutdown,
+    websocket_actor::{Outgoing, WebSocketHandle},
 };
 
+/// Close code sent to a session that was replaced by a newer connection from the same device
+const REPLACED_CLOSE_CODE: u16 = 4000;
+/// Number of messages in a row a client can send over the rate limit before it is disconnected
+const MAX_RATE_LIMIT_VIOLATIONS: u32 = 10;
+/// Messages routed to an actor that it did not get to yet
+const CHANNEL_CAPACITY: usize = 8;
+
+/// The user and device on the other end of a connection
+pub(crate) struct Client {
+    pub(crate) identity: String,
+    pub(crate) device: String,
+    pub(crate) address: IpAddr,
+}
+
 struct UserActor {
     id: String,
+    device: String,
+    connection_id: ConnectionId,
+    address: IpAddr,
+    protocol: Protocol,
     receiver: mpsc::Receiver<UserActorMessage>,
-    websocket: WebSocket,
-    other_actors: Arc<Mutex<Vec<UserActorHandle>>>,
+    websocket: WebSocketHandle,
+    registry: ConnectionRegistry,
+    offline_queue: OfflineQueue,
+    /// Messages that did not fit into the channel anymore because the actor could not keep up
+    overflow: Arc<Mutex<Vec<RoutedMessage>>>,
+    slow_consumer: Arc<Notify>,
+    /// Set along with `replaced` when the session was replaced while the channel was full
+    replaced_flushed: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    replaced: Arc<Notify>,
+    message_limiter: ClientRateLimiter,
+    metrics: Metrics,
+    /// Messages sent over the rate limit since the last accepted message
+    rate_limit_violations: u32,
 }
 enum UserActorMessage {
-    /// Instruct the actor to send a message to the user represented by the actor
-    SendMessage(Vec<u8>),
+    /// Instruct the actor to send a message to the user represented by the actor.
+    /// The message is shared between all recipients to avoid copying it for each of them.
+    SendMessage(RoutedMessage),
+    /// Instruct the actor to close the connection because a newer session replaced it.
+    /// The sender is dropped once the undelivered messages were moved to the offline queue for the new session.
+    Replaced(oneshot::Sender<()>),
 }
 
 enum Instruction {
@@ -27,17 +75,249 @@ enum Instruction {
 }
 
 impl UserActor {
-    fn new(
-        id: String,
-        websocket: WebSocket,
-        receiver: mpsc::Receiver<UserActorMessage>,
-        other_actors: Arc<Mutex<Vec<UserActorHandle>>>,
-    ) -> Self {
-        UserActor {
-            id,
-            receiver,
-            websocket,
-            other_actors,
+    fn encode(&self, message: &RoutedMessage) -> Outgoing {
+        // Cloning only increases the reference count so all recipients share the encoded message
+        Outgoing::Shared(message.encoded(self.protocol).clone())
+    }
+
+    async fn send_frame(&mut self, frame: Frame) -> Instruction {
+        let data = match frame.tls_serialize_detached() {
+            Ok(data) => data,
+            Err(error) => {
+                tracing::error!("Error serializing frame: {:?}", error);
+                return Instruction::Continue;
+            }
+        };
+
+        match self.websocket.try_send(Message::Binary(data)) {
+            Ok(()) => Instruction::Continue,
+            Err(TrySendError::Full(_)) => {
+                self.disconnect_slow_consumer(Vec::new()).await;
+                Instruction::Stop
+            }
+            Err(TrySendError::Closed(_)) => Instruction::Stop,
+        }
+    }
+
+    /// Sends the messages that were queued while the device was offline.
+    /// Messages that can not be sent are put back into the queue.
+    async fn send_queued_messages(&mut self) -> Instruction {
+        let mut messages = self.offline_queue.take(&self.id, &self.device).into_iter();
+        while let Some(message) = messages.next() {
+            // Waits for the writer as the queue can be larger than what the writer buffers
+            if let Err(error) = self.websocket.send(self.encode(&message)).await {
+                tracing::error!("Error sending queued message: {:?}", error);
+                let unsent = std::iter::once(message).chain(messages).collect();
+                self.offline_queue.requeue(&self.id, &self.device, unsent);
+                return Instruction::Stop;
+            }
+        }
+
+        Instruction::Continue
+    }
+
+    /// Moves the messages the actor did not get to send into the offline queue.
+    /// The unsent messages are the oldest.
+    fn flush_to_offline_queue(&mut self, mut messages: Vec<RoutedMessage>) {
+        self.receiver.close();
+        while let Ok(message) = self.receiver.try_recv() {
+            if let UserActorMessage::SendMessage(message) = message {
+                messages.push(message);
+            }
+        }
+
+        // Overflowing messages were sent after the channel was full so they are newer
+        messages.append(&mut self.overflow.lock().unwrap());
+        if messages.is_empty() {
+            return;
+        }
+
+        tracing::debug!("Moving {} messages to offline queue", messages.len());
+        self.offline_queue.requeue(&self.id, &self.device, messages);
+    }
+
+    /// Closes the connection because the server is shutting down.
+    /// The client can reconnect to another instance or after the restart to receive the messages from the offline queue.
+    async fn shut_down(&mut self) {
+        // Unregister first so no more messages are routed to the actor after flushing
+        self.registry.unregister(&self.id, self.connection_id);
+        self.flush_to_offline_queue(Vec::new());
+        self.websocket
+            .close(close_code::AWAY, "Server is shutting down")
+            .await;
+    }
+
+    /// Disconnects the client because it does not receive messages fast enough.
+    /// It can reconnect later to receive the messages from the offline queue.
+    async fn disconnect_slow_consumer(&mut self, unsent: Vec<RoutedMessage>) {
+        tracing::warn!("Disconnecting slow consumer {:?}", self.connection_id);
+        self.metrics.slow_consumer_disconnected();
+        self.flush_to_offline_queue(unsent);
+        self.websocket
+            .close(close_code::AGAIN, "Not receiving messages fast enough")
+            .await;
+    }
+
+    /// Takes a token for a message from the client.
+    /// Clients that keep sending over the limit are disconnected.
+    async fn check_rate_limit(&mut self, request_id: u64) -> Result<(), Instruction> {
+        let Err(limited) = self.message_limiter.check(self.address, &self.id) else {
+            self.rate_limit_violations = 0;
+            return Ok(());
+        };
+
+        self.metrics.message_rate_limited();
+        self.rate_limit_violations += 1;
+        if self.rate_limit_violations > MAX_RATE_LIMIT_VIOLATIONS {
+            tracing::warn!("Disconnecting abusive client {:?}", self.connection_id);
+            self.websocket
+                .close(close_code::POLICY, "Rate limit exceeded")
+                .await;
+            return Err(Instruction::Stop);
+        }
+
+        let message = format!(
+            "Rate limit exceeded. Retry after {} ms",
+            limited.retry_after.as_millis()
+        );
+        let error = Frame::error(request_id, ERROR_RATE_LIMITED, &message);
+        Err(self.send_frame(error).await)
+    }
+
+    /// Sends the message to the recipients and the subscribers of the group or everyone if neither are set.
+    /// Returns the recipients that are not connected.
+    fn route(
+        &self,
+        recipients: Vec<String>,
+        group_id: &[u8],
+        message: RoutedMessage,
+    ) -> Vec<String> {
+        self.metrics.message_routed();
+        let mut targets = Vec::new();
+        let mut undelivered = Vec::new();
+        let is_broadcast = recipients.is_empty() && group_id.is_empty();
+
+        for recipient in recipients {
+            let sessions = self.registry.sessions_of(&recipient);
+            if sessions.is_empty() {
+                undelivered.push(recipient);
+                continue;
+            }
+
+            targets.extend(
+                sessions
+                    .into_iter()
+                    .map(|(connection_id, actor)| (recipient.clone(), connection_id, actor)),
+            );
+        }
+
+        if !group_id.is_empty() {
+            targets.extend(self.registry.subscribers(group_id));
+        }
+
+        if is_broadcast {
+            targets = self.registry.sessions_except(self.connection_id);
+        }
+
+        // Recipients can also be subscribers
+        let mut visited = HashSet::new();
+        for (identity, connection_id, other) in targets {
+            if connection_id == self.connection_id || !visited.insert(connection_id) {
+                continue;
+            }
+
+            // Never wait for a recipient so one slow recipient can not hold up everyone else
+            match other.try_send_message(message.clone()) {
+                Delivery::Sent => {}
+                Delivery::SlowConsumer => {
+                    // Stop sending to the session so it can not pile up more messages
+                    self.registry.unregister(&identity, connection_id);
+                }
+                Delivery::Closed => {
+                    tracing::debug!("Cleaning up deceased actor remains: {:?}", connection_id);
+                    // The actor stopped after it flushed, so the message waits for the next connection of the device
+                    match self.registry.unregister(&identity, connection_id) {
+                        Some(device) => {
+                            self.offline_queue
+                                .requeue(&identity, &device, vec![message.clone()])
+                        }
+                        // Another sender cleaned up the session first and the device is not known anymore
+                        None => {
+                            self.metrics.message_dropped();
+                            undelivered.push(identity);
+                        }
+                    }
+                }
+            }
+        }
+
+        undelivered
+    }
+
+    async fn handle_frame(&mut self, frame: Frame) -> Instruction {
+        let request_id = frame.request_id;
+        match frame.body {
+            FrameBody::Mls(MlsFrame {
+                recipients,
+                group_id,
+                message,
+                ..
+            }) => {
+                if let Err(instruction) = self.check_rate_limit(request_id).await {
+                    return instruction;
+                }
+
+                let recipients = recipients
+                    .iter()
+                    .map(|recipient| String::from_utf8(recipient.as_slice().to_vec()))
+                    .collect::<Result<Vec<_>, _>>();
+                let Ok(recipients) = recipients else {
+                    let error =
+                        Frame::error(request_id, ERROR_MALFORMED_FRAME, "Invalid recipient");
+                    return self.send_frame(error).await;
+                };
+
+                let group_id = group_id.as_slice();
+                let message = Bytes::copy_from_slice(message.as_slice());
+                let Ok(message) = RoutedMessage::new(&self.id, group_id, message) else {
+                    let error = Frame::error(request_id, ERROR_MALFORMED_FRAME, "Invalid message");
+                    return self.send_frame(error).await;
+                };
+
+                let recipient_count = recipients.len();
+                let undelivered = self.route(recipients, group_id, message);
+                if recipient_count > 0
+                    && undelivered.len() == recipient_count
+                    && group_id.is_empty()
+                {
+                    let error =
+                        Frame::error(request_id, ERROR_NO_RECIPIENTS, "No recipient is connected");
+                    return self.send_frame(error).await;
+                }
+
+                let undelivered = undelivered
+                    .iter()
+                    .map(|identity| identity.as_bytes().into())
+                    .collect();
+                self.send_frame(Frame::ack(request_id, undelivered)).await
+            }
+            FrameBody::Subscribe(subscribe) => {
+                let group_ids = subscribe
+                    .group_ids
+                    .iter()
+                    .map(|group_id| Bytes::copy_from_slice(group_id.as_slice()))
+                    .collect();
+                self.registry
+                    .subscribe(&self.id, self.connection_id, group_ids);
+                self.send_frame(Frame::ack(request_id, Vec::new())).await
+            }
+            FrameBody::Ping(_) => self.send_frame(Frame::ack(request_id, Vec::new())).await,
+            // The server does not send requests yet that clients would acknowledge
+            FrameBody::Ack(_) => Instruction::Continue,
+            FrameBody::Error(error) => {
+                tracing::warn!("Received error frame with code {}", error.code);
+                Instruction::Continue
+            }
         }
     }
 
@@ -52,96 +332,480 @@ impl UserActor {
             return Instruction::Continue;
         };
 
-        let mut others = self.other_actors.lock().await;
-        let mut dead_actors = Vec::new();
-        for (index, other) in others.iter().enumerate() {
-            if other.id == self.id {
-                continue;
-            }
-            //TODO use shared reference instead to avoid cloning of possibly large messages
-            let result = other.send_message(binary.clone()).await;
-            // Erros when channel is closed
-            if let Err(error) = result {
-                dead_actors.push(index);
-                tracing::error!("Error sending actor message: {:?}", error);
+        match Frame::decode(&binary) {
+            Ok(frame) => self.handle_frame(frame).await,
+            Err(error) => {
+                tracing::debug!("Error deserializing frame: {:?}", error);
+                // Unknown frame types can not be told apart from malformed frames
+                let error =
+                    Frame::error(0, ERROR_UNSUPPORTED_FRAME, "Unsupported or malformed frame");
+                self.send_frame(error).await
             }
         }
-
-        for index in dead_actors.into_iter() {
-            tracing::debug!("Cleaning up deceased actor remains at index: {:?}", index);
-            others.remove(index);
-        }
-
-        Instruction::Continue
     }
 
     async fn handle_message(&mut self, message: UserActorMessage) -> Instruction {
         match message {
             UserActorMessage::SendMessage(message) => {
                 tracing::debug!("Sending message: {:?}", message);
-                let result = self.websocket.send(Message::Binary(message)).await;
-                if let Err(error) = result {
-                    tracing::error!("Error sending message: {:?}", error);
-                    return Instruction::Stop;
+                match self.websocket.try_send(self.encode(&message)) {
+                    Ok(()) => Instruction::Continue,
+                    Err(TrySendError::Full(_)) => {
+                        self.disconnect_slow_consumer(vec![message]).await;
+                        Instruction::Stop
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        self.flush_to_offline_queue(vec![message]);
+                        Instruction::Stop
+                    }
                 }
-
-                Instruction::Continue
+            }
+            UserActorMessage::Replaced(flushed) => {
+                self.close_replaced(Some(flushed)).await;
+                Instruction::Stop
             }
         }
     }
+
+    /// Closes the connection because a newer session of the same device replaced it
+    async fn close_replaced(&mut self, flushed: Option<oneshot::Sender<()>>) {
+        tracing::debug!("Session replaced by a new connection");
+        // Messages routed to this session before it was replaced would otherwise be lost
+        self.flush_to_offline_queue(Vec::new());
+        drop(flushed);
+        self.websocket
+            .close(REPLACED_CLOSE_CODE, "Replaced by a new connection")
+            .await;
+    }
 }
 
-async fn run_my_actor(mut actor: UserActor) {
+async fn run_my_actor(
+    mut actor: UserActor,
+    shutdown: Shutdown,
+    replaced_flushed: Option<oneshot::Receiver<()>>,
+) {
     tracing::debug!("Actor started");
 
+    // Wait for the replaced session to move its undelivered messages to the offline queue so they are sent below.
+    // Errors once the replaced actor flushed or stopped.
+    if let Some(flushed) = replaced_flushed {
+        let _ = flushed.await;
+    }
+
+    if let Instruction::Stop = actor.send_queued_messages().await {
+        // Messages routed to the session while it was sending the queue would otherwise be lost
+        actor.registry.unregister(&actor.id, actor.connection_id);
+        actor.flush_to_offline_queue(Vec::new());
+        return;
+    }
+
+    let slow_consumer = actor.slow_consumer.clone();
+    let replaced = actor.replaced.clone();
     loop {
         tokio::select! {
+            _ = shutdown.triggered() => {
+                actor.shut_down().await;
+                break;
+            },
+            _ = slow_consumer.notified() => {
+                actor.disconnect_slow_consumer(Vec::new()).await;
+                break;
+            },
+            _ = replaced.notified() => {
+                let flushed = actor.replaced_flushed.lock().unwrap().take();
+                actor.close_replaced(flushed).await;
+                break;
+            },
             Some(message) = actor.receiver.recv() => {
                 let result = actor.handle_message(message).await;
                 if let Instruction::Stop = result {
                     break;
                 }
             },
-            // Stop actor on error
-            Some(Ok(message)) = actor.websocket.recv() => {
-              let result = actor.handle_websocket_message(message).await;
-               if let Instruction::Stop = result {
-                   break;
-               }
+            // Stops when the connection is closed, failed or was idle for too long
+            Some(message) = actor.websocket.recv() => {
+                let result = actor.handle_websocket_message(message).await;
+                if let Instruction::Stop = result {
+                    break;
+                }
             },
             else => break,
         }
     }
 
+    actor.registry.unregister(&actor.id, actor.connection_id);
     tracing::debug!("Actor stopped");
 }
 
+pub(crate) enum Delivery {
+    Sent,
+    /// The actor can not keep up and is being disconnected.
+    /// The message is moved to the offline queue.
+    SlowConsumer,
+    /// The actor stopped
+    Closed,
+}
+
+#[derive(Clone)]
 pub(crate) struct UserActorHandle {
-    /// A simple unique identifier for the actor to distinguish it from others
-    /// The actor and actor handle share the same id
-    id: String,
     sender: mpsc::Sender<UserActorMessage>,
+    overflow: Arc<Mutex<Vec<RoutedMessage>>>,
+    slow_consumer: Arc<Notify>,
+    replaced_flushed: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    replaced: Arc<Notify>,
 }
 
 impl UserActorHandle {
-    pub(crate) fn new(
-        id: String,
+    /// Registers the session of the device and starts the actor.
+    /// A previous session of the same device is closed.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn spawn(
+        client: Client,
+        protocol: Protocol,
         websocket: WebSocket,
-        other_actors: Arc<Mutex<Vec<UserActorHandle>>>,
-    ) -> Self {
-        let (sender, receiver) = mpsc::channel(8);
-        let actor = UserActor::new(id.clone(), websocket, receiver, other_actors);
-        tokio::spawn(run_my_actor(actor));
-        Self { id, sender }
+        registry: ConnectionRegistry,
+        offline_queue: OfflineQueue,
+        shutdown: Shutdown,
+        message_limiter: ClientRateLimiter,
+        metrics: Metrics,
+    ) {
+        let websocket = WebSocketHandle::new(websocket, metrics.clone());
+        Self::start(
+            client,
+            protocol,
+            websocket,
+            registry,
+            offline_queue,
+            shutdown,
+            message_limiter,
+            metrics,
+        )
+        .await;
     }
 
-    /// Errors when actor stopped receiving messages meaning the channel is closed and the actor is deceased
-    pub(crate) async fn send_message(
-        &self,
-        message: Vec<u8>,
-    ) -> Result<(), impl std::error::Error> {
-        self.sender
-            .send(UserActorMessage::SendMessage(message))
+    #[allow(clippy::too_many_arguments)]
+    async fn start(
+        client: Client,
+        protocol: Protocol,
+        websocket: WebSocketHandle,
+        registry: ConnectionRegistry,
+        offline_queue: OfflineQueue,
+        shutdown: Shutdown,
+        message_limiter: ClientRateLimiter,
+        metrics: Metrics,
+    ) {
+        let Client {
+            identity: id,
+            device,
+            address,
+        } = client;
+
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let connection_id = registry.next_connection_id();
+        let handle = Self {
+            sender,
+            overflow: Default::default(),
+            slow_consumer: Default::default(),
+            replaced_flushed: Default::default(),
+            replaced: Default::default(),
+        };
+
+        let actor = UserActor {
+            id: id.clone(),
+            device: device.clone(),
+            connection_id,
+            address,
+            protocol,
+            receiver,
+            websocket,
+            registry: registry.clone(),
+            offline_queue,
+            overflow: handle.overflow.clone(),
+            slow_consumer: handle.slow_consumer.clone(),
+            replaced_flushed: handle.replaced_flushed.clone(),
+            replaced: handle.replaced.clone(),
+            message_limiter,
+            metrics,
+            rate_limit_violations: 0,
+        };
+
+        // Register before starting so the actor can not stop before it is registered and leave a dead session behind
+        let replaced = registry.register(id, device, connection_id, handle);
+        let replaced_flushed = match replaced {
+            Some(replaced) => {
+                let (flushed_sender, flushed_receiver) = oneshot::channel();
+                replaced.replace(flushed_sender);
+                Some(flushed_receiver)
+            }
+            None => None,
+        };
+
+        // Tracked so shutdown waits for the connection to be closed
+        shutdown.spawn(run_my_actor(actor, shutdown.clone(), replaced_flushed));
+    }
+
+    /// Tells the actor that a newer session replaced it without waiting for space in its channel,
+    /// so a replaced session that fell behind can not hold up the new one.
+    /// The sender is dropped once the actor moved its undelivered messages to the offline queue.
+    fn replace(&self, flushed: oneshot::Sender<()>) {
+        // Errors if the replaced actor already stopped which is fine as it flushed when it stopped
+        let result = self.sender.try_send(UserActorMessage::Replaced(flushed));
+        if let Err(TrySendError::Full(UserActorMessage::Replaced(flushed))) = result {
+            self.replaced_flushed.lock().unwrap().replace(flushed);
+            self.replaced.notify_one();
+        }
+    }
+
+    /// Passes the message to the actor without waiting.
+    /// If the channel is full the actor is told to disconnect and the message ends up in the offline queue.
+    pub(crate) fn try_send_message(&self, message: RoutedMessage) -> Delivery {
+        match self.sender.try_send(UserActorMessage::SendMessage(message)) {
+            Ok(()) => Delivery::Sent,
+            Err(TrySendError::Full(UserActorMessage::SendMessage(message))) => {
+                self.overflow.lock().unwrap().push(message);
+                self.slow_consumer.notify_one();
+                Delivery::SlowConsumer
+            }
+            Err(TrySendError::Full(_)) => Delivery::SlowConsumer,
+            // Errors when channel is closed
+            Err(TrySendError::Closed(_)) => Delivery::Closed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use axum::extract::ws::Message;
+    use bytes::Bytes;
+    use tls_codec::{Deserialize, Serialize};
+    use tokio::{sync::mpsc, time::timeout};
+
+    use super::{Client, UserActorHandle, CHANNEL_CAPACITY};
+    use crate::{
+        metrics::Metrics,
+        offline_queue::OfflineQueue,
+        protocol::{Frame, FrameBody, MlsFrame, Protocol, RoutedMessage, ERROR_NO_RECIPIENTS},
+        rate_limit::{ClientRateLimiter, RateLimit},
+        registry::ConnectionRegistry,
+        shutdown::Shutdown,
+        websocket_actor::{Outgoing, WebSocketHandle},
+    };
+
+    const MESSAGES: usize = 50;
+
+    struct TestServer {
+        registry: ConnectionRegistry,
+        offline_queue: OfflineQueue,
+        shutdown: Shutdown,
+        limiter: ClientRateLimiter,
+        metrics: Metrics,
+    }
+
+    /// The client side of a connection
+    struct TestClient {
+        incoming: mpsc::Sender<Message>,
+        outgoing: mpsc::Receiver<Outgoing>,
+    }
+
+    impl TestServer {
+        fn new() -> Self {
+            Self {
+                registry: ConnectionRegistry::default(),
+                offline_queue: OfflineQueue::default(),
+                shutdown: Shutdown::default(),
+                limiter: ClientRateLimiter::new(RateLimit {
+                    requests: 1000,
+                    period: Duration::from_secs(1),
+                }),
+                metrics: Metrics::default(),
+            }
+        }
+
+        /// Connects a client that buffers up to `capacity` messages until it reads them
+        async fn connect(&self, identity: &str, capacity: usize) -> TestClient {
+            let (outgoing_sender, outgoing) = mpsc::channel(capacity);
+            let (incoming, incoming_receiver) = mpsc::channel(MESSAGES);
+            let client = Client {
+                identity: identity.to_owned(),
+                device: "device".to_owned(),
+                address: Ipv4Addr::LOCALHOST.into(),
+            };
+
+            UserActorHandle::start(
+                client,
+                Protocol::V1,
+                WebSocketHandle::from_channels(outgoing_sender, incoming_receiver),
+                self.registry.clone(),
+                self.offline_queue.clone(),
+                self.shutdown.clone(),
+                self.limiter.clone(),
+                self.metrics.clone(),
+            )
+            .await;
+
+            TestClient { incoming, outgoing }
+        }
+    }
+
+    fn message_to(recipient: &str, request_id: u64) -> Message {
+        let frame = Frame {
+            request_id,
+            body: FrameBody::Mls(MlsFrame {
+                recipients: vec![recipient.as_bytes().into()],
+                sender: Vec::new().into(),
+                group_id: Vec::new().into(),
+                message: vec![0; 1024].into(),
+            }),
+        };
+        Message::Binary(frame.tls_serialize_detached().unwrap())
+    }
+
+    #[tokio::test]
+    async fn stalled_reader_overflows_into_offline_queue_without_blocking_sender() {
+        let server = TestServer::new();
+        // Never reads so its actor runs out of buffer after the first message
+        let mut stalled = server.connect("bob", 1).await;
+        let mut sender = server.connect("alice", MESSAGES).await;
+
+        for request_id in 1..=MESSAGES as u64 {
+            sender
+                .incoming
+                .send(message_to("bob", request_id))
+                .await
+                .unwrap();
+        }
+
+        // Every message is answered even though the recipient does not read anything
+        let mut undelivered = 0;
+        for _ in 0..MESSAGES {
+            let outgoing = timeout(Duration::from_secs(5), sender.outgoing.recv())
+                .await
+                .expect("sender was blocked by the stalled recipient")
+                .unwrap();
+            let Outgoing::Message(Message::Binary(data)) = outgoing else {
+                panic!("expected a frame");
+            };
+            let frame = Frame::tls_deserialize(&mut data.as_slice()).unwrap();
+            match frame.body {
+                FrameBody::Ack(ack) => undelivered += ack.undelivered.len(),
+                FrameBody::Error(error) if error.code == ERROR_NO_RECIPIENTS => undelivered += 1,
+                body => panic!("expected an acknowledgement, got {:?}", body),
+            }
+        }
+
+        // The stalled session is disconnected and its backlog waits for the next connection
+        timeout(Duration::from_secs(5), async {
+            while !server.registry.sessions_of("bob").is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("stalled recipient was not disconnected");
+
+        let queued = server.offline_queue.take("bob", "device").len();
+        assert!(queued > 0, "no messages were moved to the offline queue");
+
+        // At most one message made it into the buffer of the stalled reader before it was disconnected
+        let mut buffered = 0;
+        while let Ok(outgoing) = stalled.outgoing.try_recv() {
+            if let Outgoing::Shared(_) = outgoing {
+                buffered += 1;
+            }
+        }
+        assert!(buffered <= 1);
+
+        // Messages sent after the disconnect are reported as undelivered so the sender can send them again
+        assert_eq!(buffered + queued + undelivered, MESSAGES);
+    }
+
+    #[tokio::test]
+    async fn messages_to_stopped_actor_go_to_offline_queue() {
+        let server = TestServer::new();
+        // A session whose actor stopped before it was unregistered
+        let (stopped_sender, _) = mpsc::channel(1);
+        let stopped = UserActorHandle {
+            sender: stopped_sender,
+            overflow: Default::default(),
+            slow_consumer: Default::default(),
+            replaced_flushed: Default::default(),
+            replaced: Default::default(),
+        };
+        let connection_id = server.registry.next_connection_id();
+        server.registry.register(
+            "bob".to_owned(),
+            "device".to_owned(),
+            connection_id,
+            stopped,
+        );
+
+        let mut sender = server.connect("alice", MESSAGES).await;
+        sender.incoming.send(message_to("bob", 1)).await.unwrap();
+
+        let outgoing = timeout(Duration::from_secs(5), sender.outgoing.recv())
             .await
+            .expect("message was not acknowledged")
+            .unwrap();
+        let Outgoing::Message(Message::Binary(data)) = outgoing else {
+            panic!("expected a frame");
+        };
+        let frame = Frame::tls_deserialize(&mut data.as_slice()).unwrap();
+        let FrameBody::Ack(ack) = frame.body else {
+            panic!("expected an acknowledgement, got {:?}", frame.body);
+        };
+
+        assert!(ack.undelivered.is_empty());
+        assert!(server.registry.sessions_of("bob").is_empty());
+        assert_eq!(server.offline_queue.take("bob", "device").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn replacing_a_stalled_session_does_not_wait_for_it() {
+        let server = TestServer::new();
+        let queued = (0..MESSAGES)
+            .map(|_| RoutedMessage::new("alice", &[], Bytes::from_static(&[0; 16])).unwrap())
+            .collect();
+        server.offline_queue.requeue("bob", "device", queued);
+
+        // Gets stuck sending the queued messages as it never reads, so nothing takes messages from its channel
+        let stalled = server.connect("bob", 1).await;
+        let mut sender = server.connect("alice", MESSAGES).await;
+        for request_id in 1..=CHANNEL_CAPACITY as u64 {
+            sender
+                .incoming
+                .send(message_to("bob", request_id))
+                .await
+                .unwrap();
+        }
+
+        // The messages are routed before they are acknowledged so the channel is full afterwards
+        for _ in 0..CHANNEL_CAPACITY {
+            timeout(Duration::from_secs(5), sender.outgoing.recv())
+                .await
+                .expect("message was not acknowledged")
+                .unwrap();
+        }
+
+        let mut replacement = timeout(
+            Duration::from_secs(5),
+            server.connect("bob", MESSAGES + CHANNEL_CAPACITY),
+        )
+        .await
+        .expect("new session waited for the stalled session");
+
+        // The stalled session gives up once its client is gone and hands its messages over to the new session
+        drop(stalled);
+        let mut received = 0;
+        while let Ok(Some(outgoing)) =
+            timeout(Duration::from_secs(1), replacement.outgoing.recv()).await
+        {
+            if let Outgoing::Shared(_) = outgoing {
+                received += 1;
+            }
+        }
+
+        // Only the message in the buffer of the stalled client is lost with it
+        assert_eq!(received, MESSAGES - 1 + CHANNEL_CAPACITY);
     }
 }
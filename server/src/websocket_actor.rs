@@ -1,13 +1,206 @@
-use axum::extract::ws::WebSocket;
+use std::time::Duration;
 
-struct WebSocketActor {
-    websocket: WebSocket,
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use bytes::Bytes;
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::timeout,
+};
+
+use crate::metrics::Metrics;
+
+/// The client is pinged after not sending anything for this long and disconnected if it stays silent for another period
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait for the client to confirm closing the connection
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Messages waiting to be written to the socket. A client that lets this fill up is too slow.
+const OUTGOING_CAPACITY: usize = 32;
+const INCOMING_CAPACITY: usize = 8;
+
+/// A message waiting to be written to the socket
+pub(crate) enum Outgoing {
+    /// Binary content shared with other connections, like a message routed to many recipients
+    Shared(Bytes),
+    Message(Message),
+}
+
+impl From<Message> for Outgoing {
+    fn from(message: Message) -> Self {
+        Outgoing::Message(message)
+    }
+}
+
+/// Writes messages to the socket.
+/// Runs separately from the reader so a client that does not read its messages does not block reading from it.
+async fn run_writer(
+    mut sink: SplitSink<WebSocket, Message>,
+    mut outgoing: mpsc::Receiver<Outgoing>,
+    metrics: Metrics,
+) {
+    while let Some(message) = outgoing.recv().await {
+        let message = match message {
+            // The socket only takes owned content so shared content is copied right before it is written.
+            // Queued messages stay shared and only one copy per connection exists at a time.
+            Outgoing::Shared(bytes) => Message::Binary(bytes.to_vec()),
+            Outgoing::Message(message) => message,
+        };
+
+        let is_close = matches!(message, Message::Close(_));
+        if let Err(error) = sink.send(message).await {
+            tracing::debug!("Error writing to websocket: {:?}", error);
+            metrics.websocket_error();
+            break;
+        }
+
+        if is_close {
+            break;
+        }
+    }
+
+    // Flushes the reply to a close message from the client
+    if let Err(error) = sink.close().await {
+        tracing::debug!("Error closing websocket: {:?}", error);
+    }
 }
 
-impl WebSocketActor {
-    pub(crate) fn new(websocket: WebSocket) -> Self {
-        Self { websocket }
+/// Reads messages from the socket and disconnects idle clients
+async fn run_reader(
+    mut stream: SplitStream<WebSocket>,
+    incoming: mpsc::Sender<Message>,
+    outgoing: mpsc::Sender<Outgoing>,
+    metrics: Metrics,
+) {
+    let mut is_idle = false;
+    loop {
+        let result = tokio::select! {
+            // Stop when the connection is no longer used
+            _ = incoming.closed() => break,
+            result = timeout(IDLE_TIMEOUT, stream.next()) => result,
+        };
+
+        let message = match result {
+            Err(_) if !is_idle => {
+                is_idle = true;
+                // Pongs are handled like any other message and reset the idle state
+                let _ = outgoing.try_send(Message::Ping(Vec::new()).into());
+                continue;
+            }
+            Err(_) => {
+                tracing::debug!("Closing idle websocket");
+                let close = Message::Close(Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Idle timeout".into(),
+                }));
+                let _ = outgoing.try_send(close.into());
+                break;
+            }
+            Ok(None) => break,
+            Ok(Some(Err(error))) => {
+                tracing::debug!("Error reading from websocket: {:?}", error);
+                metrics.websocket_error();
+                break;
+            }
+            Ok(Some(Ok(message))) => message,
+        };
+
+        is_idle = false;
+        if let Message::Pong(_) | Message::Ping(_) = message {
+            // Pings are answered automatically
+            continue;
+        }
+
+        let is_close = matches!(message, Message::Close(_));
+        if incoming.send(message).await.is_err() || is_close {
+            break;
+        }
     }
 }
 
-async fn run_websocket_actor(mut actor: WebSocketActor) {}
+/// A websocket split into a reader and a writer task that communicate through channels
+pub(crate) struct WebSocketHandle {
+    sender: mpsc::Sender<Outgoing>,
+    receiver: mpsc::Receiver<Message>,
+}
+
+impl WebSocketHandle {
+    pub(crate) fn new(websocket: WebSocket, metrics: Metrics) -> Self {
+        let (sink, stream) = websocket.split();
+        let (outgoing_sender, outgoing_receiver) = mpsc::channel(OUTGOING_CAPACITY);
+        let (incoming_sender, incoming_receiver) = mpsc::channel(INCOMING_CAPACITY);
+
+        tokio::spawn(run_writer(sink, outgoing_receiver, metrics.clone()));
+        tokio::spawn(run_reader(
+            stream,
+            incoming_sender,
+            outgoing_sender.clone(),
+            metrics,
+        ));
+
+        Self {
+            sender: outgoing_sender,
+            receiver: incoming_receiver,
+        }
+    }
+
+    /// A handle that is connected to channels instead of a socket, to stand in for clients in tests
+    #[cfg(test)]
+    pub(crate) fn from_channels(
+        sender: mpsc::Sender<Outgoing>,
+        receiver: mpsc::Receiver<Message>,
+    ) -> Self {
+        Self { sender, receiver }
+    }
+
+    /// Queues the message for writing without waiting.
+    /// Errors if the writer can not keep up or stopped.
+    pub(crate) fn try_send(
+        &self,
+        message: impl Into<Outgoing>,
+    ) -> Result<(), TrySendError<Outgoing>> {
+        self.sender.try_send(message.into())
+    }
+
+    /// Queues the message for writing and waits for space in the queue if necessary.
+    /// Errors if the writer stopped.
+    pub(crate) async fn send(
+        &self,
+        message: impl Into<Outgoing>,
+    ) -> Result<(), mpsc::error::SendError<Outgoing>> {
+        self.sender.send(message.into()).await
+    }
+
+    /// Receives the next message from the client.
+    /// Returns `None` when the connection is closed or was idle for too long.
+    pub(crate) async fn recv(&mut self) -> Option<Message> {
+        self.receiver.recv().await
+    }
+
+    /// Closes the connection and waits for the client to confirm with a close message
+    pub(crate) async fn close(&mut self, code: u16, reason: &'static str) {
+        let close = Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        }));
+
+        // Give up if the writer is stuck
+        let Ok(Ok(())) = timeout(CLOSE_TIMEOUT, self.sender.send(close.into())).await else {
+            return;
+        };
+
+        let confirmation = async {
+            while let Some(message) = self.receiver.recv().await {
+                if let Message::Close(_) = message {
+                    break;
+                }
+            }
+        };
+
+        if timeout(CLOSE_TIMEOUT, confirmation).await.is_err() {
+            tracing::debug!("Client did not confirm closing the websocket");
+        }
+    }
+}
@@ -1,40 +1,38 @@
+use crate::error::command_error;
+use crate::{AdvertiseKeyPackageError, AppState, User, CIPHERSUITE};
 use openmls::credentials::{Credential, CredentialType, CredentialWithKey};
 use openmls::prelude::CredentialError;
 use openmls_basic_credential::SignatureKeyPair;
 use openmls_traits::types::CryptoError;
-use serde::Serialize;
 use tauri::State;
 use thiserror::Error;
-use crate::{AdvertiseKeyPackageError, AppState, CIPHERSUITE, User};
 
-#[derive(Error, Debug, Serialize)]
+#[derive(Error, Debug)]
 pub(crate) enum CreateUserError {
     #[error("User already exists")]
     UserExists,
     #[error("Error creating credentials for user")]
-    CredentialsError(
-        #[from]
-        #[serde(skip)]
-        CredentialError,
-    ),
+    CredentialsError(#[from] CredentialError),
 
     #[error("Error creating signature key pair")]
-    SignatureKeyPairError(
-        #[from]
-        #[serde(skip)]
-        CryptoError,
-    ),
+    SignatureKeyPairError(#[from] CryptoError),
 
     #[error("Error advertising key package on server")]
-    AdvertiseKeyPackageError(
-        #[from]
-        #[serde(skip)]
-        AdvertiseKeyPackageError,
-    ),
+    AdvertiseKeyPackageError(#[from] AdvertiseKeyPackageError),
 }
 
+command_error!(CreateUserError {
+    UserExists => "user_exists",
+    CredentialsError => "create_credential_failed",
+    SignatureKeyPairError => "create_signature_key_failed",
+    AdvertiseKeyPackageError => "advertise_key_package_failed",
+});
+
 #[tauri::command]
-pub(crate) async fn create_user(name: &str, state: State<'_, AppState>) -> Result<(), CreateUserError> {
+pub(crate) async fn create_user(
+    name: &str,
+    state: State<'_, AppState>,
+) -> Result<(), CreateUserError> {
     let mut state = state.user.lock().await;
     if state.is_some() {
         return Err(CreateUserError::UserExists);
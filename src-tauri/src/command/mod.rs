@@ -1,2 +1,2 @@
- mod create_user;
- pub use create_user::*;
\ No newline at end of file
+mod create_user;
+pub use create_user::*;
@@ -0,0 +1,222 @@
+//! The websocket to the delivery server.
+//! It is opened by the backend instead of the webview so it uses the same transport security as the HTTP requests,
+//! including the pinned certificate.
+
+use base64::prelude::*;
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use tauri::{
+    async_runtime::{self, JoinHandle},
+    AppHandle, Manager, State,
+};
+use thiserror::Error;
+use tokio::{net::TcpStream, sync::mpsc};
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        http::{
+            header::{InvalidHeaderValue, SEC_WEBSOCKET_PROTOCOL},
+            HeaderValue,
+        },
+        Message,
+    },
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{
+    error::command_error,
+    process_frame,
+    signed_request::{signature_headers, SignRequestError},
+    subscribe_frame, AppState,
+};
+
+/// The subprotocol tells the server to use framing
+const SUBPROTOCOL: &str = "mealt.v1";
+/// Frames waiting to be written. Sending waits for space if the server reads slower than the user sends.
+const OUTGOING_CAPACITY: usize = 32;
+
+const CONNECTION_CLOSED_EVENT: &str = "connection_closed";
+const PROCESS_MESSAGE_FAILED_EVENT: &str = "process_message_failed";
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// The open websocket. Dropping it closes the connection.
+pub(crate) struct Connection {
+    outgoing: mpsc::Sender<Vec<u8>>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+async fn run_writer(mut sink: SplitSink<Socket, Message>, mut outgoing: mpsc::Receiver<Vec<u8>>) {
+    while let Some(frame) = outgoing.recv().await {
+        if sink.send(Message::Binary(frame)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Processes the frames from the server and queues the replies
+async fn run_reader(
+    mut stream: SplitStream<Socket>,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    app: AppHandle,
+) {
+    while let Some(Ok(message)) = stream.next().await {
+        let data = match message {
+            Message::Binary(data) => data,
+            Message::Close(_) => break,
+            // Pings are answered automatically and the server only sends binary frames
+            _ => continue,
+        };
+
+        let state = app.state::<AppState>();
+        match process_frame(&data, &state, &app).await {
+            Ok(Some(reply)) => {
+                if outgoing.send(reply).await.is_err() {
+                    break;
+                }
+            }
+            Ok(None) => {}
+            // A single invalid message should not close the connection
+            Err(error) => {
+                let _ = app.emit(
+                    PROCESS_MESSAGE_FAILED_EVENT,
+                    serde_json::to_value(&error).ok(),
+                );
+            }
+        }
+    }
+
+    let _ = app.emit(CONNECTION_CLOSED_EVENT, ());
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum ConnectError {
+    #[error("No user is signed in")]
+    NoUserError,
+    #[error("Could not connect to server")]
+    WebSocketError(#[from] tungstenite::Error),
+    #[error("Error serializing subscription")]
+    SerializeError(#[from] tls_codec::Error),
+    #[error("Error signing connect request")]
+    SignRequestError(#[from] SignRequestError),
+    #[error("Invalid header in connect request")]
+    InvalidHeaderError(#[from] InvalidHeaderValue),
+}
+
+command_error!(ConnectError {
+    NoUserError => "no_user",
+    WebSocketError => "connect_failed",
+    SerializeError => "serialize_failed",
+    SignRequestError => "sign_request_failed",
+    InvalidHeaderError => "connect_failed",
+});
+
+/// Opens the websocket for the messages of the user and subscribes to all groups.
+/// Replaces the previous connection.
+#[tauri::command]
+pub(crate) async fn connect(
+    state: State<'_, AppState>,
+    app: AppHandle,
+) -> Result<(), ConnectError> {
+    let (path, headers) = {
+        let user = state.user.lock().await;
+        let Some(user) = user.as_ref() else {
+            return Err(ConnectError::NoUserError);
+        };
+        let identity = BASE64_URL_SAFE_NO_PAD.encode(user.credential.credential.identity());
+        let path = format!("/{}/messages", identity);
+        // The server only lets the owner of the identity receive its messages and send as it
+        let headers = signature_headers("GET", &path, &[], &user.signature_key)?;
+        (path, headers)
+    };
+
+    let url = state.server.websocket_url(&path);
+    let mut request = url.into_client_request()?;
+    request.headers_mut().insert(
+        SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static(SUBPROTOCOL),
+    );
+    for (name, value) in headers {
+        request
+            .headers_mut()
+            .insert(name, HeaderValue::try_from(value)?);
+    }
+
+    let (socket, _response) =
+        connect_async_tls_with_config(request, None, false, state.server.websocket_connector())
+            .await?;
+    let (sink, stream) = socket.split();
+
+    let (outgoing, receiver) = mpsc::channel(OUTGOING_CAPACITY);
+    // Queued before anything else so no message of the groups is missed
+    outgoing
+        .send(subscribe_frame(&state).await?)
+        .await
+        .expect("receiver is not dropped yet");
+
+    let connection = Connection {
+        outgoing: outgoing.clone(),
+        reader: async_runtime::spawn(run_reader(stream, outgoing, app)),
+        writer: async_runtime::spawn(run_writer(sink, receiver)),
+    };
+
+    let mut current = state.connection.lock().await;
+    current.replace(connection);
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum SendFrameError {
+    #[error("Not connected to the server")]
+    NotConnected,
+    #[error("Error serializing subscription")]
+    SerializeError(#[from] tls_codec::Error),
+}
+
+command_error!(SendFrameError {
+    NotConnected => "not_connected",
+    SerializeError => "serialize_failed",
+});
+
+/// Queues the frame for writing and waits if the queue is full
+pub(crate) async fn send(state: &AppState, frame: Vec<u8>) -> Result<(), SendFrameError> {
+    let connection = state.connection.lock().await;
+    let Some(connection) = connection.as_ref() else {
+        return Err(SendFrameError::NotConnected);
+    };
+
+    connection
+        .outgoing
+        .send(frame)
+        .await
+        .map_err(|_| SendFrameError::NotConnected)
+}
+
+/// Sends a frame created by another command, like a message or a commit, to the server
+#[tauri::command]
+pub(crate) async fn send_frame(
+    data: Vec<u8>,
+    state: State<'_, AppState>,
+) -> Result<(), SendFrameError> {
+    send(&state, data).await
+}
+
+/// Subscribes to the messages of all groups again.
+/// Needs to be called after joining a group to receive its messages.
+#[tauri::command]
+pub(crate) async fn subscribe(state: State<'_, AppState>) -> Result<(), SendFrameError> {
+    let frame = subscribe_frame(&state).await?;
+    send(&state, frame).await
+}
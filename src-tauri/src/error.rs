@@ -0,0 +1,94 @@
+//! The error model shared by all commands.
+//! Errors are serialized as `ErrorDetails` so the frontend can react to them without parsing messages.
+
+use std::error::Error;
+
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ErrorDetails {
+    /// Stable identifier the frontend can match on, e.g. "group_not_found"
+    code: &'static str,
+    /// Human readable description of the error
+    message: String,
+    /// Whether running the command again can succeed, e.g. after a timeout
+    retryable: bool,
+    /// Status code of the failed request to the server
+    status: Option<u16>,
+    /// Description of the underlying causes without URLs or other data from requests
+    details: Option<String>,
+}
+
+impl ErrorDetails {
+    pub(crate) fn new(code: &'static str, error: &(dyn Error + 'static)) -> Self {
+        let mut retryable = false;
+        let mut status = None;
+        let mut causes = Vec::new();
+
+        let mut source = error.source();
+        while let Some(cause) = source {
+            match cause.downcast_ref::<reqwest::Error>() {
+                Some(request_error) => {
+                    status = request_error.status().map(|status| status.as_u16());
+                    retryable = is_retryable(request_error);
+                    causes.push(describe_request_error(request_error));
+                }
+                None => causes.push(cause.to_string()),
+            }
+
+            source = cause.source();
+        }
+
+        Self {
+            code,
+            message: error.to_string(),
+            retryable,
+            status,
+            details: (!causes.is_empty()).then(|| causes.join(": ")),
+        }
+    }
+}
+
+fn is_retryable(error: &reqwest::Error) -> bool {
+    if error.is_timeout() || error.is_connect() {
+        return true;
+    }
+
+    error
+        .status()
+        .is_some_and(|status| status.is_server_error() || status.as_u16() == 429)
+}
+
+/// The message of request errors contains the URL which can include identities
+fn describe_request_error(error: &reqwest::Error) -> String {
+    if let Some(status) = error.status() {
+        return format!("Server responded with status {}", status.as_u16());
+    }
+
+    if error.is_timeout() {
+        "Request timed out".to_owned()
+    } else if error.is_connect() {
+        "Could not connect to server".to_owned()
+    } else if error.is_decode() || error.is_body() {
+        "Could not read response".to_owned()
+    } else {
+        "Request failed".to_owned()
+    }
+}
+
+/// Implements `Serialize` for an error returned by a command by mapping each variant to a stable code.
+macro_rules! command_error {
+    ($error:ident { $($variant:ident => $code:literal),* $(,)? }) => {
+        impl serde::Serialize for $error {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let code = match self {
+                    $($error::$variant { .. } => $code,)*
+                };
+
+                $crate::error::ErrorDetails::new(code, self).serialize(serializer)
+            }
+        }
+    };
+}
+
+pub(crate) use command_error;
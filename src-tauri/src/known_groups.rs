@@ -0,0 +1,75 @@
+//! Ids of the groups the user was a member of, kept on disk.
+//! The group state itself is only kept in memory, so after losing it these are the groups that can be recovered.
+//! Messages for other groups are ignored instead of joining groups the user never belonged to.
+
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub(crate) enum KnownGroupsError {
+    #[error("Could not access known groups file")]
+    IoError(#[from] io::Error),
+    #[error("Could not read known groups")]
+    JsonError(#[from] serde_json::Error),
+}
+
+#[derive(Default)]
+pub(crate) struct KnownGroups {
+    /// `None` until the app data directory is known
+    path: Option<PathBuf>,
+    ids: BTreeSet<String>,
+}
+
+impl KnownGroups {
+    /// Reads the groups from the file. A missing file means no groups are known yet.
+    pub(crate) fn load(path: PathBuf) -> Result<Self, KnownGroupsError> {
+        let ids = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            ids,
+        })
+    }
+
+    pub(crate) fn contains(&self, group_id: &str) -> bool {
+        self.ids.contains(group_id)
+    }
+
+    pub(crate) fn ids(&self) -> impl Iterator<Item = &str> {
+        self.ids.iter().map(String::as_str)
+    }
+
+    /// Remembers the group and saves the file if the group was not known yet
+    pub(crate) fn insert(&mut self, group_id: &str) -> Result<(), KnownGroupsError> {
+        if !self.ids.insert(group_id.to_owned()) {
+            return Ok(());
+        }
+
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        save(path, &self.ids)
+    }
+}
+
+/// Writes to a temporary file first so a crash does not leave a partial file behind
+fn save(path: &Path, ids: &BTreeSet<String>) -> Result<(), KnownGroupsError> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_vec(ids)?)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
@@ -1,19 +1,35 @@
 mod command;
+mod connection;
+mod error;
+mod known_groups;
+mod protocol;
+mod server;
+mod signed_request;
 
 use base64::prelude::*;
+use connection::{Connection, SendFrameError};
+use error::command_error;
+use known_groups::{KnownGroups, KnownGroupsError};
 use openmls::prelude::*;
 use openmls_basic_credential::SignatureKeyPair;
 use openmls_rust_crypto::{MemoryKeyStoreError, OpenMlsRustCrypto};
+use protocol::{Frame, FrameBody};
 use reqwest::{Client, Method};
 use serde::Serialize;
+use server::Server;
+use signed_request::{signature_headers, SignRequestError};
+use std::string::FromUtf8Error;
 use std::{
     collections::HashMap,
     io::Read,
-    sync::{Arc, PoisonError},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError,
+    },
 };
-use std::string::FromUtf8Error;
 use tauri::{AppHandle, Manager, State};
 use thiserror::Error;
+use tls_codec::VLBytes;
 use tokio::sync::Mutex;
 
 // Disable dead code warnings for this file
@@ -31,15 +47,66 @@ struct AppState {
     backend: Arc<OpenMlsRustCrypto>,
     user: Arc<Mutex<Option<User>>>,
     groups: Arc<Mutex<HashMap<String, MlsGroup>>>,
+    /// Groups the user was a member of, kept across restarts to know which lost groups can be recovered
+    known_groups: Arc<Mutex<KnownGroups>>,
+    /// Pending invitations by group id
+    invitations: Arc<Mutex<HashMap<String, Invitation>>>,
     client: Client,
+    server: Server,
+    /// The websocket to the server once the frontend connected
+    connection: Mutex<Option<Connection>>,
+    next_request_id: AtomicU64,
+}
+
+/// A group the user was invited to but did not join yet
+struct Invitation {
+    /// Created from the welcome but not used until the invitation is accepted
+    group: MlsGroup,
+    /// Identity of the member that sent the welcome
+    inviter: String,
 }
 
-#[derive(Error, Debug, Serialize)]
+/// Description of an invitation for the user to decide whether to accept it
+#[derive(Serialize, Clone)]
+struct InvitationInfo {
+    group_id: String,
+    inviter: String,
+    members: Vec<String>,
+}
+
+impl Invitation {
+    fn info(&self) -> InvitationInfo {
+        let members = self
+            .group
+            .members()
+            .map(|member| BASE64_URL_SAFE_NO_PAD.encode(member.credential.identity()))
+            .collect();
+
+        InvitationInfo {
+            group_id: BASE64_URL_SAFE_NO_PAD.encode(self.group.group_id().as_slice()),
+            inviter: self.inviter.clone(),
+            members,
+        }
+    }
+}
+
+impl AppState {
+    /// Ids of requests sent over the websocket to match them with the response from the server
+    fn next_request_id(&self) -> u64 {
+        self.next_request_id.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[derive(Error, Debug)]
 enum IsAuthenticatedError {
     #[error("Could not access state")]
     PoisonError,
 }
 
+command_error!(IsAuthenticatedError {
+    PoisonError => "state_unavailable",
+});
+
 impl<T> From<PoisonError<T>> for IsAuthenticatedError {
     fn from(_: PoisonError<T>) -> Self {
         IsAuthenticatedError::PoisonError
@@ -52,39 +119,84 @@ async fn is_authenticated(state: State<'_, AppState>) -> Result<bool, IsAuthenti
     Ok(state.is_some())
 }
 
-#[derive(Error, Debug, Serialize)]
+#[derive(Error, Debug)]
 enum CreateGroupError {
     #[error("No user is signed in")]
     NoUserError,
     #[error("Error creating group")]
-    NewGroupError(
-        #[from]
-        #[serde(skip)]
-        NewGroupError<MemoryKeyStoreError>,
-    ),
+    NewGroupError(#[from] NewGroupError<MemoryKeyStoreError>),
+    #[error("Error publishing group info")]
+    PublishGroupInfoError(#[from] PublishGroupInfoError),
+    #[error("Error saving known groups")]
+    KnownGroupsError(#[from] KnownGroupsError),
+}
+
+command_error!(CreateGroupError {
+    NoUserError => "no_user",
+    NewGroupError => "create_group_failed",
+    PublishGroupInfoError => "publish_group_info_failed",
+    KnownGroupsError => "save_known_groups_failed",
+});
+
+#[derive(Error, Debug)]
+enum PublishGroupInfoError {
+    #[error("Error exporting group info")]
+    ExportGroupInfoError(#[from] ExportGroupInfoError),
+    #[error("Error serializing group info")]
+    SerializeError(#[from] tls_codec::Error),
+    #[error("Error signing group info request")]
+    SignRequestError(#[from] SignRequestError),
+    #[error("Could not send group info")]
+    RequestError(#[from] reqwest::Error),
+}
+
+/// Publishes the group info of the current epoch so others can join with an external commit.
+/// Has to be called after every commit as external commits are only valid for the epoch of the group info.
+async fn publish_group_info(
+    group: &MlsGroup,
+    backend: &OpenMlsRustCrypto,
+    user: &User,
+    client: &Client,
+    server: &Server,
+) -> Result<(), PublishGroupInfoError> {
+    // Include the ratchet tree so joiners don't need to get it from somewhere else.
+    // The server also needs it to check that the group info was signed by the user.
+    let group_info = group.export_group_info(backend, &user.signature_key, true)?;
+    upload_group_info(group.group_id(), &group_info, user, client, server).await
 }
 
-#[derive(Error, Debug, Serialize)]
+/// Publishes a group info that was exported before, e.g. while joining
+async fn upload_group_info(
+    group_id: &GroupId,
+    group_info: &MlsMessageOut,
+    user: &User,
+    client: &Client,
+    server: &Server,
+) -> Result<(), PublishGroupInfoError> {
+    let data = group_info.tls_serialize_detached()?;
+
+    let identity = BASE64_URL_SAFE_NO_PAD.encode(user.credential.credential.identity());
+    let group_id = BASE64_URL_SAFE_NO_PAD.encode(group_id.as_slice());
+    let path = format!("/{}/groups/{}/info", identity, group_id);
+    let mut request = client.put(server.url(&path));
+    for (name, value) in signature_headers("PUT", &path, &data, &user.signature_key)? {
+        request = request.header(name, value);
+    }
+
+    let response = request.body(data).send().await?;
+    response.error_for_status()?;
+    Ok(())
+}
+
+#[derive(Error, Debug)]
 enum AdvertiseKeyPackageError {
     #[error("Could not create a key package")]
-    CreateKeyPackageError(
-        #[from]
-        #[serde(skip)]
-        KeyPackageNewError<MemoryKeyStoreError>,
-    ),
+    CreateKeyPackageError(#[from] KeyPackageNewError<MemoryKeyStoreError>),
     #[error("Could not serialize key package")]
-    SerializeKeyPackageError(
-        #[from]
-        #[serde(skip)]
-        tls_codec::Error,
-    ),
+    SerializeKeyPackageError(#[from] tls_codec::Error),
 
     #[error("Could not send key package")]
-    RequestError(
-        #[from]
-        #[serde(skip)]
-        reqwest::Error,
-    ),
+    RequestError(#[from] reqwest::Error),
 }
 
 async fn advertise_key_package(
@@ -92,6 +204,7 @@ async fn advertise_key_package(
     signer: &SignatureKeyPair,
     credential_with_key: CredentialWithKey,
     client: &Client,
+    server: &Server,
 ) -> Result<(), AdvertiseKeyPackageError> {
     // Create key package
     let package = KeyPackage::builder().build(
@@ -106,7 +219,7 @@ async fn advertise_key_package(
 
     let package = package.tls_serialize_detached()?;
     let response = client
-        .request(Method::POST, "http://localhost:3000/packages")
+        .request(Method::POST, server.url("/packages"))
         .body(package)
         .send()
         .await?;
@@ -115,7 +228,7 @@ async fn advertise_key_package(
     Ok(())
 }
 
-#[derive(Error, Debug, Serialize)]
+#[derive(Error, Debug)]
 enum AdvertiseError {
     #[error("No user is signed in")]
     NoUserError,
@@ -123,6 +236,11 @@ enum AdvertiseError {
     AdvertiseKeyPackageError(#[from] AdvertiseKeyPackageError),
 }
 
+command_error!(AdvertiseError {
+    NoUserError => "no_user",
+    AdvertiseKeyPackageError => "advertise_key_package_failed",
+});
+
 #[tauri::command]
 async fn advertise(state: State<'_, AppState>) -> Result<(), AdvertiseError> {
     let user = state.user.lock().await;
@@ -135,98 +253,123 @@ async fn advertise(state: State<'_, AppState>) -> Result<(), AdvertiseError> {
         &user.signature_key,
         user.credential.clone(),
         &state.client,
+        &state.server,
     )
     .await?;
 
     Ok(())
 }
-#[derive(Error, Debug, Serialize)]
+/// Key packages are small. A larger response is not a key package and is not read any further.
+const MAX_PACKAGE_SIZE: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
 enum GetPackageError {
     #[error("Error getting package from server")]
-    RequestError(
-        #[from]
-        #[serde(skip)]
-        reqwest::Error,
-    ),
+    RequestError(#[from] reqwest::Error),
+    #[error("Package exceeds the maximum size of {MAX_PACKAGE_SIZE} bytes")]
+    TooLarge,
     #[error("Error deserializing package")]
-    DeserializeError(
-        #[from]
-        #[serde(skip)]
-        tls_codec::Error,
-    ),
+    DeserializeError(#[from] tls_codec::Error),
+}
+
+async fn get_package(
+    id: &str,
+    client: &Client,
+    server: &Server,
+) -> Result<KeyPackageIn, GetPackageError> {
+    let mut response = client
+        .get(server.url(&format!("/packages/{}", id)))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    // Read chunk by chunk so a misbehaving server can't make the client buffer an unlimited response
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if bytes.len() + chunk.len() > MAX_PACKAGE_SIZE {
+            return Err(GetPackageError::TooLarge);
+        }
+
+        bytes.extend_from_slice(&chunk);
+    }
+
+    let package = KeyPackageIn::tls_deserialize_exact(&bytes)?;
+
+    Ok(package)
+}
+
+#[derive(Error, Debug)]
+enum GetGroupInfoError {
+    #[error("Error getting group info from server")]
+    RequestError(#[from] reqwest::Error),
+    #[error("Error deserializing group info")]
+    DeserializeError(#[from] tls_codec::Error),
+    #[error("Server returned a message that is not a group info")]
+    NotAGroupInfo,
 }
 
-async fn get_package(id: &str, client: &Client) -> Result<KeyPackageIn, GetPackageError> {
+async fn get_group_info(
+    group_id: &str,
+    client: &Client,
+    server: &Server,
+) -> Result<VerifiableGroupInfo, GetGroupInfoError> {
     let response = client
-        .get(&format!("http://localhost:3000/packages/{}", id))
+        .get(server.url(&format!("/groups/{}/info", group_id)))
         .send()
         .await?
         .error_for_status()?;
 
-    //TODO directly stream bytes into deserializer without first buffering
     let bytes = response.bytes().await?;
-    let package = KeyPackageIn::tls_deserialize(&mut bytes.as_ref())?;
+    let message = MlsMessageIn::tls_deserialize(&mut bytes.as_ref())?;
+    let MlsMessageInBody::GroupInfo(group_info) = message.extract() else {
+        return Err(GetGroupInfoError::NotAGroupInfo);
+    };
 
-    Ok(package)
+    Ok(group_info)
 }
 
-#[derive(Error, Debug, Serialize)]
+#[derive(Error, Debug)]
 enum SendMessageError {
     #[error("Error sending message")]
-    RequestError(
-        #[from]
-        #[serde(skip)]
-        reqwest::Error,
-    ),
+    RequestError(#[from] reqwest::Error),
     #[error("Error serializing message")]
-    SerializeError(
-        #[from]
-        #[serde(skip)]
-        tls_codec::Error,
-    ),
+    SerializeError(#[from] tls_codec::Error),
 }
 
-#[derive(Error, Debug, Serialize)]
+#[derive(Error, Debug)]
 enum InvitePackageError {
     #[error("No user is signed in")]
     NoUserError,
     #[error("Group not found")]
     GroupNotFound,
     #[error("Error getting package from server")]
-    GetPackageError(
-        #[from]
-        #[serde(skip)]
-        GetPackageError,
-    ),
+    GetPackageError(#[from] GetPackageError),
     #[error("Error validating package")]
-    ValidatePackageError(
-        #[from]
-        #[serde(skip)]
-        KeyPackageVerifyError,
-    ),
+    ValidatePackageError(#[from] KeyPackageVerifyError),
 
     #[error("Error adding member to group")]
-    AddMemberError(
-        #[from]
-        #[serde(skip)]
-        AddMembersError<MemoryKeyStoreError>,
-    ),
+    AddMemberError(#[from] AddMembersError<MemoryKeyStoreError>),
 
     #[error("Error serializing welcome message")]
-    SerializeWelcomeError(
-        #[from]
-        #[serde(skip)]
-        tls_codec::Error,
-    ),
+    SerializeWelcomeError(#[from] tls_codec::Error),
 
     #[error("Error merging pending commit")]
-    MergePendingCommitError(
-        #[from]
-        #[serde(skip)]
-        MergePendingCommitError<MemoryKeyStoreError>,
-    ),
+    MergePendingCommitError(#[from] MergePendingCommitError<MemoryKeyStoreError>),
+    #[error("Error publishing group info")]
+    PublishGroupInfoError(#[from] PublishGroupInfoError),
 }
 
+command_error!(InvitePackageError {
+    NoUserError => "no_user",
+    GroupNotFound => "group_not_found",
+    GetPackageError => "get_key_package_failed",
+    ValidatePackageError => "invalid_key_package",
+    AddMemberError => "add_member_failed",
+    SerializeWelcomeError => "serialize_failed",
+    MergePendingCommitError => "merge_commit_failed",
+    PublishGroupInfoError => "publish_group_info_failed",
+});
+
 #[tauri::command]
 async fn invite_package(
     group_id: &str,
@@ -243,7 +386,10 @@ async fn invite_package(
         return Err(InvitePackageError::GroupNotFound);
     };
 
-    let package = get_package(package_id, &state.client).await?;
+    let package = get_package(package_id, &state.client, &state.server).await?;
+    // The server knows clients by the same encoding of the identity that is used for their websocket
+    let invitee = package.unverified_credential().credential;
+    let invitee = BASE64_URL_SAFE_NO_PAD.encode(invitee.identity());
 
     let backend = state.backend.crypto();
     let package = package.validate(backend, ProtocolVersion::default())?;
@@ -254,56 +400,83 @@ async fn invite_package(
     //TODO check if commit needs to be synchronized with others
     // Merge pending commit that adds the new member
     group.merge_pending_commit(state.backend.as_ref())?;
+    publish_group_info(group, &state.backend, user, &state.client, &state.server).await?;
 
-    // Return welcome message to frontend to send over websockets to other clients
+    // Return welcome message to frontend to send over websockets to the invited client
     let data = welcome_out.tls_serialize_detached()?;
+    let frame = Frame::mls(
+        state.next_request_id(),
+        vec![invitee.as_bytes().into()],
+        &[],
+        data,
+    );
+    let data = frame.tls_serialize_detached()?;
 
     Ok(data)
 }
 
-#[derive(Error, Debug, Serialize)]
+#[derive(Error, Debug)]
 enum ReceiveMessageError {
     #[error("Error deserializing message")]
-    DeserializeError(
-        #[from]
-        #[serde(skip)]
-        tls_codec::Error,
-    ),
+    DeserializeError(#[from] tls_codec::Error),
     #[error("Error joining group")]
-    JoinGroupError(
-        #[from]
-        #[serde(skip)]
-        WelcomeError<MemoryKeyStoreError>,
-    ),
+    JoinGroupError(#[from] WelcomeError<MemoryKeyStoreError>),
     #[error("Error emitting event")]
-    EmitError(
-        #[from]
-        #[serde(skip)]
-        tauri::Error,
-    ),
+    EmitError(#[from] tauri::Error),
 
     //TODO Private message errors
-    #[error("Group not found")]
-    GroupNotFound,
     #[error("Error processing message")]
-    ProcessMessageError(
-        #[from]
-        #[serde(skip)]
-        ProcessMessageError,
-    ),
+    ProcessMessageError(#[from] ProcessMessageError),
     #[error("Error deserializing message")]
-    DeserializeMessageError(
-        #[from]
-        #[serde(skip)]
-        FromUtf8Error,
-    ),
+    DeserializeMessageError(#[from] FromUtf8Error),
+    #[error("Error serializing reply")]
+    SerializeReplyError(#[source] tls_codec::Error),
+    #[error("No user is signed in")]
+    NoUserError,
+    #[error("Error merging commit")]
+    MergeCommitError(#[from] MergeCommitError<MemoryKeyStoreError>),
+    #[error("Error publishing group info")]
+    PublishGroupInfoError(#[from] PublishGroupInfoError),
+    #[error("Invitation was not sent by a member of the group")]
+    InviterNotMemberError,
+    #[error("Message for a group the user was never a member of")]
+    UnknownGroupError,
+    #[error("Message type is not supported")]
+    UnsupportedMessageError,
 }
 
-const JOIN_GROUP_EVENT: &str = "join_group";
+command_error!(ReceiveMessageError {
+    DeserializeError => "invalid_message",
+    JoinGroupError => "join_group_failed",
+    EmitError => "emit_event_failed",
+    ProcessMessageError => "process_message_failed",
+    DeserializeMessageError => "invalid_message",
+    SerializeReplyError => "serialize_failed",
+    NoUserError => "no_user",
+    MergeCommitError => "merge_commit_failed",
+    PublishGroupInfoError => "publish_group_info_failed",
+    InviterNotMemberError => "inviter_not_member",
+    UnknownGroupError => "unknown_group",
+    UnsupportedMessageError => "unsupported_message",
+});
+
+const INVITATION_RECEIVED_EVENT: &str = "invitation_received";
 const NEW_MESSAGE_EVENT: &str = "new_message";
+const MESSAGE_ACKNOWLEDGED_EVENT: &str = "message_acknowledged";
+const MESSAGE_FAILED_EVENT: &str = "message_failed";
+const GROUP_LOST_EVENT: &str = "group_lost";
+const GROUP_RECOVERED_EVENT: &str = "group_recovered";
 
+/// A message arrived for a group the user was a member of but lost the state of.
+/// The user can rejoin it with `recover_group`.
 #[derive(Serialize, Clone)]
-struct JoinGroupEvent {
+struct GroupLostEvent {
+    group_id: String,
+}
+
+/// The user rejoined a group it had lost the state of
+#[derive(Serialize, Clone)]
+struct GroupRecoveredEvent {
     group_id: String,
 }
 
@@ -313,18 +486,89 @@ struct NewMessageEvent {
     message: String,
 }
 
-#[tauri::command]
-async fn process_message(
-    data: Vec<u8>,
-    state: State<'_, AppState>,
-    app: AppHandle,
-) -> Result<(), ReceiveMessageError> {
-    let message = MlsMessageIn::tls_deserialize(&mut data.as_slice())?;
+#[derive(Serialize, Clone)]
+struct MessageAcknowledgedEvent {
+    request_id: u64,
+    /// Recipients that were not connected
+    undelivered: Vec<String>,
+}
+
+#[derive(Serialize, Clone)]
+struct MessageFailedEvent {
+    request_id: u64,
+    code: u16,
+    message: String,
+}
+
+/// Processes a frame received from the server.
+/// Returns a frame to send back to the server if the frame requires a reply.
+async fn process_frame(
+    mut data: &[u8],
+    state: &AppState,
+    app: &AppHandle,
+) -> Result<Option<Vec<u8>>, ReceiveMessageError> {
+    let frame = Frame::tls_deserialize(&mut data)?;
+    let request_id = frame.request_id;
+    let reply = match frame.body {
+        FrameBody::Mls(frame) => {
+            let sender = String::from_utf8_lossy(frame.sender.as_slice());
+            process_mls_message(frame.message.as_slice(), &sender, state, app).await?
+        }
+        FrameBody::Ping(_) => Some(Frame::ack(request_id)),
+        FrameBody::Ack(ack) => {
+            let undelivered = ack
+                .undelivered
+                .iter()
+                .map(|identity| String::from_utf8_lossy(identity.as_slice()).into_owned())
+                .collect();
+            app.emit(
+                MESSAGE_ACKNOWLEDGED_EVENT,
+                MessageAcknowledgedEvent {
+                    request_id,
+                    undelivered,
+                },
+            )?;
+            None
+        }
+        FrameBody::Error(error) => {
+            let message = String::from_utf8_lossy(error.message.as_slice()).into_owned();
+            app.emit(
+                MESSAGE_FAILED_EVENT,
+                MessageFailedEvent {
+                    request_id,
+                    code: error.code,
+                    message,
+                },
+            )?;
+            None
+        }
+        // Only sent by clients
+        FrameBody::Subscribe(_) => None,
+    };
+
+    let Some(reply) = reply else {
+        return Ok(None);
+    };
+
+    let reply = reply
+        .tls_serialize_detached()
+        .map_err(ReceiveMessageError::SerializeReplyError)?;
+    Ok(Some(reply))
+}
+
+async fn process_mls_message(
+    mut data: &[u8],
+    sender: &str,
+    state: &AppState,
+    app: &AppHandle,
+) -> Result<Option<Frame>, ReceiveMessageError> {
+    let message = MlsMessageIn::tls_deserialize(&mut data)?;
 
     let extract = message.extract();
     match extract {
         MlsMessageInBody::Welcome(welcome) => {
-            // Create group from welcome message
+            // Creating the group does not send anything to the members.
+            // It is kept aside until the user accepts the invitation.
             let group = MlsGroup::new_from_welcome(
                 state.backend.as_ref(),
                 &MlsGroupConfig::default(),
@@ -332,42 +576,84 @@ async fn process_message(
                 None,
             )?;
 
-            let id = group.group_id();
-            let id = BASE64_URL_SAFE_NO_PAD.encode(id.as_slice());
+            // The sender is only known to the server so it has to be a member to be trusted as inviter
+            let is_member = group.members().any(|member| {
+                BASE64_URL_SAFE_NO_PAD.encode(member.credential.identity()) == sender
+            });
+            if !is_member {
+                return Err(ReceiveMessageError::InviterNotMemberError);
+            }
 
-            let mut groups = state.groups.lock().await;
-            groups.insert(id.clone(), group);
+            let invitation = Invitation {
+                group,
+                inviter: sender.to_owned(),
+            };
+            let info = invitation.info();
 
-            app.emit(JOIN_GROUP_EVENT, JoinGroupEvent { group_id: id })?;
-            Ok(())
-        },
+            let mut invitations = state.invitations.lock().await;
+            invitations.insert(info.group_id.clone(), invitation);
+
+            app.emit(INVITATION_RECEIVED_EVENT, info)?;
+            Ok(None)
+        }
 
         MlsMessageInBody::PrivateMessage(message) => {
-            // Process the message
-            let protocol_message : ProtocolMessage = message.into();
+            process_protocol_message(message.into(), state, app).await
+        }
+        // Commits of new members joining with an external commit are public
+        MlsMessageInBody::PublicMessage(message) => {
+            process_protocol_message(message.into(), state, app).await
+        }
+        // Group infos and key packages are fetched from the server instead of being sent to members
+        _ => Err(ReceiveMessageError::UnsupportedMessageError),
+    }
+}
+
+async fn process_protocol_message(
+    protocol_message: ProtocolMessage,
+    state: &AppState,
+    app: &AppHandle,
+) -> Result<Option<Frame>, ReceiveMessageError> {
+    let user = state.user.lock().await;
+    let Some(user) = user.as_ref() else {
+        return Err(ReceiveMessageError::NoUserError);
+    };
 
-            let id = protocol_message.group_id();
-            let group_id = BASE64_URL_SAFE_NO_PAD.encode(id.as_slice());
+    let id = protocol_message.group_id();
+    let group_id = BASE64_URL_SAFE_NO_PAD.encode(id.as_slice());
 
-            let mut groups = state.groups.lock().await;
-            let Some(group) = groups.get_mut(&group_id) else {
-                return Err(ReceiveMessageError::GroupNotFound);
-            };
+    let mut groups = state.groups.lock().await;
+    let Some(group) = groups.get_mut(&group_id) else {
+        // The group state was lost, e.g. because the app restarted, and the message can not be decrypted anymore.
+        // Rejoining sends a commit to all members so it is left to the user instead of happening on any message.
+        let known_groups = state.known_groups.lock().await;
+        if !known_groups.contains(&group_id) {
+            return Err(ReceiveMessageError::UnknownGroupError);
+        }
+
+        app.emit(GROUP_LOST_EVENT, GroupLostEvent { group_id })?;
+        return Ok(None);
+    };
 
-            let processed_message = group.process_message(state.backend.as_ref(), protocol_message)?;
-            match processed_message.into_content() {
-                ProcessedMessageContent::ApplicationMessage(application_message) => {
-                    let message = String::from_utf8(application_message.into_bytes())?;
-                    app.emit(NEW_MESSAGE_EVENT, NewMessageEvent { group_id, message })?;
+    let processed_message = group.process_message(state.backend.as_ref(), protocol_message)?;
+    match processed_message.into_content() {
+        ProcessedMessageContent::ApplicationMessage(application_message) => {
+            let message = String::from_utf8(application_message.into_bytes())?;
+            app.emit(NEW_MESSAGE_EVENT, NewMessageEvent { group_id, message })?;
 
-                    return Ok(());
-                },
-                _ => unimplemented!("Message processed but that type is not implemented yet"),
-            }
-        },
-        _ => unimplemented!("Processing messages is not implemented yet"),
-    }
+            Ok(None)
+        }
+        ProcessedMessageContent::StagedCommitMessage(staged_commit) => {
+            group.merge_staged_commit(state.backend.as_ref(), *staged_commit)?;
 
+            // The published group info is outdated after the commit
+            publish_group_info(group, &state.backend, user, &state.client, &state.server).await?;
+
+            Ok(None)
+        }
+        // Proposals are not sent by this client
+        _ => Err(ReceiveMessageError::UnsupportedMessageError),
+    }
 }
 
 #[tauri::command]
@@ -388,15 +674,196 @@ async fn create_group(state: State<'_, AppState>) -> Result<String, CreateGroupE
         user.credential.clone(),
     )?;
 
+    publish_group_info(&group, &state.backend, user, &state.client, &state.server).await?;
+
     let id = group.group_id().as_slice();
     let id = BASE64_URL_SAFE_NO_PAD.encode(id);
 
     let mut groups = state.groups.lock().await;
+    let mut known_groups = state.known_groups.lock().await;
+    known_groups.insert(&id)?;
+
     groups.insert(id.clone(), group);
 
     Ok(id)
 }
 
+#[derive(Error, Debug)]
+enum JoinByExternalCommitError {
+    #[error("No user is signed in")]
+    NoUserError,
+    #[error("Already a member of the group")]
+    AlreadyMemberError,
+    #[error("Error getting group info")]
+    GetGroupInfoError(#[from] GetGroupInfoError),
+    #[error("Error joining group")]
+    ExternalCommitError(#[from] ExternalCommitError),
+    #[error("Error merging pending commit")]
+    MergePendingCommitError(#[from] MergePendingCommitError<MemoryKeyStoreError>),
+    #[error("Error exporting group info")]
+    ExportGroupInfoError(#[from] ExportGroupInfoError),
+    #[error("Error publishing group info")]
+    PublishGroupInfoError(#[from] PublishGroupInfoError),
+    #[error("Error serializing commit")]
+    SerializeError(#[from] tls_codec::Error),
+    #[error("Error sending commit")]
+    SendFrameError(#[from] SendFrameError),
+    #[error("Error saving known groups")]
+    KnownGroupsError(#[from] KnownGroupsError),
+}
+
+command_error!(JoinByExternalCommitError {
+    NoUserError => "no_user",
+    AlreadyMemberError => "already_member",
+    GetGroupInfoError => "get_group_info_failed",
+    ExternalCommitError => "external_commit_failed",
+    MergePendingCommitError => "merge_commit_failed",
+    ExportGroupInfoError => "export_group_info_failed",
+    PublishGroupInfoError => "publish_group_info_failed",
+    SerializeError => "serialize_failed",
+    SendFrameError => "send_failed",
+    KnownGroupsError => "save_known_groups_failed",
+});
+
+/// Joins the group using the group info published by its members.
+/// Returns the group, the frame with the commit that needs to be sent over the websocket for the members to add the user
+/// and the group info of the new epoch. The group info must only be published once the commit was sent,
+/// otherwise others would join an epoch the members never reach.
+async fn join_with_group_info(
+    group_id: &str,
+    user: &User,
+    state: &AppState,
+) -> Result<(MlsGroup, Frame, MlsMessageOut), JoinByExternalCommitError> {
+    let group_info = get_group_info(group_id, &state.client, &state.server).await?;
+
+    let group_configuration = MlsGroupConfigBuilder::default()
+        .use_ratchet_tree_extension(true)
+        .build();
+
+    // The ratchet tree is included in the group info
+    let (mut group, commit, _group_info) = MlsGroup::join_by_external_commit(
+        state.backend.as_ref(),
+        &user.signature_key,
+        None,
+        group_info,
+        &group_configuration,
+        &[],
+        user.credential.clone(),
+    )?;
+    group.merge_pending_commit(state.backend.as_ref())?;
+    let group_info = group.export_group_info(state.backend.as_ref(), &user.signature_key, true)?;
+
+    let data = commit.tls_serialize_detached()?;
+    let frame = Frame::mls(
+        state.next_request_id(),
+        Vec::new(),
+        group.group_id().as_slice(),
+        data,
+    );
+    Ok((group, frame, group_info))
+}
+
+/// Joins the group without an invitation.
+/// Sends the commit for the members to add the user over the websocket.
+#[tauri::command]
+async fn join_by_external_commit(
+    group_id: &str,
+    state: State<'_, AppState>,
+) -> Result<(), JoinByExternalCommitError> {
+    let user = state.user.lock().await;
+    let Some(user) = user.as_ref() else {
+        return Err(JoinByExternalCommitError::NoUserError);
+    };
+
+    let mut groups = state.groups.lock().await;
+    if groups.contains_key(group_id) {
+        return Err(JoinByExternalCommitError::AlreadyMemberError);
+    }
+
+    let (group, frame, group_info) = join_with_group_info(group_id, user, &state).await?;
+    // The group is only kept if the commit was sent so joining can be tried again otherwise
+    connection::send(&state, frame.tls_serialize_detached()?).await?;
+
+    let id = group.group_id().clone();
+    let mut known_groups = state.known_groups.lock().await;
+    known_groups.insert(group_id)?;
+    groups.insert(group_id.to_owned(), group);
+
+    upload_group_info(&id, &group_info, user, &state.client, &state.server).await?;
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+enum RecoverGroupError {
+    #[error("No user is signed in")]
+    NoUserError,
+    #[error("The user was never a member of the group")]
+    UnknownGroupError,
+    #[error("Already a member of the group")]
+    AlreadyMemberError,
+    #[error("Error rejoining group")]
+    JoinError(#[from] JoinByExternalCommitError),
+    #[error("Error serializing commit")]
+    SerializeError(#[from] tls_codec::Error),
+    #[error("Error sending commit")]
+    SendFrameError(#[from] SendFrameError),
+    #[error("Error publishing group info")]
+    PublishGroupInfoError(#[from] PublishGroupInfoError),
+    #[error("Error emitting event")]
+    EmitError(#[from] tauri::Error),
+}
+
+command_error!(RecoverGroupError {
+    NoUserError => "no_user",
+    UnknownGroupError => "unknown_group",
+    AlreadyMemberError => "already_member",
+    JoinError => "recover_group_failed",
+    SerializeError => "serialize_failed",
+    SendFrameError => "send_failed",
+    PublishGroupInfoError => "publish_group_info_failed",
+    EmitError => "emit_event_failed",
+});
+
+/// Rejoins a group the user lost the state of with an external commit.
+/// Only groups the user was a member of can be recovered.
+#[tauri::command]
+async fn recover_group(
+    group_id: &str,
+    state: State<'_, AppState>,
+    app: AppHandle,
+) -> Result<(), RecoverGroupError> {
+    let user = state.user.lock().await;
+    let Some(user) = user.as_ref() else {
+        return Err(RecoverGroupError::NoUserError);
+    };
+
+    if !state.known_groups.lock().await.contains(group_id) {
+        return Err(RecoverGroupError::UnknownGroupError);
+    }
+
+    let mut groups = state.groups.lock().await;
+    if groups.contains_key(group_id) {
+        return Err(RecoverGroupError::AlreadyMemberError);
+    }
+
+    let (group, frame, group_info) = join_with_group_info(group_id, user, &state).await?;
+    // The members add the user when they process the commit.
+    // The group is only kept if it was sent so recovering can be tried again otherwise.
+    connection::send(&state, frame.tls_serialize_detached()?).await?;
+    let id = group.group_id().clone();
+    groups.insert(group_id.to_owned(), group);
+
+    app.emit(
+        GROUP_RECOVERED_EVENT,
+        GroupRecoveredEvent {
+            group_id: group_id.to_owned(),
+        },
+    )?;
+
+    upload_group_info(&id, &group_info, user, &state.client, &state.server).await?;
+    Ok(())
+}
+
 #[tauri::command]
 async fn get_groups(state: State<'_, AppState>) -> Result<Vec<String>, ()> {
     let groups = state.groups.lock().await;
@@ -406,12 +873,94 @@ async fn get_groups(state: State<'_, AppState>) -> Result<Vec<String>, ()> {
     Ok(ids)
 }
 
-#[derive(Error, Debug, Serialize)]
+#[tauri::command]
+async fn list_invitations(state: State<'_, AppState>) -> Result<Vec<InvitationInfo>, ()> {
+    let invitations = state.invitations.lock().await;
+
+    let infos = invitations.values().map(Invitation::info).collect();
+
+    Ok(infos)
+}
+
+#[derive(Error, Debug)]
+enum AcceptInvitationError {
+    #[error("Invitation not found")]
+    InvitationNotFound,
+    #[error("Already a member of the group")]
+    AlreadyMemberError,
+    #[error("Error saving known groups")]
+    KnownGroupsError(#[from] KnownGroupsError),
+}
+
+command_error!(AcceptInvitationError {
+    InvitationNotFound => "invitation_not_found",
+    AlreadyMemberError => "already_member",
+    KnownGroupsError => "save_known_groups_failed",
+});
+
+/// Joins the group of the invitation.
+/// The frontend needs to subscribe to the group afterwards to receive its messages.
+#[tauri::command]
+async fn accept_invitation(
+    group_id: &str,
+    state: State<'_, AppState>,
+) -> Result<(), AcceptInvitationError> {
+    let mut invitations = state.invitations.lock().await;
+    let mut groups = state.groups.lock().await;
+    if groups.contains_key(group_id) {
+        return Err(AcceptInvitationError::AlreadyMemberError);
+    }
+
+    if !invitations.contains_key(group_id) {
+        return Err(AcceptInvitationError::InvitationNotFound);
+    }
+
+    // Remembered first so the invitation is kept if saving fails
+    let mut known_groups = state.known_groups.lock().await;
+    known_groups.insert(group_id)?;
+
+    let invitation = invitations
+        .remove(group_id)
+        .expect("invitation exists as checked above");
+    groups.insert(group_id.to_owned(), invitation.group);
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+enum DeclineInvitationError {
+    #[error("Invitation not found")]
+    InvitationNotFound,
+}
+
+command_error!(DeclineInvitationError {
+    InvitationNotFound => "invitation_not_found",
+});
+
+/// Discards the invitation.
+/// The user stays in the member list of the group until a member removes them.
+#[tauri::command]
+async fn decline_invitation(
+    group_id: &str,
+    state: State<'_, AppState>,
+) -> Result<(), DeclineInvitationError> {
+    let mut invitations = state.invitations.lock().await;
+    if invitations.remove(group_id).is_none() {
+        return Err(DeclineInvitationError::InvitationNotFound);
+    }
+
+    Ok(())
+}
+
+#[derive(Error, Debug)]
 enum GetIdentityError {
     #[error("No user is signed in")]
     NoUserError,
 }
 
+command_error!(GetIdentityError {
+    NoUserError => "no_user",
+});
+
 #[tauri::command]
 async fn get_identity(state: State<'_, AppState>) -> Result<String, GetIdentityError> {
     let user = state.user.lock().await;
@@ -424,26 +973,25 @@ async fn get_identity(state: State<'_, AppState>) -> Result<String, GetIdentityE
     Ok(id)
 }
 
-#[derive(Error, Debug, Serialize)]
+#[derive(Error, Debug)]
 enum CreateMessageError {
     #[error("No user is signed in")]
     NoUserError,
     #[error("Group not found")]
     GroupNotFound,
     #[error("Error creating message")]
-    CreateMessageError(
-        #[from]
-        #[serde(skip)]
-        openmls::group::CreateMessageError,
-    ),
+    CreateMessageError(#[from] openmls::group::CreateMessageError),
     #[error("Error serializing message")]
-    SerializeMessageError(
-        #[from]
-        #[serde(skip)]
-        tls_codec::Error,
-    ),
+    SerializeMessageError(#[from] tls_codec::Error),
 }
 
+command_error!(CreateMessageError {
+    NoUserError => "no_user",
+    GroupNotFound => "group_not_found",
+    CreateMessageError => "create_message_failed",
+    SerializeMessageError => "serialize_failed",
+});
+
 #[tauri::command]
 async fn create_message(
     state: State<'_, AppState>,
@@ -467,17 +1015,55 @@ async fn create_message(
     )?;
 
     let data = message.tls_serialize_detached()?;
+    let frame = Frame::mls(
+        state.next_request_id(),
+        Vec::new(),
+        group.group_id().as_slice(),
+        data,
+    );
+    let data = frame.tls_serialize_detached()?;
     Ok(data)
 }
 
+/// Creates the frame to subscribe to the messages of all groups.
+/// Needs to be sent whenever the websocket connects.
+async fn subscribe_frame(state: &AppState) -> Result<Vec<u8>, tls_codec::Error> {
+    let groups = state.groups.lock().await;
+    let known_groups = state.known_groups.lock().await;
+    let mut group_ids: Vec<VLBytes> = groups
+        .values()
+        .map(|group| group.group_id().as_slice().into())
+        .collect();
+
+    // Messages of lost groups tell the user that they can be recovered
+    let lost_group_ids = known_groups
+        .ids()
+        .filter(|id| !groups.contains_key(*id))
+        .filter_map(|id| BASE64_URL_SAFE_NO_PAD.decode(id).ok());
+    group_ids.extend(lost_group_ids.map(VLBytes::from));
+
+    let frame = Frame::subscribe(state.next_request_id(), group_ids);
+    frame.tls_serialize_detached()
+}
+
+/// Name of the file in the app data directory that lists the groups the user was a member of
+const KNOWN_GROUPS_FILE: &str = "known_groups.json";
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    let client = Client::new();
+    let server = Server::from_env().expect("Invalid server configuration");
+    let client = server.client().expect("Failed to create HTTP client");
     let state = AppState {
         backend: OpenMlsRustCrypto::default().into(),
         user: Arc::default(),
         groups: Arc::default(),
+        known_groups: Arc::default(),
+        invitations: Arc::default(),
         client,
+        server,
+        connection: Mutex::default(),
+        // Zero is reserved for frames that don't expect a response
+        next_request_id: AtomicU64::new(1),
     };
 
     tauri::Builder::default()
@@ -488,20 +1074,31 @@ pub fn run() {
                 window.open_devtools();
                 window.close_devtools();
             }
+
+            let path = app.path().app_data_dir()?.join(KNOWN_GROUPS_FILE);
+            let known_groups = KnownGroups::load(path)?;
+            *app.state::<AppState>().known_groups.blocking_lock() = known_groups;
             Ok(())
         })
         .manage(state)
         .plugin(tauri_plugin_shell::init())
         .invoke_handler(tauri::generate_handler![
+            accept_invitation,
             advertise,
             create_group,
             create_message,
             command::create_user,
+            connection::connect,
+            decline_invitation,
             is_authenticated,
             get_groups,
             get_identity,
             invite_package,
-            process_message,
+            join_by_external_commit,
+            list_invitations,
+            recover_group,
+            connection::send_frame,
+            connection::subscribe,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,99 @@
+//! Framing of the messages sent over the websocket to the delivery server.
+//! Needs to be kept in sync with the server.
+//! The websocket has to be opened with the `mealt.v1` subprotocol for the server to use framing.
+
+use tls_codec::{TlsDeserialize, TlsSerialize, TlsSize, VLBytes};
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct Frame {
+    /// Chosen by the sender of a request and echoed in the acknowledgement or error for it.
+    /// Zero for frames that don't expect a response.
+    pub(crate) request_id: u64,
+    pub(crate) body: FrameBody,
+}
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+#[repr(u8)]
+pub(crate) enum FrameBody {
+    #[tls_codec(discriminant = 1)]
+    Mls(MlsFrame),
+    #[tls_codec(discriminant = 2)]
+    Ack(AckFrame),
+    #[tls_codec(discriminant = 3)]
+    Subscribe(SubscribeFrame),
+    #[tls_codec(discriminant = 4)]
+    Ping(PingFrame),
+    #[tls_codec(discriminant = 5)]
+    Error(ErrorFrame),
+}
+
+/// An MLS message routed to the recipients and the subscribers of the group
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct MlsFrame {
+    /// Identities to deliver the message to, e.g. the new member of a welcome message
+    pub(crate) recipients: Vec<VLBytes>,
+    /// Identity of the sender as known to the server. Set by the server.
+    pub(crate) sender: VLBytes,
+    /// Id of the group the message belongs to. Empty if it does not belong to a group.
+    pub(crate) group_id: VLBytes,
+    pub(crate) message: VLBytes,
+}
+
+/// Confirms a request was handled
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct AckFrame {
+    /// Recipients of a message that were not connected
+    pub(crate) undelivered: Vec<VLBytes>,
+}
+
+/// Subscribes the connection to the messages of the groups
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct SubscribeFrame {
+    pub(crate) group_ids: Vec<VLBytes>,
+}
+
+/// Checks if the other side is still there. Answered with an acknowledgement.
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct PingFrame {}
+
+#[derive(Debug, TlsSerialize, TlsDeserialize, TlsSize)]
+pub(crate) struct ErrorFrame {
+    pub(crate) code: u16,
+    /// Human readable description for debugging
+    pub(crate) message: VLBytes,
+}
+
+impl Frame {
+    pub(crate) fn mls(
+        request_id: u64,
+        recipients: Vec<VLBytes>,
+        group_id: &[u8],
+        message: Vec<u8>,
+    ) -> Self {
+        Self {
+            request_id,
+            body: FrameBody::Mls(MlsFrame {
+                recipients,
+                sender: Vec::new().into(),
+                group_id: group_id.into(),
+                message: message.into(),
+            }),
+        }
+    }
+
+    pub(crate) fn ack(request_id: u64) -> Self {
+        Self {
+            request_id,
+            body: FrameBody::Ack(AckFrame {
+                undelivered: Vec::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn subscribe(request_id: u64, group_ids: Vec<VLBytes>) -> Self {
+        Self {
+            request_id,
+            body: FrameBody::Subscribe(SubscribeFrame { group_ids }),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Connection settings of the delivery server
+
+use std::{env, fs, io, sync::Arc};
+
+use reqwest::Client;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{
+        ring::default_provider, verify_tls12_signature, verify_tls13_signature,
+        WebPkiSupportedAlgorithms,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, ClientConfig, DigitallySignedStruct, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio_tungstenite::Connector;
+
+/// Base URL of the delivery server, e.g. "https://mealt.example.com"
+const SERVER_URL_KEY: &str = "MEALT_SERVER_URL";
+/// Path to a PEM encoded certificate. If set, the server has to present exactly this certificate
+/// which allows self-hosted servers with self-signed certificates.
+const SERVER_CERTIFICATE_KEY: &str = "MEALT_SERVER_CERTIFICATE";
+const DEFAULT_SERVER_URL: &str = "http://localhost:3000";
+
+#[derive(Error, Debug)]
+pub(crate) enum ServerConfigError {
+    #[error("Server URL has to start with http:// or https://")]
+    InvalidUrl,
+    #[error("Could not read pinned certificate")]
+    ReadCertificateError(#[from] io::Error),
+    #[error("Pinned certificate file does not contain a certificate")]
+    NoCertificateError,
+    #[error("Could not build HTTP client")]
+    ClientError(#[from] reqwest::Error),
+}
+
+/// Accepts the server only if its certificate is the pinned one.
+/// Comparing the certificate itself instead of using it as root makes sure certificates it signed are not accepted.
+#[derive(Debug)]
+struct PinnedCertificateVerifier {
+    /// SHA-256 of the DER encoded certificate
+    fingerprint: [u8; 32],
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint: [u8; 32] = Sha256::digest(end_entity.as_ref()).into();
+        if fingerprint != self.fingerprint {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+
+    // The handshake signatures are still checked so the server has to own the key of the certificate
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+pub(crate) struct Server {
+    url: String,
+    /// TLS configuration that only accepts the pinned certificate if there is one
+    pinned_tls: Option<Arc<ClientConfig>>,
+}
+
+impl Server {
+    pub(crate) fn from_env() -> Result<Self, ServerConfigError> {
+        let url = env::var(SERVER_URL_KEY).unwrap_or_else(|_| DEFAULT_SERVER_URL.to_owned());
+        let url = url.trim_end_matches('/').to_owned();
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(ServerConfigError::InvalidUrl);
+        }
+
+        let pinned_tls = match env::var(SERVER_CERTIFICATE_KEY) {
+            Ok(path) => Some(pinned_tls_config(&fs::read(path)?)?),
+            Err(_) => None,
+        };
+
+        Ok(Self { url, pinned_tls })
+    }
+
+    /// Creates a client for requests to the server that only trusts the pinned certificate if there is one
+    pub(crate) fn client(&self) -> Result<Client, ServerConfigError> {
+        let Some(config) = &self.pinned_tls else {
+            return Ok(Client::new());
+        };
+
+        let client = Client::builder()
+            .use_preconfigured_tls(config.as_ref().clone())
+            .https_only(true)
+            .build()?;
+        Ok(client)
+    }
+
+    /// Connector for the websocket with the same transport security as the HTTP requests.
+    /// `None` uses the default roots.
+    pub(crate) fn websocket_connector(&self) -> Option<Connector> {
+        self.pinned_tls.clone().map(Connector::Rustls)
+    }
+
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    pub(crate) fn websocket_url(&self, path: &str) -> String {
+        let url = match self.url.strip_prefix("https://") {
+            Some(host) => format!("wss://{}", host),
+            None => format!("ws://{}", self.url.trim_start_matches("http://")),
+        };
+
+        format!("{}{}", url, path)
+    }
+}
+
+fn pinned_tls_config(pem: &[u8]) -> Result<Arc<ClientConfig>, ServerConfigError> {
+    let certificate = rustls_pemfile::certs(&mut &pem[..])
+        .next()
+        .ok_or(ServerConfigError::NoCertificateError)??;
+
+    let verifier = PinnedCertificateVerifier {
+        fingerprint: Sha256::digest(certificate.as_ref()).into(),
+        algorithms: default_provider().signature_verification_algorithms,
+    };
+
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
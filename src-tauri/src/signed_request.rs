@@ -0,0 +1,54 @@
+//! Signs requests that act on behalf of the user.
+//! The server checks the signature against the key package the user published, so only the user can act as their identity.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::prelude::*;
+use openmls::prelude::SignContent;
+use openmls_basic_credential::SignatureKeyPair;
+use openmls_traits::signatures::Signer;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tls_codec::Serialize;
+
+const SIGNATURE_HEADER: &str = "x-mealt-signature";
+const TIMESTAMP_HEADER: &str = "x-mealt-timestamp";
+const CONTENT_DIGEST_HEADER: &str = "x-mealt-content-sha256";
+/// Has to match the label the server verifies with
+const SIGNATURE_LABEL: &str = "mealt request";
+
+#[derive(Error, Debug)]
+pub(crate) enum SignRequestError {
+    #[error("Error encoding the signed content")]
+    SerializeError(#[from] tls_codec::Error),
+    #[error("Error signing the request")]
+    SignError,
+}
+
+/// Headers that authenticate the request.
+/// The path includes the query and the body is empty for requests without body.
+pub(crate) fn signature_headers(
+    method: &str,
+    path: &str,
+    body: &[u8],
+    signer: &SignatureKeyPair,
+) -> Result<[(&'static str, String); 3], SignRequestError> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let content_digest = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(body));
+
+    let content = format!("{} {}\n{}\n{}", method, path, timestamp, content_digest);
+    let payload =
+        SignContent::new(SIGNATURE_LABEL, content.into_bytes().into()).tls_serialize_detached()?;
+    let signature = signer
+        .sign(&payload)
+        .map_err(|_| SignRequestError::SignError)?;
+
+    Ok([
+        (TIMESTAMP_HEADER, timestamp.to_string()),
+        (CONTENT_DIGEST_HEADER, content_digest),
+        (SIGNATURE_HEADER, BASE64_URL_SAFE_NO_PAD.encode(signature)),
+    ])
+}
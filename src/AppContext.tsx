@@ -17,56 +17,75 @@ const [groups, { mutate: setGroups }] = createResource(
   async () => (await invoke("get_groups")) as string[]
 );
 
+export type Invitation = {
+  group_id: string;
+  inviter: string;
+  members: string[];
+};
+
+/** What commands reject with when they fail */
+export type CommandError = {
+  /** Stable identifier of the error, e.g. "no_user" or "group_not_found" */
+  code: string;
+  message: string;
+  /** Whether trying again can succeed, e.g. after a timeout */
+  retryable: boolean;
+  /** Status code of the failed request to the server */
+  status: number | null;
+  details: string | null;
+};
+
+const [invitations, { refetch: refetchInvitations }] = createResource(
+  async () => (await invoke("list_invitations")) as Invitation[]
+);
+
 const [identity, { mutate: setIdentity }] = createResource(
   async () =>
-    (await invoke("get_identity").catch((error) => {
-      console.warn(
-        "Could not get identity. But this might be expected if this is the first time the app is run",
-        error
-      );
+    (await invoke("get_identity").catch((error: CommandError) => {
+      // There is no user the first time the app is run
+      if (error.code !== "no_user")
+        console.warn("Could not get identity", error);
       return undefined;
     })) as string
 );
 
 const [messages, setMessages] = createStore<Record<string, string[]>>({});
 
-async function handleMessage(event: MessageEvent) {
-  // Might remove redundant check later
-  if (typeof event.data === "string")
-    throw new Error("Unexpected string as message event payload");
+/** Groups the user was a member of but lost the state of. They can be recovered with an external commit. */
+const [lostGroups, setLostGroups] = createSignal<string[]>([]);
 
-  const data = event.data;
-  if (!(data instanceof Blob))
-    throw new Error("Unexpected non-blob as message event payload");
+/** Opens the websocket in the backend which uses the pinned certificate and processes the messages there */
+createEffect(async () => {
+  // Reconnects with the identity
+  if (identity() === undefined) return;
 
-  //TODO find out how to pass the data as binary to tauri without going through serde
-  const buffer = await data.arrayBuffer();
-  const array = [...new Uint8Array(buffer)];
+  await invoke("connect").catch((error: CommandError) =>
+    console.warn("Could not connect to server", error)
+  );
+});
 
-  await invoke("process_message", { data: array });
+/** Subscribes to the messages of all groups the user is a member of */
+async function subscribe() {
+  await invoke("subscribe");
 }
-const [socket, setSocket] = createSignal<WebSocket | undefined>();
-createEffect<WebSocket | undefined>((previous) => {
-  const id = identity();
-  if (id === undefined) return;
-
-  previous?.removeEventListener("message", handleMessage);
-  previous?.close();
 
-  const newSocket = new WebSocket(`ws://localhost:3000/${id}/messages`);
-  newSocket.addEventListener("message", handleMessage);
-  setSocket(newSocket);
-  return newSocket;
-}, socket());
+/** Sends a frame created by a command, e.g. a message or commit, over the websocket */
+async function sendFrame(frame: number[] | Uint8Array) {
+  await invoke("send_frame", { data: Array.from(frame) });
+}
 
 const state = {
+  subscribe,
   identity,
   setIdentity,
-  socket,
+  sendFrame,
   groups,
   setGroups,
+  invitations,
+  refetchInvitations,
   messages,
   setMessages,
+  lostGroups,
 };
 
 const AppContext = createContext(state);
@@ -79,20 +98,9 @@ export function SocketProvider(properties: { children: JSX.Element }) {
   );
 }
 
-export function useWebSocket(onmessage?: (event: MessageEvent) => any) {
-  const { socket } = useContext(AppContext);
-
-  createEffect<WebSocket | undefined>((previous) => {
-    const current = socket();
-    if (onmessage === undefined || current === undefined) return current;
-
-    previous?.removeEventListener("message", onmessage);
-    current.addEventListener("message", onmessage);
-    return current;
-  }, socket());
-
-  return (data: string | ArrayBufferLike | Blob | ArrayBufferView) =>
-    socket()?.send(data);
+export function useWebSocket() {
+  const { sendFrame } = useContext(AppContext);
+  return sendFrame;
 }
 
 export const useAppState = () => useContext(AppContext);
@@ -121,11 +129,36 @@ function getMessage(payload: unknown): string {
   return payload.message;
 }
 
-listen("join_group", (event) => {
+/** Welcomes are not joined automatically but wait for the user to accept them */
+listen("invitation_received", () => refetchInvitations());
+
+/** Rejoining sends a commit to all members so it waits for the user to recover the group */
+listen("group_lost", (event) => {
   const group = getGroupId(event.payload);
-  setGroups((groups) => (groups === undefined ? [group] : [...groups, group]));
+  setLostGroups((groups) =>
+    groups.includes(group) ? groups : [...groups, group]
+  );
 });
 
+/** The user recovered a lost group. The commit for the members was already sent. */
+listen("group_recovered", async (event) => {
+  const group = getGroupId(event.payload);
+  setLostGroups((groups) => groups.filter((id) => id !== group));
+  setGroups((groups) => {
+    if (groups === undefined) return [group];
+    return groups.includes(group) ? groups : [...groups, group];
+  });
+  await state.subscribe();
+});
+
+listen("connection_closed", () =>
+  console.warn("Connection to the server closed")
+);
+
+listen("process_message_failed", (event) =>
+  console.warn("Could not process message", event.payload)
+);
+
 listen("new_message", (event) => {
   const groupId = getGroupId(event.payload);
   const message = getMessage(event.payload);
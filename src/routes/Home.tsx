@@ -11,17 +11,31 @@ const isAuthenticated = async () =>
 
 const createGroup = async () => (await invoke("create_group")) as string;
 
+/** Sends the commit that adds the user to the group to its members */
+const joinByExternalCommit = async (groupId: string) =>
+  await invoke("join_by_external_commit", { groupId });
+
 function Home() {
   const [isAuthenticatedResource, { refetch: refetchIsAuthenticated }] =
     createResource(isAuthenticated);
 
-  const { groups, setGroups, identity, setIdentity } = useAppState();
+  const {
+    groups,
+    setGroups,
+    identity,
+    setIdentity,
+    subscribe,
+    invitations,
+    refetchInvitations,
+    lostGroups,
+  } = useAppState();
 
-  function handleSubmit(event: SubmitEvent) {
+  async function handleSubmit(event: SubmitEvent) {
     event.preventDefault();
     // @ts-ignore
     const name = event.target.name.value;
-    createUser(name);
+    // The websocket connects once the identity is set which needs the user
+    await createUser(name);
     refetchIsAuthenticated();
     setIdentity(name);
   }
@@ -30,6 +44,36 @@ function Home() {
     const id = await createGroup();
 
     setGroups((groups) => (groups === undefined ? [id] : [...groups, id]));
+    // Receive messages for the new group
+    await subscribe();
+  }
+
+  async function handleJoinGroup(event: SubmitEvent) {
+    event.preventDefault();
+    // @ts-ignore
+    const id = event.target.groupId.value;
+    await joinByExternalCommit(id);
+
+    setGroups((groups) => (groups === undefined ? [id] : [...groups, id]));
+    await subscribe();
+  }
+
+  async function handleAcceptInvitation(id: string) {
+    await invoke("accept_invitation", { groupId: id });
+    refetchInvitations();
+
+    setGroups((groups) => (groups === undefined ? [id] : [...groups, id]));
+    await subscribe();
+  }
+
+  async function handleDeclineInvitation(id: string) {
+    await invoke("decline_invitation", { groupId: id });
+    refetchInvitations();
+  }
+
+  /** Rejoins the group with an external commit. The group is added once the group_recovered event arrives. */
+  async function handleRecoverGroup(id: string) {
+    await invoke("recover_group", { groupId: id });
   }
 
   async function handleAdvertise() {
@@ -50,6 +94,54 @@ function Home() {
         <p>Your identity is {identity()}</p>
         <button onMouseDown={handleCreateGroup}>Create Group</button>
         <button onMouseDown={handleAdvertise}>Advertise</button>
+        <form onSubmit={handleJoinGroup}>
+          <label for="groupId">Group id</label>
+          <input type="text" id="groupId" />
+          <button type="submit">Join Group</button>
+        </form>
+        <Show when={invitations()?.length}>
+          <h2>Invitations</h2>
+          <ul>
+            <For each={invitations()}>
+              {(invitation) => (
+                <li>
+                  {invitation.inviter} invited you to group{" "}
+                  {invitation.group_id} with {invitation.members.length}{" "}
+                  members
+                  <button
+                    onMouseDown={() =>
+                      handleAcceptInvitation(invitation.group_id)
+                    }
+                  >
+                    Accept
+                  </button>
+                  <button
+                    onMouseDown={() =>
+                      handleDeclineInvitation(invitation.group_id)
+                    }
+                  >
+                    Decline
+                  </button>
+                </li>
+              )}
+            </For>
+          </ul>
+        </Show>
+        <Show when={lostGroups().length}>
+          <h2>Lost groups</h2>
+          <ul>
+            <For each={lostGroups()}>
+              {(id) => (
+                <li>
+                  Group {id} can not be read anymore
+                  <button onMouseDown={() => handleRecoverGroup(id)}>
+                    Recover
+                  </button>
+                </li>
+              )}
+            </For>
+          </ul>
+        </Show>
         <ol>
           <For each={groups()}>
             {(id) => (